@@ -1,6 +1,9 @@
 use core::fmt;
 
-use crate::scanner::{Lexer, TokenType};
+use crate::{
+    diagnostic::Diagnostic,
+    scanner::{interpolation_length, Lexer, Position, TokenType},
+};
 use serde::{Deserialize, Serialize};
 
 /// Represents a node in the Abstract Syntax Tree (AST)
@@ -11,15 +14,49 @@ pub enum ASTNode {
     Identifier(String),
     Boolean(bool),
     String(String),
+    Array(Vec<ASTNode>),
     Op(Ops, Vec<ASTNode>),
     Callee(String, Vec<ASTNode>),
+    /// `name = value` in the arguments of a call
+    NamedArg(String, Vec<ASTNode>),
+    /// `...array` as the last argument of a call, its elements are the arguments
+    Spread(Vec<ASTNode>),
     Let(String, Vec<ASTNode>),
     Assign(String, Vec<ASTNode>),
     If(Vec<ASTNode>, Vec<ASTNode>, Option<Vec<ASTNode>>),
     While(Vec<ASTNode>, Vec<ASTNode>),
+    /// `until (condition) body`, runs the body while the condition is false
+    Until(Vec<ASTNode>, Vec<ASTNode>),
+    /// `break`, leaves the innermost loop
+    Break,
+    /// `continue`, goes back to the condition of the innermost loop
+    Continue,
     Print(Vec<ASTNode>),
-    Function(String, Vec<String>, Vec<ASTNode>),
+    /// The parameters are `Identifier`s, or `NamedArg`s for the ones with a default value
+    Function(String, Vec<ASTNode>, Vec<ASTNode>),
+    Return(Vec<ASTNode>),
+    /// `defer expr` or `defer print(..)`, run when the enclosing block exits
+    Defer(Vec<ASTNode>),
     Block(Vec<ASTNode>),
+    /// `try { body } catch (name) { handler } finally { cleanup }`, a runtime
+    /// error in the body runs the handler with its message in `name`. The cleanup
+    /// runs however the rest ends, one of `catch` and `finally` can be left out
+    Try(
+        Vec<ASTNode>,
+        Option<(String, Vec<ASTNode>)>,
+        Option<Vec<ASTNode>>,
+    ),
+    /// `throw value`, a runtime error the `catch` gets `value` from
+    Throw(Vec<ASTNode>),
+    /// `"x = ${x}"`, the pieces of the string are `String`s alternating with the
+    /// interpolated expressions, joined in order. The first piece starts with the
+    /// opening quote and the last ends with the closing one
+    Interpolation(Vec<ASTNode>),
+    /// `import "path"`, the top-level definitions of another file, or with
+    /// `as name` a module holding them
+    Import(String, Option<String>),
+    /// A statement and where it starts in the source
+    Located(Position, Box<ASTNode>),
 }
 
 /// Represents binary operations
@@ -29,6 +66,7 @@ pub enum BinaryOp {
     Sub,
     Mul,
     Div,
+    Mod,
     At,
     Eq,
     Ne,
@@ -36,6 +74,8 @@ pub enum BinaryOp {
     Le,
     Gt,
     Ge,
+    And,
+    Or,
 }
 
 /// Represents unary operations
@@ -94,7 +134,7 @@ pub struct Parser<'a> {
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(lexer: &mut Lexer) -> Parser {
+    pub fn new(lexer: &'a mut Lexer) -> Parser<'a> {
         Parser { lexer }
     }
 
@@ -109,15 +149,104 @@ impl<'a> Parser<'a> {
         Ok(statements)
     }
 
-    /// Parse a single statement
+    /// Like `parse`, but after an error skips to the next statement and goes on,
+    /// so every error of the source is reported at once
+    pub fn parse_recovering(&mut self) -> (Vec<ASTNode>, Vec<Diagnostic>) {
+        let mut statements = vec![];
+        let mut diagnostics = vec![];
+
+        while self.lexer.peek().token_type != TokenType::EOF {
+            let remaining = self.lexer.tokens.len();
+            match self.parse_statement() {
+                Ok(statement) => statements.push(statement),
+                Err(e) => {
+                    diagnostics.push(Diagnostic::error(self.lexer.previous, e.to_string()));
+                    // always move forward, even when the error consumed nothing
+                    if self.lexer.tokens.len() == remaining {
+                        self.lexer.next();
+                    }
+                    self.synchronize();
+                }
+            }
+        }
+
+        (statements, diagnostics)
+    }
+
+    /// Skips the rest of the statement an error happened in
+    fn synchronize(&mut self) {
+        loop {
+            match self.lexer.peek().token_type {
+                TokenType::EOF
+                | TokenType::LET
+                | TokenType::FN
+                | TokenType::PRINT
+                | TokenType::RETURN
+                | TokenType::DEFER
+                | TokenType::THROW
+                | TokenType::IMPORT
+                | TokenType::IF
+                | TokenType::UNLESS
+                | TokenType::WHILE
+                | TokenType::UNTIL
+                | TokenType::BREAK
+                | TokenType::CONTINUE
+                | TokenType::TRY => return,
+                TokenType::SEMICOLON => {
+                    self.lexer.next();
+                    return;
+                }
+                _ => {
+                    self.lexer.next();
+                }
+            }
+        }
+    }
+
+    /// Parse a single statement, remembering where it starts
     fn parse_statement(&mut self) -> ParseResult<ASTNode> {
+        let position = self.lexer.peek().position;
+        let statement = self.parse_unlocated_statement()?;
+
+        Ok(ASTNode::Located(position, Box::new(statement)))
+    }
+
+    fn parse_unlocated_statement(&mut self) -> ParseResult<ASTNode> {
+        // these end with a statement or a block of their own, which is terminated
+        let compound = matches!(
+            self.lexer.peek().token_type,
+            TokenType::FN
+                | TokenType::LeftBrace
+                | TokenType::IF
+                | TokenType::UNLESS
+                | TokenType::WHILE
+                | TokenType::UNTIL
+                | TokenType::TRY
+        );
         let statement = match self.lexer.peek().token_type {
             TokenType::PRINT => self.parse_print(),
             TokenType::LET => self.parse_let(),
             TokenType::FN => self.parse_function(),
+            TokenType::RETURN => self.parse_return(),
+            TokenType::DEFER => self.parse_defer(),
+            TokenType::TRY => self.parse_try(),
+            TokenType::THROW => {
+                self.lexer.next();
+                Ok(ASTNode::Throw(vec![self.parse_expression()?]))
+            }
             TokenType::LeftBrace => self.parse_block(),
             TokenType::IF => self.parse_if(),
-            TokenType::WHILE => self.parse_while(),
+            TokenType::UNLESS => self.parse_unless(),
+            TokenType::WHILE | TokenType::UNTIL => self.parse_while(),
+            TokenType::IMPORT => self.parse_import(),
+            TokenType::BREAK => {
+                self.lexer.next();
+                Ok(ASTNode::Break)
+            }
+            TokenType::CONTINUE => {
+                self.lexer.next();
+                Ok(ASTNode::Continue)
+            }
             TokenType::Identifier if self.is_assignment() => self.parse_assign(),
             TokenType::SEMICOLON => {
                 self.lexer.next(); // Consume the semicolon
@@ -126,9 +255,23 @@ impl<'a> Parser<'a> {
             _ => self.parse_expression(),
         }?;
 
-        // Consume the semicolon if present
-        if self.lexer.peek().token_type == TokenType::SEMICOLON {
-            self.lexer.next();
+        // a statement ends with a `;`, or at the end of its line. A `}`, `else` or
+        // the end of the source end it too
+        match self.lexer.peek().token_type {
+            TokenType::SEMICOLON => {
+                self.lexer.next();
+            }
+            TokenType::RightBrace | TokenType::ELSE | TokenType::EOF => {}
+            TokenType::Error(_) => {
+                return Err(ParseError::SyntaxError(self.lexer.next().lexeme));
+            }
+            _ if compound || self.lexer.next_on_new_line() => {}
+            token_type => {
+                return Err(ParseError::UnexpectedToken(
+                    token_type,
+                    "Expected ';' or a newline after the statement".to_string(),
+                ))
+            }
         }
 
         Ok(statement)
@@ -140,14 +283,37 @@ impl<'a> Parser<'a> {
                 "Expected '(' after print".to_string(),
             ));
         }
-        let expr = self.parse_expression()?;
+        let args = parse_args(self.lexer)?;
         if self.lexer.next().token_type != TokenType::RightParen {
             return Err(ParseError::MissingToken(
                 TokenType::RightParen,
                 "to close print statement".to_string(),
             ));
         }
-        Ok(ASTNode::Print(vec![expr]))
+        Ok(ASTNode::Print(args))
+    }
+
+    fn parse_import(&mut self) -> ParseResult<ASTNode> {
+        self.lexer.next();
+        let path = self.lexer.next();
+        if path.token_type != TokenType::String {
+            return Err(ParseError::SyntaxError(
+                "Expected a file path string after import".to_string(),
+            ));
+        }
+        let path = path.lexeme.trim_matches('"').to_string();
+
+        if self.lexer.peek().token_type != TokenType::AS {
+            return Ok(ASTNode::Import(path, None));
+        }
+        self.lexer.next();
+        let alias = self.lexer.next();
+        if alias.token_type != TokenType::Identifier {
+            return Err(ParseError::SyntaxError(
+                "Expected a module name after as".to_string(),
+            ));
+        }
+        Ok(ASTNode::Import(path, Some(alias.lexeme)))
     }
 
     fn parse_let(&mut self) -> ParseResult<ASTNode> {
@@ -170,7 +336,7 @@ impl<'a> Parser<'a> {
         while self.lexer.peek().token_type != TokenType::RightBrace {
             statements.push(self.parse_statement()?);
         }
-        self.lexer.next(); // consume RightBrace
+        self.expect_token(TokenType::RightBrace, "to close block")?;
         Ok(ASTNode::Block(statements))
     }
 
@@ -227,25 +393,43 @@ impl<'a> Parser<'a> {
         Ok(ASTNode::If(vec![condition], then_branch, else_branch))
     }
 
+    /// `unless (condition) body` is an `if` that only has an `else` branch
+    fn parse_unless(&mut self) -> ParseResult<ASTNode> {
+        self.lexer.next();
+        self.expect_token(TokenType::LeftParen, "to start unless condition")?;
+        let condition = self.parse_expression()?;
+        self.expect_token(TokenType::RightParen, "to close unless condition")?;
+
+        let body = vec![self.parse_statement()?];
+        Ok(ASTNode::If(
+            vec![condition],
+            vec![ASTNode::Block(vec![])],
+            Some(body),
+        ))
+    }
+
     // TODO: might need fixing
     fn parse_while(&mut self) -> ParseResult<ASTNode> {
-        self.lexer.next();
+        let keyword = self.lexer.next();
         if self.lexer.next().token_type != TokenType::LeftParen {
             return Err(ParseError::MissingToken(
                 TokenType::LeftParen,
-                "to start while condition".to_string(),
+                format!("to start {} condition", keyword.lexeme),
             ));
         }
         let condition = self.parse_expression()?;
         if self.lexer.next().token_type != TokenType::RightParen {
             return Err(ParseError::MissingToken(
                 TokenType::RightParen,
-                "to close while condition".to_string(),
+                format!("to close {} condition", keyword.lexeme),
             ));
         }
         // let body = self.parse_block()?;
         let body = vec![self.parse_statement()?];
-        Ok(ASTNode::While(vec![condition], body))
+        match keyword.token_type {
+            TokenType::UNTIL => Ok(ASTNode::Until(vec![condition], body)),
+            _ => Ok(ASTNode::While(vec![condition], body)),
+        }
     }
 
     fn parse_function(&mut self) -> ParseResult<ASTNode> {
@@ -259,7 +443,13 @@ impl<'a> Parser<'a> {
         }
         let mut params = vec![];
         while self.lexer.peek().token_type != TokenType::RightParen {
-            params.push(self.lexer.next().lexeme);
+            let param = self.lexer.next().lexeme;
+            if self.lexer.peek().token_type == TokenType::EQUAL {
+                self.lexer.next();
+                params.push(ASTNode::NamedArg(param, vec![self.parse_expression()?]));
+            } else {
+                params.push(ASTNode::Identifier(param));
+            }
             if self.lexer.peek().token_type == TokenType::COMMA {
                 self.lexer.next();
             }
@@ -269,6 +459,84 @@ impl<'a> Parser<'a> {
         let body = vec![self.parse_statement()?];
         Ok(ASTNode::Function(name, params, body))
     }
+
+    /// `return;` or `return expr;`
+    fn parse_return(&mut self) -> ParseResult<ASTNode> {
+        self.lexer.next();
+        match self.lexer.peek().token_type {
+            TokenType::SEMICOLON | TokenType::RightBrace => Ok(ASTNode::Return(vec![])),
+            _ => Ok(ASTNode::Return(vec![self.parse_expression()?])),
+        }
+    }
+
+    fn parse_try(&mut self) -> ParseResult<ASTNode> {
+        self.lexer.next();
+        let body = self.parse_braced_block("to start try block")?;
+
+        let catch = match self.lexer.peek().token_type {
+            TokenType::CATCH => {
+                self.lexer.next();
+                self.expect_token(TokenType::LeftParen, "to start catch variable")?;
+                let name = self.lexer.next();
+                if name.token_type != TokenType::Identifier {
+                    return Err(ParseError::UnexpectedToken(
+                        name.token_type,
+                        "Expected the name of the catch variable".to_string(),
+                    ));
+                }
+                self.expect_token(TokenType::RightParen, "to close catch variable")?;
+
+                let ASTNode::Block(handler) = self.parse_braced_block("to start catch block")?
+                else {
+                    unreachable!()
+                };
+                Some((name.lexeme, handler))
+            }
+            _ => None,
+        };
+
+        let finally = match self.lexer.peek().token_type {
+            TokenType::FINALLY => {
+                self.lexer.next();
+                let ASTNode::Block(cleanup) = self.parse_braced_block("to start finally block")?
+                else {
+                    unreachable!()
+                };
+                Some(cleanup)
+            }
+            _ => None,
+        };
+
+        if catch.is_none() && finally.is_none() {
+            return Err(ParseError::MissingToken(
+                TokenType::CATCH,
+                "or finally after try block".to_string(),
+            ));
+        }
+        Ok(ASTNode::Try(vec![body], catch, finally))
+    }
+
+    /// A block that must be there, unlike the statement after `if` or `while`
+    fn parse_braced_block(&mut self, context: &str) -> ParseResult<ASTNode> {
+        if self.lexer.peek().token_type != TokenType::LeftBrace {
+            return Err(ParseError::MissingToken(
+                TokenType::LeftBrace,
+                context.to_string(),
+            ));
+        }
+        self.parse_block()
+    }
+
+    /// `defer` takes an expression or a `print`
+    fn parse_defer(&mut self) -> ParseResult<ASTNode> {
+        self.lexer.next();
+        let deferred = match self.lexer.peek().token_type {
+            TokenType::PRINT => self.parse_print()?,
+            _ => self.parse_expression()?,
+        };
+        Ok(ASTNode::Defer(vec![deferred]))
+    }
+
     fn parse_assign(&mut self) -> ParseResult<ASTNode> {
         let id = self.lexer.next().lexeme;
         let op = self.lexer.next().token_type;
@@ -340,11 +608,11 @@ impl<'a> Parser<'a> {
 fn expr_bp(lexer: &mut Lexer, min_bp: u8) -> ParseResult<ASTNode> {
     let mut lhs = parse_prefix(lexer)?;
 
-    loop {
-        let op = match infix_op(lexer.peek().token_type) {
-            Some(op) => op,
-            None => break,
-        };
+    while let Some(op) = infix_op(lexer.peek().token_type) {
+        // a `[` starting a line is an array statement, not an index of this line
+        if op == Ops::PostfixOp(PostfixOp::Index) && lexer.next_on_new_line() {
+            break;
+        }
 
         if let Some((l_bp, r_bp)) = infix_binding_power(op) {
             if l_bp < min_bp {
@@ -380,11 +648,31 @@ fn parse_prefix(lexer: &mut Lexer) -> ParseResult<ASTNode> {
 
     let token = lexer.next();
     match token.token_type {
+        TokenType::Error(_) => Err(ParseError::SyntaxError(token.lexeme)),
         TokenType::IntNumber(n) => Ok(ASTNode::IntNumber(n)),
         TokenType::FloatNumber(n) => Ok(ASTNode::FloatNumber(n)),
+        TokenType::Identifier
+            if lexer.peek().token_type == TokenType::LeftParen && !lexer.next_on_new_line() =>
+        {
+            lexer.next();
+            let args = parse_args(lexer)?;
+            if lexer.next().token_type != TokenType::RightParen {
+                return Err(ParseError::MissingToken(
+                    TokenType::RightParen,
+                    "to close function call arguments".to_string(),
+                ));
+            }
+            Ok(ASTNode::Callee(token.lexeme, args))
+        }
         TokenType::Identifier => Ok(ASTNode::Identifier(token.lexeme)),
         TokenType::Boolean(b) => Ok(ASTNode::Boolean(b)),
-        TokenType::String => Ok(ASTNode::String(token.lexeme)),
+        TokenType::String if token.lexeme.contains("${") || token.lexeme.contains("\\$") => {
+            parse_interpolation(&token.lexeme)
+        }
+        // the value of a string is its text, the quotes are only shown when it's printed
+        TokenType::String => Ok(ASTNode::String(
+            token.lexeme[1..token.lexeme.len() - 1].to_string(),
+        )),
         TokenType::LeftParen => {
             let expr = expr_bp(lexer, 0)?;
             if lexer.next().token_type != TokenType::RightParen {
@@ -395,6 +683,23 @@ fn parse_prefix(lexer: &mut Lexer) -> ParseResult<ASTNode> {
             }
             Ok(expr)
         }
+        TokenType::LeftBracket => {
+            let mut elements = Vec::new();
+            while lexer.peek().token_type != TokenType::RightBracket {
+                elements.push(expr_bp(lexer, 0)?);
+                if lexer.peek().token_type != TokenType::COMMA {
+                    break;
+                }
+                lexer.next();
+            }
+            if lexer.next().token_type != TokenType::RightBracket {
+                return Err(ParseError::MissingToken(
+                    TokenType::RightBracket,
+                    "to close array literal".to_string(),
+                ));
+            }
+            Ok(ASTNode::Array(elements))
+        }
         TokenType::PLUS | TokenType::MINUS | TokenType::BANG => {
             let op = match token.token_type {
                 TokenType::MINUS => Ops::UnaryOp(UnaryOp::Negate),
@@ -417,6 +722,46 @@ fn parse_prefix(lexer: &mut Lexer) -> ParseResult<ASTNode> {
     }
 }
 
+/// Splits a string literal on its `${expr}`s, `\$` is a `$` that doesn't start one
+fn parse_interpolation(lexeme: &str) -> ParseResult<ASTNode> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let content = &lexeme[1..lexeme.len() - 1];
+    let mut i = 0;
+    while let Some(c) = content[i..].chars().next() {
+        i += c.len_utf8();
+        match c {
+            '\\' if content[i..].starts_with('$') => {
+                text.push('$');
+                i += 1;
+            }
+            '$' if content[i..].starts_with('{') => {
+                // the expression can hold strings and braces of its own
+                let Some(length) = interpolation_length(&content[i + 1..]) else {
+                    return Err(ParseError::SyntaxError(
+                        "Unterminated '${' in string".to_string(),
+                    ));
+                };
+                let source = content[i + 1..i + 1 + length].to_string();
+                i += length + 2;
+
+                let mut lexer = Lexer::new(source);
+                let expr = expr_bp(&mut lexer, 0)?;
+                if lexer.peek().token_type != TokenType::EOF {
+                    return Err(ParseError::SyntaxError(
+                        "Expected a single expression in '${...}'".to_string(),
+                    ));
+                }
+                parts.push(ASTNode::String(std::mem::take(&mut text)));
+                parts.push(expr);
+            }
+            c => text.push(c),
+        }
+    }
+    parts.push(ASTNode::String(text));
+    Ok(ASTNode::Interpolation(parts))
+}
+
 /// Parse postfix expressions
 fn parse_postfix(op: Ops, lhs: ASTNode, lexer: &mut Lexer) -> ParseResult<ASTNode> {
     match op {
@@ -432,6 +777,10 @@ fn parse_postfix(op: Ops, lhs: ASTNode, lexer: &mut Lexer) -> ParseResult<ASTNod
         }
         Ops::PostfixOp(PostfixOp::Call) => {
             let callee = lexer.next().lexeme;
+            // `module.name` without a call reads a member of a module
+            if lexer.peek().token_type != TokenType::LeftParen {
+                return Ok(ASTNode::Op(op, vec![lhs, ASTNode::Identifier(callee)]));
+            }
             if lexer.next().token_type != TokenType::LeftParen {
                 return Err(ParseError::MissingToken(
                     TokenType::LeftParen,
@@ -462,9 +811,42 @@ fn parse_postfix(op: Ops, lhs: ASTNode, lexer: &mut Lexer) -> ParseResult<ASTNod
 fn parse_args(lexer: &mut Lexer) -> ParseResult<Vec<ASTNode>> {
     let mut args = Vec::new();
     while lexer.peek().token_type != TokenType::RightParen {
-        args.push(expr_bp(lexer, 0)?);
-        if lexer.peek().token_type == TokenType::COMMA {
+        if lexer.peek_n_type(2) == [TokenType::Identifier, TokenType::EQUAL] {
+            let name = lexer.next().lexeme;
             lexer.next();
+            args.push(ASTNode::NamedArg(name, vec![expr_bp(lexer, 0)?]));
+        } else if matches!(args.last(), Some(ASTNode::NamedArg(..))) {
+            return Err(ParseError::SyntaxError(
+                "Positional argument after a keyword argument".to_string(),
+            ));
+        } else if lexer.peek().token_type == TokenType::ELLIPSIS {
+            lexer.next();
+            args.push(ASTNode::Spread(vec![expr_bp(lexer, 0)?]));
+        } else {
+            args.push(expr_bp(lexer, 0)?);
+        }
+
+        if matches!(args.last(), Some(ASTNode::Spread(..)))
+            && lexer.peek_n_type(2) != [TokenType::COMMA, TokenType::RightParen]
+            && lexer.peek().token_type != TokenType::RightParen
+        {
+            return Err(ParseError::SyntaxError(
+                "A spread '...' must be the last argument".to_string(),
+            ));
+        }
+
+        // `f(5 -3)` is two numbers missing a `,`, not a subtraction
+        match lexer.peek().token_type {
+            TokenType::COMMA => {
+                lexer.next();
+            }
+            TokenType::RightParen => {}
+            _ => {
+                return Err(ParseError::MissingToken(
+                    TokenType::COMMA,
+                    "between arguments".to_string(),
+                ))
+            }
         }
     }
     Ok(args)
@@ -477,6 +859,7 @@ fn infix_op(token_type: TokenType) -> Option<Ops> {
         TokenType::MINUS => Some(Ops::BinaryOp(BinaryOp::Sub)),
         TokenType::STAR => Some(Ops::BinaryOp(BinaryOp::Mul)),
         TokenType::SLASH => Some(Ops::BinaryOp(BinaryOp::Div)),
+        TokenType::PERCENT => Some(Ops::BinaryOp(BinaryOp::Mod)),
         TokenType::AT => Some(Ops::BinaryOp(BinaryOp::At)),
         TokenType::EqualEqual => Some(Ops::BinaryOp(BinaryOp::Eq)),
         TokenType::BangEqual => Some(Ops::BinaryOp(BinaryOp::Ne)),
@@ -484,6 +867,8 @@ fn infix_op(token_type: TokenType) -> Option<Ops> {
         TokenType::LessEqual => Some(Ops::BinaryOp(BinaryOp::Le)),
         TokenType::GREATER => Some(Ops::BinaryOp(BinaryOp::Gt)),
         TokenType::GreaterEqual => Some(Ops::BinaryOp(BinaryOp::Ge)),
+        TokenType::AND => Some(Ops::BinaryOp(BinaryOp::And)),
+        TokenType::OR => Some(Ops::BinaryOp(BinaryOp::Or)),
         TokenType::DOT => Some(Ops::PostfixOp(PostfixOp::Call)),
         TokenType::LeftBracket => Some(Ops::PostfixOp(PostfixOp::Index)),
         TokenType::StarStar => Some(Ops::PostfixOp(PostfixOp::StarStar)),
@@ -512,13 +897,17 @@ fn postfix_binding_power(op: Ops) -> Option<(u8, ())> {
 /// Get the binding power for infix operators
 fn infix_binding_power(op: Ops) -> Option<(u8, u8)> {
     match op {
+        Ops::BinaryOp(BinaryOp::Or) => Some((1, 2)),
+        Ops::BinaryOp(BinaryOp::And) => Some((3, 4)),
         Ops::BinaryOp(BinaryOp::Eq) | Ops::BinaryOp(BinaryOp::Ne) => Some((5, 6)),
         Ops::BinaryOp(BinaryOp::Lt)
         | Ops::BinaryOp(BinaryOp::Le)
         | Ops::BinaryOp(BinaryOp::Gt)
         | Ops::BinaryOp(BinaryOp::Ge) => Some((7, 8)),
         Ops::BinaryOp(BinaryOp::Add) | Ops::BinaryOp(BinaryOp::Sub) => Some((9, 10)),
-        Ops::BinaryOp(BinaryOp::Mul) | Ops::BinaryOp(BinaryOp::Div) => Some((11, 12)),
+        Ops::BinaryOp(BinaryOp::Mul)
+        | Ops::BinaryOp(BinaryOp::Div)
+        | Ops::BinaryOp(BinaryOp::Mod) => Some((11, 12)),
         Ops::BinaryOp(BinaryOp::At) => Some((14, 13)),
         _ => None,
     }
@@ -537,6 +926,7 @@ impl fmt::Display for Ops {
             Ops::BinaryOp(BinaryOp::Sub) => write!(f, "{}", "-".green()),
             Ops::BinaryOp(BinaryOp::Mul) => write!(f, "{}", "*".green()),
             Ops::BinaryOp(BinaryOp::Div) => write!(f, "{}", "/".green()),
+            Ops::BinaryOp(BinaryOp::Mod) => write!(f, "{}", "%".green()),
             Ops::BinaryOp(BinaryOp::At) => write!(f, "{}", "@".green()),
             Ops::BinaryOp(BinaryOp::Eq) => write!(f, "{}", "==".green()),
             Ops::BinaryOp(BinaryOp::Ne) => write!(f, "{}", "!=".green()),
@@ -544,6 +934,8 @@ impl fmt::Display for Ops {
             Ops::BinaryOp(BinaryOp::Le) => write!(f, "{}", "<=".green()),
             Ops::BinaryOp(BinaryOp::Gt) => write!(f, "{}", ">".green()),
             Ops::BinaryOp(BinaryOp::Ge) => write!(f, "{}", ">=".green()),
+            Ops::BinaryOp(BinaryOp::And) => write!(f, "{}", "and".green()),
+            Ops::BinaryOp(BinaryOp::Or) => write!(f, "{}", "or".green()),
 
             Ops::UnaryOp(UnaryOp::Negate) => write!(f, "{}", "-".green()),
             Ops::UnaryOp(UnaryOp::Not) => write!(f, "{}", "!".green()),
@@ -563,7 +955,24 @@ impl fmt::Display for ASTNode {
             ASTNode::FloatNumber(i) => write!(f, "{}", i.to_string().blue()),
             ASTNode::Identifier(s) => write!(f, "{}", s.red()),
             ASTNode::Boolean(b) => write!(f, "{}", b.to_string().yellow()),
-            ASTNode::String(s) => write!(f, "{}", s.yellow()),
+            ASTNode::String(s) => write!(f, "{}", format!("\"{}\"", s).yellow()),
+            ASTNode::Interpolation(parts) => {
+                write!(f, "(str")?;
+                for part in parts {
+                    write!(f, " {}", part)?;
+                }
+                write!(f, ")")
+            }
+            ASTNode::Array(elements) => {
+                write!(f, "[")?;
+                for (i, e) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                write!(f, "]")
+            }
             ASTNode::Callee(callee, args) => {
                 write!(f, "({}", callee.purple().magenta())?;
                 for arg in args {
@@ -571,6 +980,8 @@ impl fmt::Display for ASTNode {
                 }
                 write!(f, "{}", ")".normal().clear())
             }
+            ASTNode::NamedArg(name, value) => write!(f, "{}={}", name, value[0]),
+            ASTNode::Spread(value) => write!(f, "...{}", value[0]),
             ASTNode::Print(expr) => {
                 write!(f, "print!(")?;
                 for e in expr {
@@ -581,12 +992,41 @@ impl fmt::Display for ASTNode {
             ASTNode::Let(identifier, expr) => {
                 write!(f, "let {} = {}", identifier, expr[0])
             }
+            ASTNode::Return(value) => match value.first() {
+                Some(value) => write!(f, "return {}", value),
+                None => write!(f, "return"),
+            },
+            ASTNode::Defer(expr) => write!(f, "defer {}", expr[0]),
+            ASTNode::Break => write!(f, "break"),
+            ASTNode::Continue => write!(f, "continue"),
+            ASTNode::Throw(value) => write!(f, "throw {}", value[0]),
+            ASTNode::Try(body, catch, finally) => {
+                write!(f, "try {{{}}}", body[0])?;
+                if let Some((name, handler)) = catch {
+                    write!(f, " catch ({}) {{", name)?;
+                    for stmt in handler {
+                        write!(f, "{}", stmt)?;
+                    }
+                    write!(f, "}}")?;
+                }
+                if let Some(cleanup) = finally {
+                    write!(f, " finally {{")?;
+                    for stmt in cleanup {
+                        write!(f, "{}", stmt)?;
+                    }
+                    write!(f, "}}")?;
+                }
+                write!(f, "")
+            }
             ASTNode::Block(statements) => {
                 for stmt in statements {
                     write!(f, "{}", stmt)?;
                 }
                 write!(f, "")
             }
+            ASTNode::Import(path, None) => write!(f, "import \"{}\"", path),
+            ASTNode::Import(path, Some(alias)) => write!(f, "import \"{}\" as {}", path, alias),
+            ASTNode::Located(_, statement) => write!(f, "{}", statement),
             ASTNode::Assign(identifier, expr) => {
                 write!(f, "{} = {}", identifier, expr[0])
             }
@@ -612,6 +1052,13 @@ impl fmt::Display for ASTNode {
                 }
                 write!(f, "}}")
             }
+            ASTNode::Until(condition, body) => {
+                write!(f, "until {} {{", condition[0])?;
+                for stmt in body {
+                    write!(f, "{}", stmt)?;
+                }
+                write!(f, "}}")
+            }
             ASTNode::Op(head, rest) => {
                 write!(f, "({}", head)?;
                 for s in rest {
@@ -649,6 +1096,18 @@ pub fn ast_to_ascii(node: &ASTNode, indent: usize) -> String {
         ASTNode::Identifier(s) => writeln!(result, "{}Identifier({})", indent_str, s).unwrap(),
         ASTNode::Boolean(b) => writeln!(result, "{}Boolean({})", indent_str, b).unwrap(),
         ASTNode::String(s) => writeln!(result, "{}String(\"{}\")", indent_str, s).unwrap(),
+        ASTNode::Interpolation(parts) => {
+            writeln!(result, "{}Interpolation", indent_str).unwrap();
+            for part in parts {
+                result.push_str(&ast_to_ascii(part, indent + 1));
+            }
+        }
+        ASTNode::Array(elements) => {
+            writeln!(result, "{}Array", indent_str).unwrap();
+            for e in elements {
+                result.push_str(&ast_to_ascii(e, indent + 1));
+            }
+        }
         ASTNode::Op(op, args) => {
             writeln!(result, "{}Op({:?})", indent_str, op).unwrap();
             for arg in args {
@@ -661,6 +1120,16 @@ pub fn ast_to_ascii(node: &ASTNode, indent: usize) -> String {
                 result.push_str(&ast_to_ascii(arg, indent + 1));
             }
         }
+        ASTNode::NamedArg(name, value) => {
+            writeln!(result, "{}NamedArg({})", indent_str, name).unwrap();
+            for v in value {
+                result.push_str(&ast_to_ascii(v, indent + 1));
+            }
+        }
+        ASTNode::Spread(value) => {
+            writeln!(result, "{}Spread", indent_str).unwrap();
+            result.push_str(&ast_to_ascii(&value[0], indent + 1));
+        }
         ASTNode::Let(name, value) => {
             writeln!(result, "{}Let({})", indent_str, name).unwrap();
             for v in value {
@@ -690,8 +1159,12 @@ pub fn ast_to_ascii(node: &ASTNode, indent: usize) -> String {
                 }
             }
         }
-        ASTNode::While(condition, body) => {
-            writeln!(result, "{}While", indent_str).unwrap();
+        ASTNode::While(condition, body) | ASTNode::Until(condition, body) => {
+            let keyword = match node {
+                ASTNode::Until(..) => "Until",
+                _ => "While",
+            };
+            writeln!(result, "{}{}", indent_str, keyword).unwrap();
             writeln!(result, "{}  Condition:", indent_str).unwrap();
             for cond in condition {
                 result.push_str(&ast_to_ascii(cond, indent + 2));
@@ -709,18 +1182,61 @@ pub fn ast_to_ascii(node: &ASTNode, indent: usize) -> String {
         }
         ASTNode::Function(name, params, body) => {
             writeln!(result, "{}Function({})", indent_str, name).unwrap();
+            let params: Vec<String> = params.iter().map(|param| param.to_string()).collect();
             writeln!(result, "{}  Parameters: {:?}", indent_str, params).unwrap();
             writeln!(result, "{}  Body:", indent_str).unwrap();
             for stmt in body {
                 result.push_str(&ast_to_ascii(stmt, indent + 2));
             }
         }
+        ASTNode::Return(value) => {
+            writeln!(result, "{}Return", indent_str).unwrap();
+            for v in value {
+                result.push_str(&ast_to_ascii(v, indent + 1));
+            }
+        }
+        ASTNode::Try(body, catch, finally) => {
+            writeln!(result, "{}Try", indent_str).unwrap();
+            for stmt in body {
+                result.push_str(&ast_to_ascii(stmt, indent + 1));
+            }
+            if let Some((name, handler)) = catch {
+                writeln!(result, "{}  Catch {}:", indent_str, name).unwrap();
+                for stmt in handler {
+                    result.push_str(&ast_to_ascii(stmt, indent + 2));
+                }
+            }
+            if let Some(cleanup) = finally {
+                writeln!(result, "{}  Finally:", indent_str).unwrap();
+                for stmt in cleanup {
+                    result.push_str(&ast_to_ascii(stmt, indent + 2));
+                }
+            }
+        }
+        ASTNode::Defer(expr) => {
+            writeln!(result, "{}Defer", indent_str).unwrap();
+            for e in expr {
+                result.push_str(&ast_to_ascii(e, indent + 1));
+            }
+        }
+        ASTNode::Throw(value) => {
+            writeln!(result, "{}Throw", indent_str).unwrap();
+            for v in value {
+                result.push_str(&ast_to_ascii(v, indent + 1));
+            }
+        }
         ASTNode::Block(statements) => {
             writeln!(result, "{}Block", indent_str).unwrap();
             for stmt in statements {
                 result.push_str(&ast_to_ascii(stmt, indent + 1));
             }
         }
+        ASTNode::Break => writeln!(result, "{}Break", indent_str).unwrap(),
+        ASTNode::Continue => writeln!(result, "{}Continue", indent_str).unwrap(),
+        ASTNode::Import(path, alias) => {
+            writeln!(result, "{}Import(\"{}\", {:?})", indent_str, path, alias).unwrap()
+        }
+        ASTNode::Located(_, statement) => result.push_str(&ast_to_ascii(statement, indent)),
     }
 
     result
@@ -755,6 +1271,9 @@ mod tests {
         let s = expr("f @ g @ h");
         assert_eq!(s, "(@ f (@ g h))");
 
+        let s = expr("a + b % c * d");
+        assert_eq!(s, "(+ a (* (% b c) d))");
+
         let s = expr("1 + 2 + f @ g @ h * 3 * 4");
         assert_eq!(s, "(+ (+ 1 2) (* (* (@ f (@ g h)) 3) 4))");
 
@@ -782,17 +1301,35 @@ mod tests {
         let s = expr("x.relu(0, 1).relu(2, 3)");
         assert_eq!(s, "(. (. x (relu 0 1)) (relu 2 3))");
 
-        let s = expr("x.relu(a.b(0+2), 2-1).max(0)/2");
+        let s = expr("x.relu(a.b(0+2), 2, -1).max(0)/2");
         assert_eq!(s, "(/ (. (. x (relu (. a (b (+ 0 2))) 2 -1)) (max 0)) 2)");
 
         let s = expr("x.relu(a.sigmoid(0+2))");
         assert_eq!(s, "(. x (relu (. a (sigmoid (+ 0 2)))))");
 
+        let s = expr("a or b and c == d");
+        assert_eq!(s, "(or a (and b (== c d)))");
+
+        let s = expr("a and b or c and d");
+        assert_eq!(s, "(or (and a b) (and c d))");
+
+        let s = expr("randn(2, cols = a + 1)");
+        assert_eq!(s, "(randn 2 cols=(+ a 1))");
+
+        let s = expr("relu(x, f()) + 1");
+        assert_eq!(s, "(+ (relu x (f)) 1)");
+
         let s = expr("a == b");
         assert_eq!(s, "(== a b)");
 
         let s = expr("--1");
         assert_eq!(s, "(- -1)");
+
+        let s = expr("[1, [2, a + b], []]");
+        assert_eq!(s, "[1 [2 (+ a b)] []]");
+
+        let s = expr("[1, 2][0]");
+        assert_eq!(s, "([ [1 2] 0)");
     }
 
     #[test]
@@ -824,5 +1361,66 @@ mod tests {
         // function definition test
         let s = parse("fn add(a, b) { a + b; }");
         assert_eq!(s, "fn add(a, b) {(+ a b)}");
+
+        let s = parse("fn add(a, b = -1) { a + b; }");
+        assert_eq!(s, "fn add(a, b=-1) {(+ a b)}");
+
+        // the arguments are separated by commas, `5-3` is lexed as `5` and `-3`
+        let s = parse("print(5 - 3, f(a, 1));");
+        assert_eq!(s, "print!((- 5 3), (f a 1), )");
+        for source in ["print(5-3);", "f(5-3);", "print(1 2);"] {
+            let mut lexer = Lexer::new(source.to_string());
+            assert_eq!(
+                Parser::new(&mut lexer).parse().unwrap_err().to_string(),
+                "Missing token COMMA between arguments",
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_recovering() {
+        let mut lexer = Lexer::new("let = 1;\nprint(2);\n  let b = (;\nprint(b);".to_string());
+        let (statements, diagnostics) = Parser::new(&mut lexer).parse_recovering();
+
+        let statements: Vec<String> = statements.iter().map(|s| s.to_string()).collect();
+        assert_eq!(statements.len(), 2);
+        let positions: Vec<(usize, usize)> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.column))
+            .collect();
+        assert_eq!(positions, vec![(1, 7), (3, 12)]);
+    }
+
+    #[test]
+    fn test_newline_terminated_statements() {
+        fn parse(source: &str) -> ParseResult<Vec<String>> {
+            let mut lexer = Lexer::new(source.to_string());
+            let out = Parser::new(&mut lexer).parse()?;
+            Ok(out.iter().map(|statement| statement.to_string()).collect())
+        }
+
+        let s = parse("let a = 1\na += 2;\nprint(a)\nif (a > 1) print(a) else { a }").unwrap();
+        assert_eq!(
+            s,
+            vec![
+                "let a = 1",
+                "a = (+ a 2)",
+                "print!(a, )",
+                "if (> a 1) {print!(a, )} else {a}"
+            ]
+        );
+
+        // an operator or a `.` starting a line goes on with the expression of the
+        // line before, a `(` or `[` starts a new statement
+        let s = parse("let b = a\n    * 2\n    .relu()\n(b)\n[b]").unwrap();
+        assert_eq!(s, vec!["let b = (* a (. 2 (relu)))", "b", "[b]"]);
+        let s = parse("let b = a(\n  1,\n  2\n)[0]").unwrap();
+        assert_eq!(s, vec!["let b = ([ (a 1 2) 0)"]);
+
+        // on one line, the statements still need a `;`
+        assert!(parse("let a = 1 let b = 2").is_err());
+        assert!(parse("let a = 1; let b = 2").is_ok());
     }
 }
@@ -1,13 +1,17 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 /// This module contains the implementation of the Chunk struct and its methods.
 /// The Chunk struct is used to store the bytecode and the constants.
-use crate::value::ValueType;
+use crate::{interner::StringObjIdx, native::TENSOR_METHODS, value::ValueType};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum OpCode {
     OpConstant,
+    /// an integer from 0 to 255 held in its operand instead of the constants
+    OpConstantSmall,
     OpNil,
     OpTrue,
     OpFalse,
@@ -16,7 +20,10 @@ pub enum OpCode {
     OpSubtract,
     OpMultiply,
     OpDivide,
+    /// the remainder of the division, with the sign of the dividend
+    OpModulo,
     OpPower,
+    OpMatMul,
 
     OpNot,
     OpEqualEqual,
@@ -24,6 +31,9 @@ pub enum OpCode {
     OpLess,
     OpReturn,
     OpPrint,
+    /// the value on top of the stack as the string `print` shows, a string
+    /// without its quotes
+    OpToString,
     OpPop,
     OpDefineGlobal,
     OpGetGlobal,
@@ -36,21 +46,141 @@ pub enum OpCode {
     OpJumpIfFalse,
     OpJump,
     OpLoop,
+    /// starts a `try` body, a runtime error until the `OpEndTry` jumps to the
+    /// handler at the operand with the message on the stack
+    OpTry,
+    /// like `OpTry` for a `finally`, the handler gets the error and whether it
+    /// was thrown, and `OpRethrow` raises it again
+    OpFinally,
+    OpEndTry,
+    OpRethrow,
+    /// a runtime error whose value, on top of the stack, is what `catch` gets
+    OpThrow,
+
+    OpBuildArray,
+    /// a call by name, the first operand is the global slot of the name like for
+    /// `OpGetGlobal`
+    OpCall,
+    /// a call of the tensor method whose index in `TENSOR_METHODS` is the first
+    /// operand, so the VM doesn't look its name up
+    OpCallMethod,
+    OpCallValue,
+    OpCallNamed,
+    /// like `OpCall` with an array on top of the stack whose elements are passed
+    /// after the other arguments, the operand counts the others
+    OpCallSpread,
+    OpTailCall,
+    OpGetField,
+    /// `a[i]`, the index is on top of the array or string
+    OpIndex,
+}
+
+/// Every opcode, in the order of their byte values
+pub(crate) const OPCODES: &[OpCode] = &[
+    OpCode::OpConstant,
+    OpCode::OpConstantSmall,
+    OpCode::OpNil,
+    OpCode::OpTrue,
+    OpCode::OpFalse,
+    OpCode::OpNegate,
+    OpCode::OpAdd,
+    OpCode::OpSubtract,
+    OpCode::OpMultiply,
+    OpCode::OpDivide,
+    OpCode::OpModulo,
+    OpCode::OpPower,
+    OpCode::OpMatMul,
+    OpCode::OpNot,
+    OpCode::OpEqualEqual,
+    OpCode::OpGreater,
+    OpCode::OpLess,
+    OpCode::OpReturn,
+    OpCode::OpPrint,
+    OpCode::OpToString,
+    OpCode::OpPop,
+    OpCode::OpDefineGlobal,
+    OpCode::OpGetGlobal,
+    OpCode::OpSetGlobal,
+    OpCode::OpDefineLocal,
+    OpCode::OpGetLocal,
+    OpCode::OpSetLocal,
+    OpCode::OpJumpIfFalse,
+    OpCode::OpJump,
+    OpCode::OpLoop,
+    OpCode::OpTry,
+    OpCode::OpFinally,
+    OpCode::OpEndTry,
+    OpCode::OpRethrow,
+    OpCode::OpThrow,
+    OpCode::OpBuildArray,
+    OpCode::OpCall,
+    OpCode::OpCallMethod,
+    OpCode::OpCallValue,
+    OpCode::OpCallNamed,
+    OpCode::OpCallSpread,
+    OpCode::OpTailCall,
+    OpCode::OpGetField,
+    OpCode::OpIndex,
+];
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
 
-    // OpCall,
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        OPCODES.get(byte as usize).copied().ok_or(byte)
+    }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub enum VectorType {
-    Constant(usize),
-    Code(OpCode),
+impl OpCode {
+    /// Width in bytes of each operand that follows the opcode. Constant indices,
+    /// global slots, array sizes and jump targets are shorts, local slots,
+    /// argument counts, method ids and small integers are bytes
+    pub fn operands(&self) -> &'static [usize] {
+        match self {
+            OpCode::OpConstant
+            | OpCode::OpDefineGlobal
+            | OpCode::OpGetGlobal
+            | OpCode::OpSetGlobal
+            | OpCode::OpBuildArray
+            | OpCode::OpGetField
+            | OpCode::OpJumpIfFalse
+            | OpCode::OpJump
+            | OpCode::OpLoop
+            | OpCode::OpTry
+            | OpCode::OpFinally => &[2],
+            OpCode::OpDefineLocal
+            | OpCode::OpGetLocal
+            | OpCode::OpSetLocal
+            | OpCode::OpPrint
+            | OpCode::OpCallValue
+            | OpCode::OpConstantSmall => &[1],
+            OpCode::OpCall | OpCode::OpCallSpread | OpCode::OpTailCall => &[2, 1],
+            OpCode::OpCallMethod => &[1, 1],
+            OpCode::OpCallNamed => &[2, 1, 2],
+            _ => &[],
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chunk {
-    /// VectorType is either a index to the constants or an OpCode, see `VectorType` enum
-    pub code: Vec<VectorType>,
+    /// Opcodes, each followed by its operands, see `OpCode::operands`. Shorts are big endian
+    pub code: Vec<u8>,
     pub constants: Vec<ValueType>,
+    /// Index of every plain constant, so `add_constant` reuses one without a scan
+    #[serde(skip)]
+    constant_indices: HashMap<ConstKey, usize>,
+    /// Name of every global slot, `OpDefineGlobal`/`OpGetGlobal`/`OpSetGlobal` and the
+    /// calls by name take the slot
+    pub global_names: Vec<StringObjIdx>,
+    /// Source line of the code from an offset on, as `(offset, line)` pairs in offset order
+    pub lines: Vec<(usize, usize)>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -58,21 +188,251 @@ impl Chunk {
         Self {
             code: Vec::new(),
             constants: Vec::new(),
+            constant_indices: HashMap::new(),
+            global_names: Vec::new(),
+            lines: Vec::new(),
         }
     }
 
-    pub fn write(&mut self, byte: VectorType) {
+    pub fn write(&mut self, byte: u8) {
         self.code.push(byte);
     }
 
+    pub fn write_op(&mut self, op: OpCode) {
+        self.code.push(op as u8);
+    }
+
+    /// Writes a two byte operand, `value` is truncated to 16 bits, the compiler
+    /// checks that its operands fit
+    pub fn write_short(&mut self, value: usize) {
+        self.code.extend((value as u16).to_be_bytes());
+    }
+
+    /// Overwrites the short at `offset`, for jumps whose target is only known later
+    pub fn patch_short(&mut self, offset: usize, value: usize) {
+        self.code[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+    }
+
+    /// The code written from now on comes from `line`
+    pub fn mark_line(&mut self, line: usize) {
+        match self.lines.last_mut() {
+            Some((offset, last)) if *offset == self.code.len() => *last = line,
+            Some((_, last)) if *last == line => {}
+            _ => self.lines.push((self.code.len(), line)),
+        }
+    }
+
+    /// Line of the code at `offset`, `None` for code the compiler marked no line for
+    pub fn line_of(&self, offset: usize) -> Option<usize> {
+        let entry = self
+            .lines
+            .partition_point(|&(start, _)| start <= offset)
+            .checked_sub(1)?;
+        Some(self.lines[entry].1)
+    }
+
+    /// Line of the instruction at `offset` if it's the first one of the line,
+    /// where a breakpoint on the line stops
+    pub fn starts_line(&self, offset: usize) -> Option<usize> {
+        let entry = self
+            .lines
+            .binary_search_by_key(&offset, |&(start, _)| start);
+        entry.ok().map(|entry| self.lines[entry].1)
+    }
+
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        self.code[offset]
+    }
+
+    pub fn read_short(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    /// The index of `value` in the constants. A number, bool, string or name
+    /// already in the pool is reused, tensors, arrays, functions and modules are
+    /// always added since two of them are distinct even when they look the same
     pub fn add_constant(&mut self, value: ValueType) -> usize {
+        let key = ConstKey::of(&value);
+        if let Some(index) = key.and_then(|key| self.constant_indices.get(&key)) {
+            return *index;
+        }
         self.constants.push(value);
-        self.constants.len() - 1 // return the index of the constant
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        index
+    }
+
+    /// Checks that the chunk can be run: every opcode is known and has all its
+    /// operands, constant indices and global slots are in bounds, names (of
+    /// callees, fields and keywords) are identifiers and `OpConstant` never loads
+    /// one, jumps land on an instruction and the code ends in a return. Function bodies are checked
+    /// too. Returns a description of the first problem found
+    pub fn verify(&self) -> Result<(), String> {
+        self.verify_code(self.global_names.len())
+    }
+
+    /// `globals` is the number of global slots, function chunks share the script's
+    fn verify_code(&self, globals: usize) -> Result<(), String> {
+        let mut instructions = Vec::new();
+        let mut jumps = Vec::new();
+        let mut last = None;
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let op = OpCode::try_from(self.code[offset])
+                .map_err(|byte| format!("Unknown opcode {} at {}", byte, offset))?;
+            let widths = op.operands();
+            let end = offset + 1 + widths.iter().sum::<usize>();
+            if end > self.code.len() {
+                return Err(format!(
+                    "Missing operand of {} at {}, past the end of the code",
+                    op, offset
+                ));
+            }
+
+            let constant = |at: usize| {
+                let index = self.read_short(at) as usize;
+                match index < self.constants.len() {
+                    true => Ok(()),
+                    false => Err(format!(
+                        "Constant index {} at {} is out of bounds ({} constants)",
+                        index,
+                        offset,
+                        self.constants.len()
+                    )),
+                }
+            };
+            // identifiers and strings are both interned, only the tag tells a name
+            // from a string value
+            let name = |at: usize| {
+                constant(at)?;
+                let index = self.read_short(at) as usize;
+                match &self.constants[index] {
+                    ValueType::Identifier(_) => Ok(()),
+                    value => Err(format!(
+                        "Constant {} at {} is a {}, expected a name",
+                        index,
+                        offset,
+                        value.type_name()
+                    )),
+                }
+            };
+            match op {
+                OpCode::OpConstant => {
+                    constant(offset + 1)?;
+                    let index = self.read_short(offset + 1) as usize;
+                    if let ValueType::Identifier(_) = self.constants[index] {
+                        return Err(format!(
+                            "Constant {} at {} is a name, not a value",
+                            index, offset
+                        ));
+                    }
+                }
+                OpCode::OpGetField => name(offset + 1)?,
+                OpCode::OpCallNamed => {
+                    name(offset + 1)?;
+                    constant(offset + 4)?;
+                    let index = self.read_short(offset + 4) as usize;
+                    let names = match &self.constants[index] {
+                        ValueType::Array(names) => names
+                            .borrow()
+                            .iter()
+                            .all(|name| matches!(name, ValueType::Identifier(_))),
+                        _ => false,
+                    };
+                    if !names {
+                        return Err(format!(
+                            "Constant {} at {} isn't an array of keyword names",
+                            index, offset
+                        ));
+                    }
+                }
+                OpCode::OpDefineGlobal
+                | OpCode::OpGetGlobal
+                | OpCode::OpSetGlobal
+                | OpCode::OpCall
+                | OpCode::OpCallSpread
+                | OpCode::OpTailCall => {
+                    let slot = self.read_short(offset + 1) as usize;
+                    if slot >= globals {
+                        return Err(format!(
+                            "Global slot {} at {} is out of bounds ({} globals)",
+                            slot, offset, globals
+                        ));
+                    }
+                }
+                OpCode::OpCallMethod => {
+                    let id = self.read_byte(offset + 1) as usize;
+                    if id >= TENSOR_METHODS.len() {
+                        return Err(format!(
+                            "Method id {} at {} is out of bounds ({} methods)",
+                            id,
+                            offset,
+                            TENSOR_METHODS.len()
+                        ));
+                    }
+                }
+                OpCode::OpJump
+                | OpCode::OpJumpIfFalse
+                | OpCode::OpLoop
+                | OpCode::OpTry
+                | OpCode::OpFinally => jumps.push((offset, self.read_short(offset + 1) as usize)),
+                _ => {}
+            }
+
+            instructions.push(offset);
+            last = Some(op);
+            offset = end;
+        }
+
+        for (offset, target) in jumps {
+            if target >= self.code.len() {
+                return Err(format!(
+                    "Jump at {} targets {}, past the end of the code ({})",
+                    offset,
+                    target,
+                    self.code.len()
+                ));
+            }
+            if instructions.binary_search(&target).is_err() {
+                return Err(format!(
+                    "Jump at {} targets {}, in the middle of an instruction",
+                    offset, target
+                ));
+            }
+        }
+
+        if last != Some(OpCode::OpReturn) {
+            return Err("The code doesn't end in a return".to_string());
+        }
+
+        for constant in &self.constants {
+            match constant {
+                ValueType::Function(function) => function
+                    .chunk
+                    .verify_code(globals)
+                    .map_err(|e| format!("In {}: {}", function.name, e))?,
+                ValueType::Module(module) => {
+                    if let Some((_, slot)) =
+                        module.members.iter().find(|(_, slot)| *slot >= globals)
+                    {
+                        return Err(format!(
+                            "Global slot {} of module {} is out of bounds ({} globals)",
+                            slot, module.name, globals
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
     }
 }
 
 ////////////////////////
-/// Display impls
+// Display impls
 ////////////////////////
 
 impl std::fmt::Display for OpCode {
@@ -80,12 +440,15 @@ impl std::fmt::Display for OpCode {
         match self {
             OpCode::OpReturn => write!(f, "OP_RETURN"),
             OpCode::OpConstant => write!(f, "OP_CONSTANT"),
+            OpCode::OpConstantSmall => write!(f, "OP_CONSTANT_SMALL"),
             OpCode::OpNegate => write!(f, "OP_NEGATE"),
             OpCode::OpAdd => write!(f, "OP_ADD"),
             OpCode::OpSubtract => write!(f, "OP_SUBTRACT"),
             OpCode::OpMultiply => write!(f, "OP_MULTIPLY"),
             OpCode::OpDivide => write!(f, "OP_DIVIDE"),
+            OpCode::OpModulo => write!(f, "OP_MODULO"),
             OpCode::OpPower => write!(f, "OP_POWER"),
+            OpCode::OpMatMul => write!(f, "OP_MATMUL"),
 
             OpCode::OpNil => write!(f, "OP_NIL"),
             OpCode::OpTrue => write!(f, "OP_TRUE"),
@@ -95,6 +458,7 @@ impl std::fmt::Display for OpCode {
             OpCode::OpGreater => write!(f, "OP_GREATER"),
             OpCode::OpLess => write!(f, "OP_LESS"),
             OpCode::OpPrint => write!(f, "OP_PRINT"),
+            OpCode::OpToString => write!(f, "OP_TO_STRING"),
             OpCode::OpPop => write!(f, "OP_POP"),
             OpCode::OpDefineGlobal => write!(f, "OP_DEFINE_GLOBAL"),
             OpCode::OpGetGlobal => write!(f, "OP_GET_GLOBAL"),
@@ -107,17 +471,203 @@ impl std::fmt::Display for OpCode {
             OpCode::OpJumpIfFalse => write!(f, "OP_JUMP_IF_FALSE"),
             OpCode::OpJump => write!(f, "OP_JUMP"),
             OpCode::OpLoop => write!(f, "OP_LOOP"),
+            OpCode::OpTry => write!(f, "OP_TRY"),
+            OpCode::OpFinally => write!(f, "OP_FINALLY"),
+            OpCode::OpEndTry => write!(f, "OP_END_TRY"),
+            OpCode::OpRethrow => write!(f, "OP_RETHROW"),
+            OpCode::OpThrow => write!(f, "OP_THROW"),
+
+            OpCode::OpBuildArray => write!(f, "OP_BUILD_ARRAY"),
 
-            // OpCode::OpCall => write!(f, "OP_CALL"),
+            OpCode::OpCall => write!(f, "OP_CALL"),
+            OpCode::OpCallMethod => write!(f, "OP_CALL_METHOD"),
+            OpCode::OpCallValue => write!(f, "OP_CALL_VALUE"),
+            OpCode::OpCallNamed => write!(f, "OP_CALL_NAMED"),
+            OpCode::OpCallSpread => write!(f, "OP_CALL_SPREAD"),
+            OpCode::OpTailCall => write!(f, "OP_TAIL_CALL"),
+            OpCode::OpGetField => write!(f, "OP_GET_FIELD"),
+            OpCode::OpIndex => write!(f, "OP_INDEX"),
         }
     }
 }
 
-impl std::fmt::Display for VectorType {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            VectorType::Constant(c) => write!(f, "cons->[{}]", c),
-            VectorType::Code(op) => write!(f, "{}", op),
+/// What a plain constant is looked up by in the pool. Floats are keyed by their
+/// bits, so `0.0` and `-0.0` stay apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Integer(i64),
+    Float(u64),
+    Boolean(bool),
+    String(StringObjIdx),
+    Identifier(StringObjIdx),
+    Nil,
+}
+
+impl ConstKey {
+    /// `None` for the constants that are always added
+    fn of(value: &ValueType) -> Option<Self> {
+        match value {
+            ValueType::Integer(n) => Some(ConstKey::Integer(*n)),
+            ValueType::Float(n) => Some(ConstKey::Float(n.to_bits())),
+            ValueType::Boolean(b) => Some(ConstKey::Boolean(*b)),
+            ValueType::String(idx) => Some(ConstKey::String(*idx)),
+            ValueType::Identifier(idx) => Some(ConstKey::Identifier(*idx)),
+            ValueType::Nil => Some(ConstKey::Nil),
+            _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, interner::Interner};
+
+    #[test]
+    fn test_verify_compiled_chunk() {
+        let chunk = compile(
+            r#"
+            fn sum(n, total = 0) {
+                if (n == 0) { return total; }
+                return sum(n - 1, total + n);
+            }
+            let i = 0;
+            while (i < 3) { print(sum(i), [i]); i = i + 1; }
+            "#,
+            &mut Interner::default(),
+        )
+        .unwrap();
+
+        assert_eq!(chunk.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_out_of_range_jump() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::OpJump);
+        chunk.write_short(42);
+        chunk.write_op(OpCode::OpReturn);
+
+        assert_eq!(
+            chunk.verify(),
+            Err("Jump at 0 targets 42, past the end of the code (4)".to_string())
+        );
+
+        chunk.patch_short(1, 2);
+        assert_eq!(
+            chunk.verify(),
+            Err("Jump at 0 targets 2, in the middle of an instruction".to_string())
+        );
+
+        chunk.patch_short(1, 3);
+        assert_eq!(chunk.verify(), Ok(()));
+        chunk.code.pop();
+        chunk.write_op(OpCode::OpPop);
+        assert_eq!(
+            chunk.verify(),
+            Err("The code doesn't end in a return".to_string())
+        );
+    }
+
+    #[test]
+    fn test_line_of() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::OpNil);
+        chunk.mark_line(2);
+        chunk.write_op(OpCode::OpNil);
+        chunk.write_op(OpCode::OpPop);
+        chunk.mark_line(5);
+        chunk.write_op(OpCode::OpReturn);
+
+        let lines: Vec<_> = (0..4).map(|offset| chunk.line_of(offset)).collect();
+        assert_eq!(lines, [None, Some(2), Some(2), Some(5)]);
+        assert_eq!(chunk.starts_line(2), None);
+    }
+
+    #[test]
+    fn test_verify_names() {
+        let mut chunk = Chunk::new();
+        let name = chunk.add_constant(ValueType::Identifier(0));
+        let string = chunk.add_constant(ValueType::String(0));
+        chunk.write_op(OpCode::OpGetField);
+        chunk.write_short(string);
+        chunk.write_op(OpCode::OpReturn);
+
+        // a string literal where a field name is expected
+        assert_eq!(
+            chunk.verify(),
+            Err("Constant 1 at 0 is a string, expected a name".to_string())
+        );
+        chunk.patch_short(1, name);
+        assert_eq!(chunk.verify(), Ok(()));
+
+        // and a name loaded as a value
+        chunk.code[0] = OpCode::OpConstant as u8;
+        assert_eq!(
+            chunk.verify(),
+            Err("Constant 0 at 0 is a name, not a value".to_string())
+        );
+        chunk.patch_short(1, string);
+        assert_eq!(chunk.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_method_id() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::OpCallMethod);
+        chunk.write(TENSOR_METHODS.len() as u8);
+        chunk.write(1);
+        chunk.write_op(OpCode::OpReturn);
+
+        assert_eq!(
+            chunk.verify(),
+            Err(format!(
+                "Method id {0} at 0 is out of bounds ({0} methods)",
+                TENSOR_METHODS.len()
+            ))
+        );
+        chunk.code[1] = 0;
+        assert_eq!(chunk.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_opcodes_are_contiguous() {
+        // the byte of an opcode is its index, so the VM's `match` on the opcode is
+        // a dense jump table
+        for (byte, op) in OPCODES.iter().enumerate() {
+            assert_eq!(*op as usize, byte);
+            assert_eq!(OpCode::try_from(byte as u8), Ok(*op));
+        }
+        assert_eq!(
+            OpCode::try_from(OPCODES.len() as u8),
+            Err(OPCODES.len() as u8)
+        );
+    }
+
+    #[test]
+    fn test_multi_byte_operands() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::OpCall);
+        chunk.write_short(300);
+        chunk.write(7);
+        chunk.write_short(u16::MAX as usize);
+
+        assert_eq!(chunk.code, vec![OpCode::OpCall as u8, 1, 44, 7, 255, 255]);
+        assert_eq!(OpCode::try_from(chunk.read_byte(0)), Ok(OpCode::OpCall));
+        assert_eq!(chunk.read_short(1), 300);
+        assert_eq!(chunk.read_byte(3), 7);
+        assert_eq!(chunk.read_short(4), u16::MAX);
+
+        chunk.patch_short(1, 0x1234);
+        assert_eq!(chunk.read_short(1), 0x1234);
+        assert_eq!(chunk.read_byte(3), 7);
+
+        // a call missing its argument count
+        chunk.code.truncate(3);
+        assert_eq!(
+            chunk.verify(),
+            Err("Missing operand of OP_CALL at 0, past the end of the code".to_string())
+        );
+        assert_eq!(OpCode::try_from(200), Err(200));
+    }
+}
@@ -1,16 +1,39 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use crate::{
-    ast::{ASTNode, BinaryOp, Ops, PostfixOp, UnaryOp},
-    chunk::{Chunk, OpCode, VectorType},
-    interner::Interner,
-    tensor::Tensor,
-    value::ValueType,
+    ast::{ASTNode, BinaryOp, Ops, Parser, PostfixOp, UnaryOp},
+    chunk::{Chunk, OpCode},
+    diagnostic::Diagnostic,
+    interner::{Interner, StringObjIdx},
+    native::{self, NATIVES, TENSOR_METHODS},
+    scanner::{Lexer, Position},
+    value::{Function, Module, ValueType},
+    vm::Capabilities,
 };
 
 #[derive(Debug, Clone, Default)]
 struct Local {
     name: String,
     depth: u8,
+    // false while its initializer is compiled, which can't read it
+    initialized: bool,
+}
+
+/// A loop being compiled, with what was open when it started: a `break` or
+/// `continue` leaves the locals, deferred expressions and `try`s opened since
+#[derive(Debug, Clone, Default)]
+struct Loop {
+    start: usize,
+    local_count: usize,
+    deferred: usize,
+    tries: usize,
+    // the `OpJump`s of its `break`s, patched to the end of the loop
+    breaks: Vec<usize>,
 }
 
 // impl display for Local
@@ -20,39 +43,68 @@ impl std::fmt::Display for Local {
     }
 }
 
-#[derive(Debug, Clone)]
-struct Function {
-    name: String,
-    arity: u8,
-    chunk: Chunk,
-}
-
-impl Function {
-    pub fn new(name: String, arity: u8) -> Self {
-        Self {
-            name,
-            arity,
-            chunk: Chunk::new(),
-        }
-    }
-}
-
 pub struct Compiler {
     chunk: Chunk,
     interner: Interner,
+    global_slots: HashMap<StringObjIdx, usize>,
+    global_names: Vec<StringObjIdx>,
 
     locals: Vec<Local>,
     local_count: usize,
     scope_depth: u8,
+    // the statement being compiled is the last of the program, if it's an
+    // expression its value is left on the stack as the program's result
+    last_statement: bool,
+    // the `defer`red expressions of the function being compiled and the depth of
+    // the scope they were deferred in, in the order they were deferred
+    deferred: Vec<(u8, ASTNode)>,
+    // the loops the statement being compiled is in, innermost last
+    loops: Vec<Loop>,
+    // number of `OpTry` and `OpFinally` handlers open at the statement being compiled
+    tries: usize,
+
+    // number of functions being compiled, the innermost one is in `chunk`
+    function_depth: usize,
+    // name of the innermost function being compiled
+    function_name: Option<String>,
+
+    // compilation goes on after an error, so every error is reported at once
+    errors: Vec<Diagnostic>,
+    // where the statement being compiled starts
+    position: Position,
 
-    functions: Vec<Function>,
-    function_count: usize,
+    // inline the globals that are only ever defined once, to a constant
+    optimize: bool,
+    // globals defined once at the top level and never assigned, see `write_once_globals`
+    write_once: HashSet<String>,
+    // the write once globals already defined, reads after their `let` use the value
+    inlined: HashMap<String, ValueType>,
+
+    // the file being compiled, imports are found relative to it
+    source_path: Option<PathBuf>,
+    // the files being imported, innermost last, to find import cycles
+    importing: Vec<PathBuf>,
+    // every file imported so far and the module it was imported as, a file is
+    // only imported once in each
+    imported: HashSet<(PathBuf, Option<String>)>,
+    // the prefix of the module being imported and its top-level names, which
+    // are compiled to the globals `prefix.name`
+    namespace: Option<(String, HashSet<String>)>,
+    // globals holding a module, `module.f()` calls the member `f`
+    modules: HashSet<String>,
+    // what the program will be run with, natives are only built at compile
+    // time when the VM could call them
+    capabilities: Capabilities,
+    // the program defines its own `tensor`, in a part compiled so far
+    defines_tensor: bool,
 }
 
 // write a macro that can take single or multiple opcodes and write them to the chunk, (without mentioning self.chunk)
 macro_rules! write_op {
     ($chunk:expr, $($op:expr),*) => {
-        $( $chunk.write(VectorType::Code($op)))*
+        {
+            $( $chunk.write_op($op); )*
+        }
     };
 }
 
@@ -62,49 +114,671 @@ macro_rules! add_con {
     };
 }
 
-macro_rules! write_cons {
-    ($chunk:expr, $code:expr) => {
-        $chunk.write(VectorType::Constant($code))
+// a two byte operand: constant index, global slot, array size or jump target
+macro_rules! write_short {
+    ($chunk:expr, $operand:expr) => {
+        $chunk.write_short($operand)
     };
 }
 
+// a one byte operand: local slot or argument count
+macro_rules! write_byte {
+    ($chunk:expr, $operand:expr) => {
+        $chunk.write($operand as u8)
+    };
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Compiler {
     pub fn new() -> Self {
         Self {
             chunk: Chunk::new(),
             interner: Interner::default(),
+            global_slots: HashMap::new(),
+            global_names: Vec::new(),
             locals: Vec::new(),
             local_count: 0,
             scope_depth: 0,
-            functions: Vec::new(),
-            function_count: 0,
+            last_statement: false,
+            deferred: Vec::new(),
+            loops: Vec::new(),
+            tries: 0,
+            function_depth: 0,
+            function_name: None,
+            errors: Vec::new(),
+            position: Position::default(),
+            optimize: false,
+            write_once: HashSet::new(),
+            inlined: HashMap::new(),
+            source_path: None,
+            importing: Vec::new(),
+            imported: HashSet::new(),
+            namespace: None,
+            modules: HashSet::new(),
+            capabilities: Capabilities::ALL,
+            defines_tensor: false,
+        }
+    }
+
+    /// A compiler whose strings are interned in `interner`
+    pub fn with_interner(interner: Interner) -> Self {
+        Self {
+            interner,
+            ..Self::new()
+        }
+    }
+
+    /// Makes reads of a global defined once to a constant, and never assigned,
+    /// load the constant instead. Only for a program compiled all at once, a
+    /// part compiled later (like a REPL line) could redefine the global
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// What the VM running the program can call, `tensor(...)` of literals is
+    /// only built at compile time when natives are enabled
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// The file the source comes from, the paths it imports are relative to its
+    /// directory. Without one they are relative to the working directory
+    pub fn set_source_path(&mut self, path: impl AsRef<Path>) {
+        self.source_path = Some(path.as_ref().canonicalize().unwrap_or(path.as_ref().into()));
+    }
+
+    /// Every call compiles into a fresh chunk, globals keep the slots they got
+    /// in the previous calls
+    pub fn compile(&mut self, ast: Vec<ASTNode>) -> Result<(Chunk, Interner), Vec<Diagnostic>> {
+        self.chunk = Chunk::new();
+        self.errors.clear();
+        self.inlined.clear();
+        if self.optimize {
+            self.write_once = write_once_globals(&ast);
+        }
+        self.defines_tensor |= defines(&ast, "tensor");
+
+        let count = ast.len();
+        for (i, stmt) in ast.into_iter().enumerate() {
+            self.last_statement = i + 1 == count;
+            self.visit_statement(stmt);
+        }
+        self.check_chunk_size();
+        if self.global_names.len() > u16::MAX as usize + 1 {
+            self.error("Too many global variables.");
+        }
+
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+
+        // ends the script, taking the value of the last statement if it left one
+        write_op!(self.chunk, OpCode::OpReturn);
+        self.chunk.global_names = self.global_names.clone();
+
+        Ok((self.chunk.clone(), self.interner.clone()))
+    }
+
+    /// Compiles one more part of a program, like a REPL line, which can use the
+    /// globals of the parts compiled before. Strings are interned in `interner`.
+    /// The statements that parse are compiled even when others don't, so the
+    /// syntax and compile errors are all reported together, in source order
+    pub fn compile_source(
+        &mut self,
+        source: &str,
+        interner: &mut Interner,
+    ) -> Result<Chunk, Vec<Diagnostic>> {
+        let mut lexer = Lexer::new(source.to_string());
+        let (ast, mut diagnostics) = Parser::new(&mut lexer).parse_recovering();
+
+        self.interner = std::mem::take(interner);
+        let result = self.compile(ast);
+        *interner = std::mem::take(&mut self.interner);
+
+        match result {
+            Ok((chunk, _)) if diagnostics.is_empty() => Ok(chunk),
+            Ok(_) => Err(diagnostics),
+            Err(errors) => {
+                diagnostics.extend(errors);
+                diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+                Err(diagnostics)
+            }
         }
     }
 
-    pub fn compile(&mut self, ast: Vec<ASTNode>) -> (Chunk, Interner) {
-        ast.iter().for_each(|stmt| self.visit(stmt.clone()));
+    fn error(&mut self, message: &str) {
+        self.errors.push(Diagnostic::error(self.position, message));
+    }
 
-        // add return
-        self.chunk.write(VectorType::Code(OpCode::OpReturn));
+    /// The constants and the code of a chunk are indexed by two byte operands
+    fn check_chunk_size(&mut self) {
+        if self.chunk.constants.len() > u16::MAX as usize + 1 {
+            self.error("Too many constants in one chunk.");
+        }
+        if self.chunk.code.len() > u16::MAX as usize + 1 {
+            self.error("Too much code to jump over.");
+        }
+    }
 
-        (self.chunk.clone(), self.interner.clone())
+    /// Argument counts are one byte operands
+    fn check_arg_count(&mut self, count: usize) {
+        if count > u8::MAX as usize {
+            self.error("Can't have more than 255 arguments.");
+        }
     }
 
-    fn visit_function(&mut self, name: String, params: Vec<String>, body: Vec<ASTNode>) {
-        let function = Function::new(name.clone(), params.len() as u8);
-        self.functions.push(function);
-        self.function_count += 1;
+    /// Writes a jump whose target isn't known yet, returns where the target goes
+    /// so it can be patched
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        write_op!(self.chunk, op);
+        write_short!(self.chunk, 0);
+        self.chunk.code.len() - 2
+    }
+
+    /// The body is compiled into a chunk of its own, where the arguments are the
+    /// first locals. The function is then stored like any other variable
+    fn visit_function(&mut self, name: String, params: Vec<ASTNode>, body: Vec<ASTNode>) {
+        let mut names = Vec::new();
+        let mut defaults = Vec::new();
+        for param in params {
+            match param {
+                ASTNode::NamedArg(param, value) => {
+                    match self.constant_value(&value[0]) {
+                        Some(value) => defaults.push(value),
+                        None => {
+                            self.error(&format!("Default value of '{}' must be a constant", param))
+                        }
+                    }
+                    names.push(param);
+                }
+                ASTNode::Identifier(param) => {
+                    if !defaults.is_empty() {
+                        self.error(&format!(
+                            "Parameter '{}' without a default follows one with a default",
+                            param
+                        ));
+                    }
+                    names.push(param);
+                }
+                _ => self.error("Expected a parameter name"),
+            }
+        }
+
+        let enclosing_chunk = std::mem::take(&mut self.chunk);
+        let enclosing_locals = std::mem::take(&mut self.locals);
+        let enclosing_local_count = std::mem::replace(&mut self.local_count, 0);
+        let enclosing_depth = std::mem::replace(&mut self.scope_depth, 1);
+        let enclosing_deferred = std::mem::take(&mut self.deferred);
+        let enclosing_loops = std::mem::take(&mut self.loops);
+        self.function_depth += 1;
+        let enclosing_name = self.function_name.replace(name.clone());
+
+        for param in &names {
+            self.locals.push(Local {
+                name: param.clone(),
+                depth: 1,
+                initialized: true,
+            });
+            self.local_count += 1;
+        }
 
         // Compile function body
         for stmt in body {
-            self.visit(stmt);
+            self.visit_statement(stmt);
         }
 
-        // Add return
-        self.chunk.write(VectorType::Code(OpCode::OpReturn));
+        // a function that doesn't `return` returns nil
+        self.emit_deferred(0);
+        write_op!(self.chunk, OpCode::OpNil, OpCode::OpReturn);
+        self.check_chunk_size();
+        if names.len() > u8::MAX as usize {
+            self.error("Can't have more than 255 parameters.");
+        }
+
+        let function = Function {
+            name: name.clone(),
+            arity: names.len(),
+            defaults,
+            chunk: std::mem::replace(&mut self.chunk, enclosing_chunk),
+        };
+        self.locals = enclosing_locals;
+        self.local_count = enclosing_local_count;
+        self.scope_depth = enclosing_depth;
+        self.deferred = enclosing_deferred;
+        self.loops = enclosing_loops;
+        self.function_depth -= 1;
+        self.function_name = enclosing_name;
+
+        write_op!(self.chunk, OpCode::OpConstant);
+        let constant = add_con!(self.chunk, ValueType::Function(Rc::new(function)));
+        write_short!(self.chunk, constant);
+
+        if self.scope_depth > 0 {
+            // the function stays on the stack as a local
+            self.locals.push(Local {
+                name,
+                depth: self.scope_depth,
+                initialized: true,
+            });
+            self.local_count += 1;
+        } else {
+            let slot = self.resolve_global(name);
+            write_op!(self.chunk, OpCode::OpDefineGlobal);
+            write_short!(self.chunk, slot);
+        }
+    }
+
+    /// The VM unwinds to the stack of the `OpTry` and pushes the message, which
+    /// becomes the first local of the handler's scope. A `finally` is an outer
+    /// `OpFinally` around both, the normal and the caught path fall through to
+    /// its cleanup. An error escaping the body or the handler runs a copy of the
+    /// cleanup and is raised again by `OpRethrow`
+    fn visit_try(
+        &mut self,
+        body: Vec<ASTNode>,
+        catch: Option<(String, Vec<ASTNode>)>,
+        finally: Option<Vec<ASTNode>>,
+    ) {
+        let cleanup = finally.map(ASTNode::Block);
+        let finally_jump = cleanup.as_ref().map(|cleanup| {
+            // a `return` in the body or the handler runs the cleanup on its way out
+            self.deferred.push((self.scope_depth, cleanup.clone()));
+            self.tries += 1;
+            self.emit_jump(OpCode::OpFinally)
+        });
+
+        match catch {
+            Some((name, handler)) => {
+                let handler_jump = self.emit_jump(OpCode::OpTry);
+                self.tries += 1;
+                body.into_iter().for_each(|stmt| self.visit_statement(stmt));
+                self.tries -= 1;
+                write_op!(self.chunk, OpCode::OpEndTry);
+                let end_jump = self.emit_jump(OpCode::OpJump);
+
+                self.chunk.patch_short(handler_jump, self.chunk.code.len());
+                self.scope_depth += 1;
+                self.add_local(name);
+                for stmt in handler {
+                    self.visit_statement(stmt);
+                }
+                self.end_scope();
+
+                self.chunk.patch_short(end_jump, self.chunk.code.len());
+            }
+            None => body.into_iter().for_each(|stmt| self.visit_statement(stmt)),
+        }
+
+        let (Some(cleanup), Some(finally_jump)) = (cleanup, finally_jump) else {
+            return;
+        };
+        self.deferred.pop();
+        self.tries -= 1;
+        write_op!(self.chunk, OpCode::OpEndTry);
+        self.visit_statement(cleanup.clone());
+        let end_jump = self.emit_jump(OpCode::OpJump);
+
+        // the VM pushes the error and whether it was thrown, which stay on the
+        // stack under the cleanup's locals until `OpRethrow` pops them
+        self.chunk.patch_short(finally_jump, self.chunk.code.len());
+        self.scope_depth += 1;
+        self.add_local(" error".to_string());
+        self.add_local(" thrown".to_string());
+        self.visit_statement(cleanup);
+        write_op!(self.chunk, OpCode::OpRethrow);
+        self.scope_depth -= 1;
+        self.local_count -= 2;
+        self.locals.truncate(self.local_count);
+
+        self.chunk.patch_short(end_jump, self.chunk.code.len());
+    }
+
+    /// A local of the innermost scope for a value the VM put on the stack. The
+    /// names with a space can't be written in the source
+    fn add_local(&mut self, name: String) {
+        self.locals.push(Local {
+            name,
+            depth: self.scope_depth,
+            initialized: true,
+        });
+        self.local_count += 1;
+    }
+
+    /// Leaves the innermost scope, running its deferred expressions and popping its locals
+    fn end_scope(&mut self) {
+        // the deferred expressions can still read the locals of the block
+        self.emit_deferred(self.scope_depth - 1);
+        let depth = self.scope_depth;
+        self.deferred.retain(|(scope, _)| *scope < depth);
+        self.scope_depth -= 1;
+
+        while self.local_count > 0 && self.locals[self.local_count - 1].depth > self.scope_depth {
+            self.local_count -= 1;
+            write_op!(self.chunk, OpCode::OpPop);
+        }
+        self.locals.truncate(self.local_count);
+    }
+
+    /// Starts a loop whose `continue`s go back to `start`
+    fn begin_loop(&mut self, start: usize) {
+        self.loops.push(Loop {
+            start,
+            local_count: self.local_count,
+            deferred: self.deferred.len(),
+            tries: self.tries,
+            breaks: Vec::new(),
+        });
+    }
+
+    /// Ends the innermost loop, its `break`s jump to the code after it
+    fn end_loop(&mut self) {
+        let exit_offset = self.chunk.code.len();
+        for jump in self.loops.pop().map(|lp| lp.breaks).unwrap_or_default() {
+            self.chunk.patch_short(jump, exit_offset);
+        }
+    }
+
+    /// Closes what was opened inside the innermost loop, for a `break` or
+    /// `continue`. The compiler keeps them open for the statements after it
+    fn exit_loop(&mut self, keyword: &str) -> Option<()> {
+        let Some(lp) = self.loops.last() else {
+            self.error(&format!("Can't {} outside of a loop.", keyword));
+            return None;
+        };
+        let (local_count, deferred, tries) = (lp.local_count, lp.deferred, lp.tries);
+
+        for _ in tries..self.tries {
+            write_op!(self.chunk, OpCode::OpEndTry);
+        }
+        let exprs: Vec<ASTNode> = self.deferred[deferred..]
+            .iter()
+            .rev()
+            .map(|(_, expr)| expr.clone())
+            .collect();
+        for expr in exprs {
+            self.visit_statement(expr);
+        }
+        for _ in local_count..self.local_count {
+            write_op!(self.chunk, OpCode::OpPop);
+        }
+        Some(())
+    }
+
+    /// Compiles the expressions deferred in the scopes deeper than `depth`, the
+    /// last deferred first
+    fn emit_deferred(&mut self, depth: u8) {
+        let deferred: Vec<ASTNode> = self
+            .deferred
+            .iter()
+            .rev()
+            .filter(|(scope, _)| *scope > depth)
+            .map(|(_, expr)| expr.clone())
+            .collect();
+        for expr in deferred {
+            self.visit_statement(expr);
+        }
+    }
+
+    /// The top-level statements of the file are compiled in place, as if they
+    /// were written where the `import` is. With an alias its top-level names
+    /// are globals of their own, kept in a module stored in the alias. Errors in
+    /// the file are reported at the `import`, with where they are in the file
+    fn visit_import(&mut self, path: String, alias: Option<String>) {
+        if self.function_depth > 0 || self.scope_depth > 0 {
+            self.error("Imports must be at the top level.");
+            return;
+        }
+
+        let directory = match self.importing.last().or(self.source_path.as_ref()) {
+            Some(file) => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+            None => PathBuf::new(),
+        };
+        let Ok(file) = directory.join(&path).canonicalize() else {
+            self.error(&format!("Can't import '{}': file not found", path));
+            return;
+        };
+        if self.source_path.as_ref() == Some(&file) || self.importing.contains(&file) {
+            self.error(&format!("Can't import '{}': it imports itself", path));
+            return;
+        }
+        let alias = alias.map(|alias| self.global_name(alias));
+        if !self.imported.insert((file.clone(), alias.clone())) {
+            return;
+        }
+        let source = match std::fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(e) => {
+                self.error(&format!("Can't import '{}': {}", path, e));
+                return;
+            }
+        };
+
+        let mut lexer = Lexer::new(source);
+        let (ast, diagnostics) = Parser::new(&mut lexer).parse_recovering();
+        let errors = self.errors.len();
+        self.errors.extend(diagnostics);
+
+        let namespace = alias
+            .as_ref()
+            .map(|alias| (format!("{}.", alias), top_level_names(&ast)));
+        let enclosing = std::mem::replace(&mut self.namespace, namespace);
+        self.importing.push(file);
+        for statement in ast {
+            self.visit_statement(statement);
+        }
+        self.importing.pop();
+        let namespace = std::mem::replace(&mut self.namespace, enclosing);
+
+        if let (Some(alias), Some((prefix, names))) = (alias, namespace) {
+            let mut names: Vec<String> = names.into_iter().collect();
+            names.sort();
+            let members = names
+                .into_iter()
+                .map(|name| {
+                    let slot = self.resolve_global(format!("{}{}", prefix, name));
+                    (self.interner.intern_string(name), slot)
+                })
+                .collect();
+            let module = Module {
+                name: alias.clone(),
+                members,
+            };
+            write_op!(self.chunk, OpCode::OpConstant);
+            let constant = add_con!(self.chunk, ValueType::Module(Rc::new(module)));
+            write_short!(self.chunk, constant);
+            let slot = self.resolve_global(alias.clone());
+            write_op!(self.chunk, OpCode::OpDefineGlobal);
+            write_short!(self.chunk, slot);
+            self.modules.insert(alias);
+        }
+
+        for error in &mut self.errors[errors..] {
+            error.message = format!(
+                "In '{}' [line {}:{}]: {}",
+                path, error.line, error.column, error.message
+            );
+            error.line = self.position.line;
+            error.column = self.position.column;
+        }
+    }
+
+    /// The global a name refers to, in a module being imported its top-level
+    /// names are prefixed with the module's
+    fn global_name(&self, name: String) -> String {
+        match &self.namespace {
+            Some((prefix, names)) if names.contains(&name) => format!("{}{}", prefix, name),
+            _ => name,
+        }
+    }
+
+    /// Slot of a global, a name gets a slot the first time it is used, defined or not
+    fn resolve_global(&mut self, name: String) -> usize {
+        let name = self.global_name(name);
+        let name = self.interner.intern_string(name);
+        *self.global_slots.entry(name).or_insert_with(|| {
+            self.global_names.push(name);
+            self.global_names.len() - 1
+        })
+    }
+
+    /// `a.relu(b)` is compiled as `relu(a, b)`, the receiver is pushed as the first
+    /// argument and counted in the argument count written after the name. A local
+    /// is called by value, it's pushed after the arguments for `OpCallValue`. A
+    /// spread array is pushed last and counted out of the argument count
+    fn visit_call(&mut self, iden: String, receiver: Option<ASTNode>, args: Vec<ASTNode>) {
+        let mut arg_count = args.len() + receiver.is_some() as usize;
+        self.check_arg_count(arg_count);
+        if let Some(receiver) = receiver {
+            self.visit(receiver);
+        }
+        let mut names = Vec::new();
+        let mut spread = false;
+        for arg in args {
+            match arg {
+                ASTNode::NamedArg(name, mut value) => {
+                    names.push(ValueType::Identifier(self.interner.intern_string(name)));
+                    self.visit(value.remove(0));
+                }
+                ASTNode::Spread(mut value) => {
+                    spread = true;
+                    arg_count -= 1;
+                    self.visit(value.remove(0));
+                }
+                arg => self.visit(arg),
+            }
+        }
+
+        // the elements are passed like the arguments of `OpCall`, once the VM
+        // knows how many there are
+        if spread {
+            if self.resolve_local(&iden).is_some() {
+                self.error(&format!(
+                    "Can't spread the arguments of '{}', only functions called by name take a spread",
+                    iden
+                ));
+                return;
+            }
+            let global = self.resolve_global(iden);
+            write_op!(self.chunk, OpCode::OpCallSpread);
+            write_short!(self.chunk, global);
+            write_byte!(self.chunk, arg_count);
+            return;
+        }
+
+        // keyword arguments follow the positional ones, the names are a constant
+        // after the argument count
+        if !names.is_empty() {
+            let iden = self.global_name(iden);
+            let callee = add_con!(
+                self.chunk,
+                ValueType::Identifier(self.interner.intern_string(iden))
+            );
+            let names = add_con!(self.chunk, ValueType::Array(Rc::new(RefCell::new(names))));
+            write_op!(self.chunk, OpCode::OpCallNamed);
+            write_short!(self.chunk, callee);
+            write_byte!(self.chunk, arg_count);
+            write_short!(self.chunk, names);
+            return;
+        }
+
+        if let Some(local) = self.resolve_local(&iden) {
+            self.check_initialized(local);
+            write_op!(self.chunk, OpCode::OpGetLocal);
+            write_byte!(self.chunk, local);
+            write_op!(self.chunk, OpCode::OpCallValue);
+            write_byte!(self.chunk, arg_count);
+            return;
+        }
+
+        if let Some(id) = method_id(&self.global_name(iden.clone())) {
+            write_op!(self.chunk, OpCode::OpCallMethod);
+            write_byte!(self.chunk, id);
+            write_byte!(self.chunk, arg_count);
+            return;
+        }
+        let global = self.resolve_global(iden);
+        write_op!(self.chunk, OpCode::OpCall);
+        write_short!(self.chunk, global);
+        write_byte!(self.chunk, arg_count);
+    }
+
+    /// The global of an `import ... as` module a receiver names, a local of the
+    /// same name hides it
+    fn module_alias(&self, receiver: &ASTNode) -> Option<String> {
+        match receiver {
+            ASTNode::Identifier(name) if self.resolve_local(name).is_none() => {
+                let global = self.global_name(name.clone());
+                self.modules.contains(&global).then_some(global)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `tensor(...)` calls the native, which natives must be enabled for
+    /// and no function or variable of the program can take the place of
+    fn calls_native_tensor(&self) -> bool {
+        self.capabilities.natives
+            && !self.defines_tensor
+            && self.resolve_local(&"tensor".to_string()).is_none()
+            && self.global_name("tensor".to_string()) == "tensor"
+    }
+
+    /// A call of the function being compiled, by the global name it was defined with
+    fn is_self_call(&self, iden: &String, args: &[ASTNode]) -> bool {
+        self.function_name.as_ref() == Some(iden)
+            && self.function_depth == 1
+            && self.resolve_local(iden).is_none()
+            && !args
+                .iter()
+                .any(|arg| matches!(arg, ASTNode::NamedArg(..) | ASTNode::Spread(..)))
+    }
+
+    /// `return f(args)` inside `f`, the call reuses the frame of the running call
+    /// so tail recursion runs in constant frame space
+    fn visit_tail_call(&mut self, iden: String, args: Vec<ASTNode>) {
+        let arg_count = args.len();
+        self.check_arg_count(arg_count);
+        for arg in args {
+            self.visit(arg);
+        }
+
+        let global = self.resolve_global(iden);
+        write_op!(self.chunk, OpCode::OpTailCall);
+        write_short!(self.chunk, global);
+        write_byte!(self.chunk, arg_count);
+    }
+
+    /// Statements leave the stack as they found it, so the value of an expression
+    /// statement (including assignments, which leave the assigned value) is popped.
+    /// Only the last statement of the program keeps it, as the program's result
+    fn visit_statement(&mut self, node: ASTNode) {
+        // `Located` passes `last_statement` on to the statement it wraps
+        let last =
+            !matches!(node, ASTNode::Located(..)) && std::mem::take(&mut self.last_statement);
+        let is_expression = matches!(
+            node,
+            ASTNode::IntNumber(_)
+                | ASTNode::FloatNumber(_)
+                | ASTNode::Identifier(_)
+                | ASTNode::Boolean(_)
+                | ASTNode::String(_)
+                | ASTNode::Interpolation(_)
+                | ASTNode::Array(_)
+                | ASTNode::Op(..)
+                | ASTNode::Callee(..)
+                | ASTNode::Assign(..)
+        );
 
-        let function_idx = self.chunk.add_constant(ValueType::Function(name));
-        write_cons!(self.chunk, function_idx);
+        self.visit(node);
+
+        if is_expression && !last {
+            write_op!(self.chunk, OpCode::OpPop);
+        }
     }
 
     fn visit(&mut self, node: ASTNode) {
@@ -113,17 +787,21 @@ impl Compiler {
             //     write_op!(self.chunk, OpCode::OpConstant);
             //     // add_con!(self.chunk, ValueType::Tensor(Tensor::from(n)));
             //     add_con!(self.chunk, ValueType::Float(n));
-            //     write_cons!(self.chunk, self.chunk.constants.len() - 1);
+            //     write_short!(self.chunk, self.chunk.constants.len() - 1);
             // }
+            ASTNode::IntNumber(n @ 0..=255) => {
+                write_op!(self.chunk, OpCode::OpConstantSmall);
+                write_byte!(self.chunk, n);
+            }
             ASTNode::IntNumber(n) => {
                 write_op!(self.chunk, OpCode::OpConstant);
-                add_con!(self.chunk, ValueType::Integer(n));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                let constant = add_con!(self.chunk, ValueType::Integer(n));
+                write_short!(self.chunk, constant);
             }
             ASTNode::FloatNumber(n) => {
                 write_op!(self.chunk, OpCode::OpConstant);
-                add_con!(self.chunk, ValueType::Float(n));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                let constant = add_con!(self.chunk, ValueType::Float(n));
+                write_short!(self.chunk, constant);
             }
             ASTNode::Boolean(b) => {
                 write_op!(self.chunk, if b { OpCode::OpTrue } else { OpCode::OpFalse })
@@ -131,24 +809,93 @@ impl Compiler {
 
             ASTNode::String(s) => {
                 write_op!(self.chunk, OpCode::OpConstant);
-                add_con!(
+                let constant = add_con!(
                     self.chunk,
                     ValueType::String(self.interner.intern_string(s))
                 );
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_short!(self.chunk, constant);
+            }
+            // the pieces are concatenated like `+` does, an expression is converted to
+            // a string first
+            ASTNode::Interpolation(parts) => {
+                for (i, part) in parts.into_iter().enumerate() {
+                    // the text and the expressions alternate, an expression can
+                    // be a string literal too
+                    let is_text = i % 2 == 0;
+                    self.visit(part);
+                    if !is_text {
+                        write_op!(self.chunk, OpCode::OpToString);
+                    }
+                    if i > 0 {
+                        write_op!(self.chunk, OpCode::OpAdd);
+                    }
+                }
+            }
+            ASTNode::Array(elements) => {
+                let count = elements.len();
+                for element in elements {
+                    self.visit(element);
+                }
+                write_op!(self.chunk, OpCode::OpBuildArray);
+                write_short!(self.chunk, count);
             }
             ASTNode::Identifier(iden) => {
                 if let Some(local) = self.resolve_local(&iden) {
+                    self.check_initialized(local);
                     write_op!(self.chunk, OpCode::OpGetLocal);
-                    write_cons!(self.chunk, local);
+                    write_byte!(self.chunk, local);
+                } else if let Some(value) = self.inlined.get(&iden) {
+                    write_op!(self.chunk, OpCode::OpConstant);
+                    let constant = add_con!(self.chunk, value.clone());
+                    write_short!(self.chunk, constant);
                 } else {
+                    let slot = self.resolve_global(iden);
                     write_op!(self.chunk, OpCode::OpGetGlobal);
-                    let global = self
-                        .chunk
-                        .add_constant(ValueType::Identifier(self.interner.intern_string(iden)));
-                    write_cons!(self.chunk, global);
+                    write_short!(self.chunk, slot);
+                }
+            }
+            ASTNode::Op(Ops::PostfixOp(PostfixOp::Call), mut vec) => {
+                assert_eq!(vec.len(), 2);
+                let (callee, receiver) = (vec.pop().unwrap(), vec.pop().unwrap());
+                match callee {
+                    ASTNode::Callee(iden, args) => match self.module_alias(&receiver) {
+                        Some(module) => self.visit_call(format!("{}.{}", module, iden), None, args),
+                        None => self.visit_call(iden, Some(receiver), args),
+                    },
+                    ASTNode::Identifier(member) => {
+                        self.visit(receiver);
+                        write_op!(self.chunk, OpCode::OpGetField);
+                        let member = self.interner.intern_string(member);
+                        let constant = add_con!(self.chunk, ValueType::Identifier(member));
+                        write_short!(self.chunk, constant);
+                    }
+                    _ => self.error("Expected a callee after '.'"),
                 }
             }
+            // the right operand only runs when the left one doesn't decide the
+            // result, which is left on the stack otherwise
+            ASTNode::Op(Ops::BinaryOp(BinaryOp::And), mut vec) => {
+                assert_eq!(vec.len(), 2);
+                let rhs = vec.pop().unwrap();
+                self.visit(vec.pop().unwrap());
+
+                let end_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+                write_op!(self.chunk, OpCode::OpPop);
+                self.visit(rhs);
+                self.chunk.patch_short(end_jump, self.chunk.code.len());
+            }
+            ASTNode::Op(Ops::BinaryOp(BinaryOp::Or), mut vec) => {
+                assert_eq!(vec.len(), 2);
+                let rhs = vec.pop().unwrap();
+                self.visit(vec.pop().unwrap());
+
+                let else_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+                let end_jump = self.emit_jump(OpCode::OpJump);
+                self.chunk.patch_short(else_jump, self.chunk.code.len());
+                write_op!(self.chunk, OpCode::OpPop);
+                self.visit(rhs);
+                self.chunk.patch_short(end_jump, self.chunk.code.len());
+            }
             ASTNode::Op(op, vec) => {
                 for node in vec {
                     self.visit(node);
@@ -158,9 +905,9 @@ impl Compiler {
                     Ops::BinaryOp(BinaryOp::Add) => write_op!(self.chunk, OpCode::OpAdd),
                     Ops::BinaryOp(BinaryOp::Sub) => write_op!(self.chunk, OpCode::OpSubtract),
                     Ops::BinaryOp(BinaryOp::Mul) => write_op!(self.chunk, OpCode::OpMultiply),
-                    // @ - dot product - TODO: need to implement
-                    Ops::BinaryOp(BinaryOp::At) => write_op!(self.chunk, OpCode::OpMultiply),
+                    Ops::BinaryOp(BinaryOp::At) => write_op!(self.chunk, OpCode::OpMatMul),
                     Ops::BinaryOp(BinaryOp::Div) => write_op!(self.chunk, OpCode::OpDivide),
+                    Ops::BinaryOp(BinaryOp::Mod) => write_op!(self.chunk, OpCode::OpModulo),
                     Ops::BinaryOp(BinaryOp::Eq) => write_op!(self.chunk, OpCode::OpEqualEqual),
                     Ops::BinaryOp(BinaryOp::Ne) => {
                         write_op!(self.chunk, OpCode::OpEqualEqual);
@@ -185,45 +932,54 @@ impl Compiler {
                     Ops::PostfixOp(PostfixOp::StarStar) => {
                         write_op!(self.chunk, OpCode::OpPower);
                     }
-                    Ops::PostfixOp(PostfixOp::Call) => {
-                        panic!("Call not implemented");
-
-                        // write_op!(self.chunk, OpCode::OpCall);
-                        // self.chunk
-                        //     .write(VectorType::Constant(self.chunk.constants.len() - 1));
-                        // TODO: need for testing for this - a.relu(c.relu()), a.relu().relu()
+                    Ops::PostfixOp(PostfixOp::Call)
+                    | Ops::BinaryOp(BinaryOp::And)
+                    | Ops::BinaryOp(BinaryOp::Or) => {
+                        unreachable!("method calls and logical operators are compiled above")
                     }
-                    Ops::UnaryOp(UnaryOp::Not) | Ops::PostfixOp(PostfixOp::Index) => todo!(),
+                    Ops::UnaryOp(UnaryOp::Not) => write_op!(self.chunk, OpCode::OpNot),
+                    Ops::PostfixOp(PostfixOp::Index) => write_op!(self.chunk, OpCode::OpIndex),
                 }
             }
-            ASTNode::Print(expr) => {
-                assert!(expr.len() == 1);
-                self.visit(expr[0].clone());
+            ASTNode::Print(args) => {
+                let count = args.len();
+                self.check_arg_count(count);
+                for arg in args {
+                    self.visit(arg);
+                }
                 write_op!(self.chunk, OpCode::OpPrint);
+                write_byte!(self.chunk, count);
             }
             ASTNode::Let(iden, expr) => {
                 assert!(expr.len() == 1);
 
                 if self.scope_depth > 0 {
                     if self.local_count == 256 {
-                        panic!("Too many local variables.");
+                        self.error("Too many local variables.");
+                        return;
                     }
                     self.locals.push(Local {
                         name: iden,
                         depth: self.scope_depth,
+                        initialized: false,
                     });
                     self.local_count += 1;
                     self.visit(expr[0].clone());
+                    self.locals[self.local_count - 1].initialized = true;
                     return;
                 }
 
-                let global = add_con!(
-                    self.chunk,
-                    ValueType::Identifier(self.interner.intern_string(iden))
-                );
+                // the global is still defined, only the reads after this are inlined
+                if self.write_once.contains(&iden) {
+                    if let Some(value) = self.constant_value(&expr[0]) {
+                        self.inlined.insert(iden.clone(), value);
+                    }
+                }
+
+                let slot = self.resolve_global(iden);
                 self.visit(expr[0].clone());
                 write_op!(self.chunk, OpCode::OpDefineGlobal);
-                write_cons!(self.chunk, global);
+                write_short!(self.chunk, slot);
             }
             ASTNode::Assign(iden, expr) => {
                 assert!(expr.len() == 1);
@@ -231,71 +987,66 @@ impl Compiler {
 
                 if let Some(local) = self.resolve_local(&iden) {
                     write_op!(self.chunk, OpCode::OpSetLocal);
-                    write_cons!(self.chunk, local);
+                    write_byte!(self.chunk, local);
                 } else {
-                    let global = add_con!(
-                        self.chunk,
-                        ValueType::Identifier(self.interner.intern_string(iden))
-                    );
+                    let slot = self.resolve_global(iden);
                     write_op!(self.chunk, OpCode::OpSetGlobal);
-                    write_cons!(self.chunk, global);
+                    write_short!(self.chunk, slot);
                 }
             }
             ASTNode::Block(stmts) => {
                 self.scope_depth += 1;
                 for stmt in stmts {
-                    self.visit(stmt);
+                    self.visit_statement(stmt);
                 }
-                self.scope_depth -= 1;
-
-                while self.local_count > 0
-                    && self.locals[self.local_count - 1].depth > self.scope_depth
-                {
-                    self.local_count -= 1;
-                    write_op!(self.chunk, OpCode::OpPop);
+                self.end_scope();
+            }
+            ASTNode::Try(body, catch, finally) => self.visit_try(body, catch, finally),
+            // `tensor(2.0)` or `tensor([[1, 2], [3, 4]])` with literals is built
+            // once and stored in the constant pool, ragged rows are an error
+            ASTNode::Callee(iden, args)
+                if iden == "tensor" && args.len() == 1 && self.calls_native_tensor() =>
+            {
+                match literal_value(&args[0]).map(|value| native::tensor_of(&value)) {
+                    Some(Ok(tensor)) => {
+                        write_op!(self.chunk, OpCode::OpConstant);
+                        let constant = add_con!(self.chunk, ValueType::Tensor(tensor));
+                        write_short!(self.chunk, constant);
+                    }
+                    Some(Err(e)) => self.error(&e),
+                    None => self.visit_call(iden, None, args),
                 }
             }
-            ASTNode::Callee(iden, _) => {
-                let global = add_con!(
-                    self.chunk,
-                    ValueType::Identifier(self.interner.intern_string(iden))
-                );
-                write_cons!(self.chunk, global);
+            ASTNode::Callee(iden, args) => self.visit_call(iden, None, args),
+            ASTNode::NamedArg(name, _) => {
+                self.error(&format!("Keyword argument '{}' outside of a call", name))
             }
+            ASTNode::Spread(_) => self.error("Spread '...' outside of the arguments of a call"),
             ASTNode::If(cond, then, els) => {
                 assert_eq!(cond.len(), 1);
                 self.visit(cond[0].clone());
 
-                let else_jump_offset = self.chunk.code.len();
-                write_op!(self.chunk, OpCode::OpJumpIfFalse);
-                add_con!(self.chunk, ValueType::JumpOffset(else_jump_offset));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
-                let else_jump_const_idx = add_con!(self.chunk, ValueType::JumpOffset(0));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                let else_jump = self.emit_jump(OpCode::OpJumpIfFalse);
                 write_op!(self.chunk, OpCode::OpPop);
 
-                then.iter().for_each(|stmt| {
-                    self.visit(stmt.clone())
-                });
+                then.iter()
+                    .for_each(|stmt| self.visit_statement(stmt.clone()));
 
-                let jump_to_end = self.chunk.code.len();
-                write_op!(self.chunk, OpCode::OpJump);
-                add_con!(self.chunk, ValueType::JumpOffset(jump_to_end));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
-                let jump_const_idx = add_con!(self.chunk, ValueType::JumpOffset(0));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                let end_jump = self.emit_jump(OpCode::OpJump);
                 write_op!(self.chunk, OpCode::OpPop);
 
+                // the condition is popped by the `OpPop` before the else branch
                 let else_offset = self.chunk.code.len();
-                self.chunk.constants[else_jump_const_idx] = ValueType::JumpOffset(else_offset - 1);
+                self.chunk.patch_short(else_jump, else_offset - 1);
 
                 // Compile the "else" block if it exists
                 if let Some(els) = els {
-                    els.iter().for_each(|stmt| self.visit(stmt.clone()));
+                    els.iter()
+                        .for_each(|stmt| self.visit_statement(stmt.clone()));
                 }
 
                 let end_offset = self.chunk.code.len();
-                self.chunk.constants[jump_const_idx] = ValueType::JumpOffset(end_offset);
+                self.chunk.patch_short(end_jump, end_offset);
             }
             ASTNode::While(cond, body) => {
                 let loop_start = self.chunk.code.len();
@@ -303,39 +1054,636 @@ impl Compiler {
                 assert_eq!(cond.len(), 1);
                 self.visit(cond[0].clone());
 
-                let exit_jump_offset = self.chunk.code.len();
-                write_op!(self.chunk, OpCode::OpJumpIfFalse);
-                add_con!(self.chunk, ValueType::JumpOffset(exit_jump_offset));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
-                let exit_jump_const_idx = add_con!(self.chunk, ValueType::JumpOffset(0));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse);
                 write_op!(self.chunk, OpCode::OpPop);
 
-                body.iter().for_each(|stmt| self.visit(stmt.clone()));
+                self.begin_loop(loop_start);
+                body.iter()
+                    .for_each(|stmt| self.visit_statement(stmt.clone()));
 
-                let loop_jump_offset = self.chunk.code.len();
                 write_op!(self.chunk, OpCode::OpLoop);
-                add_con!(self.chunk, ValueType::JumpOffset(loop_jump_offset));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
-                add_con!(self.chunk, ValueType::JumpOffset(loop_start));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_short!(self.chunk, loop_start);
+                write_op!(self.chunk, OpCode::OpPop);
+
+                // a `break` has popped the condition already, it jumps past the `OpPop`
+                let exit_offset = self.chunk.code.len();
+                self.chunk.patch_short(exit_jump, exit_offset - 1);
+                self.end_loop();
+            }
+            // the body is jumped to while the condition is false, a true one
+            // jumps over it to the exit
+            ASTNode::Until(cond, body) => {
+                let loop_start = self.chunk.code.len();
+
+                assert_eq!(cond.len(), 1);
+                self.visit(cond[0].clone());
+
+                let body_jump = self.emit_jump(OpCode::OpJumpIfFalse);
                 write_op!(self.chunk, OpCode::OpPop);
+                let exit_jump = self.emit_jump(OpCode::OpJump);
+
+                self.chunk.patch_short(body_jump, self.chunk.code.len());
+                write_op!(self.chunk, OpCode::OpPop);
+                self.begin_loop(loop_start);
+                body.iter()
+                    .for_each(|stmt| self.visit_statement(stmt.clone()));
+
+                write_op!(self.chunk, OpCode::OpLoop);
+                write_short!(self.chunk, loop_start);
 
                 let exit_offset = self.chunk.code.len();
-                self.chunk.constants[exit_jump_const_idx] = ValueType::JumpOffset(exit_offset - 1);
+                self.chunk.patch_short(exit_jump, exit_offset);
+                self.end_loop();
             }
             ASTNode::Function(name, params, body) => {
                 self.visit_function(name, params, body);
             }
+            ASTNode::Import(path, alias) => self.visit_import(path, alias),
+            ASTNode::Located(position, statement) => {
+                // the lines of an imported file aren't lines of the chunk's source
+                let imported = !self.importing.is_empty();
+                let enclosing = std::mem::replace(&mut self.position, position);
+                if !imported {
+                    self.chunk.mark_line(position.line);
+                }
+                self.visit_statement(*statement);
+                self.position = enclosing;
+                if !imported {
+                    self.chunk.mark_line(enclosing.line);
+                }
+            }
+            ASTNode::Return(value) => {
+                if self.function_depth == 0 {
+                    self.error("Can't return from top-level code.");
+                    return;
+                }
+
+                match value.into_iter().next() {
+                    // the deferred expressions run after the call and the `try`s around it
+                    // catch its errors, so it can't reuse the frame
+                    Some(ASTNode::Callee(iden, args))
+                        if self.is_self_call(&iden, &args)
+                            && self.deferred.is_empty()
+                            && self.tries == 0 =>
+                    {
+                        self.visit_tail_call(iden, args);
+                        return;
+                    }
+                    Some(value) => self.visit(value),
+                    None => write_op!(self.chunk, OpCode::OpNil),
+                }
+                // the returned value is computed before the deferred expressions run
+                self.emit_deferred(0);
+                write_op!(self.chunk, OpCode::OpReturn);
+            }
+            ASTNode::Throw(value) => {
+                self.visit(value[0].clone());
+                write_op!(self.chunk, OpCode::OpThrow);
+            }
+            ASTNode::Break => {
+                if self.exit_loop("break").is_some() {
+                    let jump = self.emit_jump(OpCode::OpJump);
+                    if let Some(lp) = self.loops.last_mut() {
+                        lp.breaks.push(jump);
+                    }
+                }
+            }
+            ASTNode::Continue => {
+                if self.exit_loop("continue").is_some() {
+                    let start = self.loops.last().map_or(0, |lp| lp.start);
+                    write_op!(self.chunk, OpCode::OpLoop);
+                    write_short!(self.chunk, start);
+                }
+            }
+            ASTNode::Defer(expr) => {
+                if self.scope_depth == 0 {
+                    self.error("Can't defer outside of a block.");
+                    return;
+                }
+                self.deferred.push((self.scope_depth, expr[0].clone()));
+            }
+        }
+    }
+
+    /// The value of a literal, for the default values of parameters
+    fn constant_value(&mut self, node: &ASTNode) -> Option<ValueType> {
+        match node {
+            ASTNode::IntNumber(n) => Some(ValueType::Integer(*n)),
+            ASTNode::FloatNumber(n) => Some(ValueType::Float(*n)),
+            ASTNode::Boolean(b) => Some(ValueType::Boolean(*b)),
+            ASTNode::String(s) => Some(ValueType::String(self.interner.intern_string(s.clone()))),
+            ASTNode::Op(Ops::UnaryOp(UnaryOp::Negate), operand) if operand.len() == 1 => {
+                match self.constant_value(&operand[0])? {
+                    ValueType::Integer(n) => Some(ValueType::Integer(-n)),
+                    ValueType::Float(n) => Some(ValueType::Float(-n)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// A local is only on the stack once its initializer has run, `let a = a;`
+    /// in a block would read the slot being initialized
+    fn check_initialized(&mut self, local: usize) {
+        if !self.locals[local].initialized {
+            let name = self.locals[local].name.clone();
+            self.error(&format!(
+                "Can't read local variable '{}' in its own initializer.",
+                name
+            ));
         }
     }
 
     fn resolve_local(&self, name: &String) -> Option<usize> {
-        for i in (0..self.local_count).rev() {
-            if self.locals[i].name == *name {
-                return Some(i);
+        (0..self.local_count)
+            .rev()
+            .find(|&i| self.locals[i].name == *name)
+    }
+}
+
+/// Compiles `source` to a chunk without running it, strings are interned in
+/// `interner` so a VM can be built from the two
+pub fn compile(source: &str, interner: &mut Interner) -> Result<Chunk, Vec<Diagnostic>> {
+    Compiler::new().compile_source(source, interner)
+}
+
+/// Globals `let` once in the top-level statements and never assigned or defined
+/// again. The top-level statements run in order, so a read compiled after the
+/// `let` (even in a function) always runs after it
+fn write_once_globals(ast: &[ASTNode]) -> HashSet<String> {
+    fn assigned(node: &ASTNode, names: &mut HashSet<String>) {
+        if let ASTNode::Assign(name, _) | ASTNode::Function(name, _, _) = node {
+            names.insert(name.clone());
+        }
+        for child in children(node) {
+            assigned(child, names);
+        }
+    }
+
+    let mut defined = HashMap::new();
+    let mut written = HashSet::new();
+    for statement in ast {
+        let mut statement = statement;
+        while let ASTNode::Located(_, inner) = statement {
+            statement = inner;
+        }
+        match statement {
+            ASTNode::Let(name, _) => *defined.entry(name.clone()).or_insert(0) += 1,
+            // the imported file may assign any global
+            ASTNode::Import(..) => return HashSet::new(),
+            _ => {}
+        }
+        assigned(statement, &mut written);
+    }
+
+    defined
+        .into_iter()
+        .filter(|(name, count)| *count == 1 && !written.contains(name))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Index in `TENSOR_METHODS` of the method a call of `name` runs, natives of
+/// the same name come first like in `OpCall`
+fn method_id(name: &str) -> Option<usize> {
+    if NATIVES.iter().any(|native| native.name == name) {
+        return None;
+    }
+    TENSOR_METHODS.iter().position(|method| method.name == name)
+}
+
+/// The nodes directly inside `node`
+fn children(node: &ASTNode) -> Vec<&ASTNode> {
+    match node {
+        ASTNode::Assign(_, nodes)
+        | ASTNode::Array(nodes)
+        | ASTNode::Op(_, nodes)
+        | ASTNode::Callee(_, nodes)
+        | ASTNode::NamedArg(_, nodes)
+        | ASTNode::Spread(nodes)
+        | ASTNode::Let(_, nodes)
+        | ASTNode::Print(nodes)
+        | ASTNode::Return(nodes)
+        | ASTNode::Defer(nodes)
+        | ASTNode::Throw(nodes)
+        | ASTNode::Block(nodes) => nodes.iter().collect(),
+        ASTNode::Function(_, params, body) => params.iter().chain(body).collect(),
+        ASTNode::If(condition, then, otherwise) => condition
+            .iter()
+            .chain(then)
+            .chain(otherwise.iter().flatten())
+            .collect(),
+        ASTNode::While(condition, body) | ASTNode::Until(condition, body) => {
+            condition.iter().chain(body).collect()
+        }
+        ASTNode::Try(body, catch, finally) => body
+            .iter()
+            .chain(catch.iter().flat_map(|(_, handler)| handler))
+            .chain(finally.iter().flatten())
+            .collect(),
+        ASTNode::Located(_, statement) => vec![statement],
+        _ => vec![],
+    }
+}
+
+/// Whether `name` is defined or assigned anywhere in `nodes`, as a global or a
+/// local, or could be by a file imported without a module name
+fn defines<'a>(nodes: impl IntoIterator<Item = &'a ASTNode>, name: &str) -> bool {
+    nodes.into_iter().any(|node| match node {
+        ASTNode::Let(defined, _) | ASTNode::Assign(defined, _) | ASTNode::Function(defined, ..)
+            if defined == name =>
+        {
+            true
+        }
+        ASTNode::Import(_, None) => true,
+        node => defines(children(node), name),
+    })
+}
+
+/// Names the top-level statements define, the members of a module
+fn top_level_names(ast: &[ASTNode]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for statement in ast {
+        let mut statement = statement;
+        while let ASTNode::Located(_, inner) = statement {
+            statement = inner;
+        }
+        match statement {
+            ASTNode::Let(name, _)
+            | ASTNode::Function(name, _, _)
+            | ASTNode::Import(_, Some(name)) => {
+                names.insert(name.clone());
             }
+            _ => {}
         }
-        None
+    }
+    names
+}
+
+/// The value of a number literal, including a negated one like `-2.0`
+fn literal_number(node: &ASTNode) -> Option<f64> {
+    match node {
+        ASTNode::IntNumber(n) => Some(*n as f64),
+        ASTNode::FloatNumber(n) => Some(*n),
+        ASTNode::Op(Ops::UnaryOp(UnaryOp::Negate), operand) if operand.len() == 1 => {
+            literal_number(&operand[0]).map(|n| -n)
+        }
+        _ => None,
+    }
+}
+
+/// A number literal or an array literal nesting only number literals, as a value
+fn literal_value(node: &ASTNode) -> Option<ValueType> {
+    match node {
+        ASTNode::Array(elements) => {
+            let elements = elements.iter().map(literal_value).collect::<Option<_>>()?;
+            Some(ValueType::Array(Rc::new(RefCell::new(elements))))
+        }
+        node => literal_number(node).map(ValueType::Float),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile_ok(src: &str) -> Chunk {
+        compile(src, &mut Interner::default()).unwrap()
+    }
+
+    #[test]
+    fn test_compile() {
+        let mut interner = Interner::default();
+        let chunk = compile("let a = 1; print(a + 2);", &mut interner).unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::OpConstantSmall as u8,
+                1,
+                OpCode::OpDefineGlobal as u8,
+                0,
+                0,
+                OpCode::OpGetGlobal as u8,
+                0,
+                0,
+                OpCode::OpConstantSmall as u8,
+                2,
+                OpCode::OpAdd as u8,
+                OpCode::OpPrint as u8,
+                1,
+                OpCode::OpReturn as u8,
+            ]
+        );
+        assert_eq!(interner.lookup(chunk.global_names[0]), "a");
+
+        assert!(compile("let = 1;", &mut interner).is_err());
+    }
+
+    #[test]
+    fn test_method_ids() {
+        let chunk = compile_ok("let x = 2; x.relu(); grad(x); str(x);");
+        let relu = TENSOR_METHODS
+            .iter()
+            .position(|m| m.name == "relu")
+            .unwrap();
+        let grad = TENSOR_METHODS
+            .iter()
+            .position(|m| m.name == "grad")
+            .unwrap();
+
+        assert_eq!(
+            chunk.code[8..16],
+            [
+                OpCode::OpCallMethod as u8,
+                relu as u8,
+                1,
+                OpCode::OpPop as u8,
+                OpCode::OpGetGlobal as u8,
+                0,
+                0,
+                OpCode::OpCallMethod as u8,
+            ]
+        );
+        assert_eq!(chunk.code[16], grad as u8);
+        // natives are still called by name
+        assert_eq!(chunk.code[22], OpCode::OpCall as u8);
+    }
+
+    #[test]
+    fn test_small_integer_constants() {
+        let chunk = compile_ok("print(0 + 1 + 2 + 255 + 256 + 3.0);");
+        let mut smalls = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let op = OpCode::try_from(chunk.code[offset]).unwrap();
+            if op == OpCode::OpConstantSmall {
+                smalls.push(chunk.code[offset + 1]);
+            }
+            offset += 1 + op.operands().iter().sum::<usize>();
+        }
+        assert_eq!(smalls, vec![0, 1, 2, 255]);
+        // only 256 and 3.0 are in the constants
+        assert_eq!(chunk.constants.len(), 2);
+
+        let mut interner = Interner::default();
+        let chunk = compile("print(0 + 1 + 2 + 255 + 256 + 3.0);", &mut interner).unwrap();
+        assert_eq!(
+            crate::vm::VM::init(chunk, interner).run(),
+            crate::vm::Result::Ok(vec!["517".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_compile_diagnostics() {
+        let src = "let = 1;\nprint(2);\nreturn 3;\nfn f() { return; }\n  return;";
+        let diagnostics = compile(src, &mut Interner::default()).unwrap_err();
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic::error(
+                    Position { line: 1, column: 7 },
+                    "Missing token EQUAL to assign value to variable"
+                ),
+                Diagnostic::error(
+                    Position { line: 3, column: 1 },
+                    "Can't return from top-level code."
+                ),
+                Diagnostic::error(
+                    Position { line: 5, column: 3 },
+                    "Can't return from top-level code."
+                ),
+            ]
+        );
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "[line 3:1] error: Can't return from top-level code."
+        );
+    }
+
+    #[test]
+    fn test_triple_quoted_strings() {
+        let mut interner = Interner::default();
+        let chunk = compile(
+            "let s = \"\"\"one\n  \"two\"\n\"\"\"\nprint(s)",
+            &mut interner,
+        )
+        .unwrap();
+        let ValueType::String(text) = chunk.constants[0] else {
+            panic!("{:?}", chunk.constants);
+        };
+        // the content is kept verbatim
+        assert_eq!(interner.lookup(text), "one\n  \"two\"\n");
+
+        let src = "print(1);\nlet t = \"\"\"never\nclosed;";
+        let diagnostics = compile(src, &mut Interner::default()).unwrap_err();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::error(
+                Position { line: 2, column: 9 },
+                "Syntax error: Unterminated string"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let mut interner = Interner::default();
+        let chunk = compile(r#"print("line1\nline2\t\"q\" \\ \$");"#, &mut interner).unwrap();
+        let ValueType::String(text) = chunk.constants[0] else {
+            panic!("{:?}", chunk.constants);
+        };
+        // the scanner keeps `\$` for the parser, which makes it a `$`
+        assert_eq!(interner.lookup(text), "line1\nline2\t\"q\" \\ $");
+
+        let src = "print(1);\nlet s = \"a\\qb\";";
+        let diagnostics = compile(src, &mut Interner::default()).unwrap_err();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::error(
+                Position { line: 2, column: 9 },
+                "Syntax error: Invalid escape '\\q' in string"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_local_in_own_initializer() {
+        let src = "let a = 1;\n{\n  let a = a + 1;\n}";
+        let diagnostics = compile(src, &mut Interner::default()).unwrap_err();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::error(
+                Position { line: 3, column: 3 },
+                "Can't read local variable 'a' in its own initializer."
+            )]
+        );
+
+        // a local shadowing another is only in scope after its `let`
+        compile_ok("{ let a = 1; { let b = a * 2; let a = b; print(a); } }");
+        assert!(compile("{ let f = f(); }", &mut Interner::default()).is_err());
+    }
+
+    #[test]
+    fn test_constants_are_deduplicated() {
+        let chunk = compile_ok("print(1.5 + 1.5 + 1.5);");
+        assert_eq!(chunk.constants.len(), 1);
+        assert!(matches!(chunk.constants[0], ValueType::Float(n) if n == 1.5));
+
+        let chunk = compile_ok(
+            r#"
+            print(1000, 1000, "a", "a", 0.0, -0.0);
+            f(x = 1); f(x = 2);
+            "#,
+        );
+        let kinds: Vec<_> = chunk.constants.iter().map(ValueType::type_name).collect();
+        assert_eq!(
+            kinds,
+            [
+                "number",
+                "string",
+                "number",
+                "number",
+                "identifier",
+                "array",
+                "array"
+            ]
+        );
+
+        // two tensors are never the same constant, each is a leaf of its own
+        let chunk = compile_ok("tensor(1); tensor(1);");
+        assert_eq!(chunk.constants.len(), 2);
+    }
+
+    #[test]
+    fn test_tensor_literal_is_a_constant() {
+        let chunk = compile_ok("tensor(-2.5);");
+
+        // the value of the last statement stays on the stack for `OpReturn`
+        assert_eq!(
+            chunk.code,
+            vec![OpCode::OpConstant as u8, 0, 0, OpCode::OpReturn as u8,]
+        );
+        assert!(matches!(&chunk.constants[0], ValueType::Tensor(t) if t.data() == vec![-2.5]));
+
+        let chunk = compile_ok("tensor([1, 2, 3]); tensor([[1, 2], [3, -4]]); tensor([]);");
+        assert!(!chunk.code.contains(&(OpCode::OpCall as u8)));
+        let tensors: Vec<_> = chunk
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                ValueType::Tensor(t) => (t.shape(), t.data()),
+                constant => panic!("{:?}", constant),
+            })
+            .collect();
+        assert_eq!(
+            tensors,
+            [
+                (vec![3], vec![1.0, 2.0, 3.0]),
+                (vec![2, 2], vec![1.0, 2.0, 3.0, -4.0]),
+                (vec![0], vec![])
+            ]
+        );
+
+        // anything but a literal is a call to the `tensor` native
+        let chunk = compile_ok("let a = 1; tensor(a); tensor([a, 2]);");
+        assert_eq!(
+            chunk
+                .code
+                .iter()
+                .filter(|&&byte| byte == OpCode::OpCall as u8)
+                .count(),
+            2
+        );
+
+        // and so is a `tensor` the program defines, wherever it is defined
+        let has_tensor = |chunk: &Chunk| {
+            chunk.constants.iter().any(|constant| match constant {
+                ValueType::Tensor(_) => true,
+                ValueType::Function(function) => function
+                    .chunk
+                    .constants
+                    .iter()
+                    .any(|constant| matches!(constant, ValueType::Tensor(_))),
+                _ => false,
+            })
+        };
+        assert!(has_tensor(&compile_ok("fn f() { return tensor(2.0); }")));
+        for src in [
+            "fn tensor(x) { return x; } tensor(2.0);",
+            "fn f() { return tensor(2.0); } fn tensor(x) { return x; }",
+            "tensor(2.0); let tensor = 1;",
+            "fn f(tensor) { return tensor(2.0); }",
+        ] {
+            assert!(!has_tensor(&compile_ok(src)), "{}", src);
+        }
+
+        let diagnostics = compile(
+            "print(1);\ntensor([[1, 2], [3]]);",
+            &mut Interner::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 2:1] error: tensor rows must have the same shape, got [2] and [1]"
+        );
+    }
+
+    #[test]
+    fn test_inline_write_once_globals() {
+        let compile_optimized = |src: &str| {
+            let mut compiler = Compiler::new();
+            compiler.set_optimize(true);
+            compiler
+                .compile_source(src, &mut Interner::default())
+                .unwrap()
+        };
+        fn ops(chunk: &Chunk) -> Vec<OpCode> {
+            let mut ops = Vec::new();
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let op = OpCode::try_from(chunk.code[offset]).unwrap();
+                offset += 1 + op.operands().iter().sum::<usize>();
+                ops.push(op);
+            }
+            ops
+        }
+        // `OpGetGlobal`s of the chunk and of the functions in it
+        fn reads(chunk: &Chunk) -> usize {
+            let functions = chunk.constants.iter().map(|constant| match constant {
+                ValueType::Function(f) => reads(&f.chunk),
+                _ => 0,
+            });
+            let count = ops(chunk)
+                .into_iter()
+                .filter(|op| *op == OpCode::OpGetGlobal);
+            count.count() + functions.sum::<usize>()
+        }
+
+        // `n` is only read after its `let`, in the script and in `f`
+        let chunk = compile_optimized("let n = -2; fn f() { return n * 2; } print(n, f());");
+        assert_eq!(reads(&chunk), 0);
+        assert!(chunk
+            .constants
+            .iter()
+            .any(|constant| matches!(constant, ValueType::Integer(-2))));
+
+        // assigned, defined twice, not a constant, read before the `let`, or shadowed
+        for src in [
+            "let n = 1; n = 2; print(n);",
+            "let n = 1; fn f() { n = 2; } print(n);",
+            "let n = 1; let n = 2; print(n);",
+            "let n = [1]; print(n);",
+            "fn f() { return n; } let n = 1; print(f());",
+        ] {
+            assert_eq!(reads(&compile_optimized(src)), 1, "{}", src);
+        }
+        let chunk = compile_optimized("let n = 1; { let n = true; print(n); }");
+        assert!(ops(&chunk).contains(&OpCode::OpGetLocal));
+
+        // off by default
+        assert_eq!(reads(&compile_ok("let n = 1; print(n);")), 1);
     }
 }
@@ -1,4 +1,4 @@
-use crate::{chunk, interner::Interner, value::ValueType};
+use crate::{chunk, interner::Interner, native::TENSOR_METHODS, value::ValueType};
 use colored::*;
 
 pub struct Debug {
@@ -29,83 +29,148 @@ impl Debug {
         let mut offset = 0;
         while offset < self.chunk.code.len() {
             let (new_offset, instruction) = self.disassemble_instruction(offset);
-            output.push(instruction);
+            output.push(format!("{} {}", self.format_line(offset), instruction));
             offset = new_offset;
         }
 
         output.push(self.format_footer());
+
+        // function bodies live in chunks of their own, they share the script's globals
+        for constant in &self.chunk.constants {
+            if let ValueType::Function(function) = constant {
+                let mut chunk = function.chunk.clone();
+                chunk.global_names = self.chunk.global_names.clone();
+
+                let mut debug = Debug::new(&function.name, chunk, self.interner.clone());
+                debug.set_color_usage(self.use_colors);
+                output.push(debug.disassemble());
+            }
+        }
+
         output.join("\n")
     }
 
     pub fn disassemble_instruction(&self, offset: usize) -> (usize, String) {
-        let instruction = self.chunk.code.get(offset).ok_or_else(|| {
+        let byte = self.chunk.code.get(offset).ok_or_else(|| {
             format!("Invalid offset {} in chunk of length {}", offset, self.chunk.code.len())
         }).unwrap();
 
-        match instruction {
-            chunk::VectorType::Code(op) if op.is_simple() => {
-                (offset + 1, self.format_simple_instruction(offset, op))
-            },
-            chunk::VectorType::Code(op) if op.uses_constant() => {
-                self.format_constant_instruction(offset, op)
+        let op = match chunk::OpCode::try_from(*byte) {
+            Ok(op) => op,
+            Err(byte) => return (offset + 1, format!("Unknown opcode {}", byte)),
+        };
+        let next = offset + 1 + op.operands().iter().sum::<usize>();
+        if next > self.chunk.code.len() {
+            return (self.chunk.code.len(), format!("Missing operands of {}", op));
+        }
+
+        let instruction = match op {
+            op if op.is_simple() => self.format_simple_instruction(offset, &op),
+            chunk::OpCode::OpCall | chunk::OpCode::OpCallSpread | chunk::OpCode::OpTailCall => {
+                let instruction = self.format_global_instruction(offset, &op);
+                format!("{} ({} args)", instruction, self.chunk.read_byte(offset + 3))
             },
-            chunk::VectorType::Code(op) if op.is_jump() => {
-                self.format_jump_instruction(offset, op)
+            chunk::OpCode::OpCallMethod => {
+                let id = self.chunk.read_byte(offset + 1) as usize;
+                let name = match TENSOR_METHODS.get(id) {
+                    Some(method) => method.name,
+                    None => "Invalid method id",
+                };
+                format!("{} {} {} | {} ({} args)",
+                    self.colorize_offset(offset),
+                    self.colorize_op(&op),
+                    self.colorize_constant_idx(id),
+                    self.colorize_constant_str(name),
+                    self.chunk.read_byte(offset + 2))
             },
-            chunk::VectorType::Constant(_) => {
-                (offset + 1, "Unexpected constant in code vector".to_string())
+            chunk::OpCode::OpCallNamed => {
+                let instruction = self.format_call_instruction(offset, &op);
+                let names = self.format_constant(self.chunk.read_short(offset + 4) as usize);
+                format!("{} {}", instruction, self.colorize_constant_str(&names))
             },
-            _ => (offset + 1, "Unknown instruction type".to_string()),
-        }
+            op if op.uses_global() => self.format_global_instruction(offset, &op),
+            op if op.uses_constant() => self.format_constant_instruction(offset, &op),
+            op if op.uses_operand() => self.format_operand_instruction(offset, &op),
+            op if op.is_jump() => self.format_jump_instruction(offset, &op),
+            op => format!("Unknown instruction {}", op),
+        };
+        (next, instruction)
     }
 
     fn format_simple_instruction(&self, offset: usize, op: &chunk::OpCode) -> String {
         format!("{} {}", self.colorize_offset(offset), self.colorize_op(op))
     }
 
-    fn format_constant_instruction(&self, offset: usize, op: &chunk::OpCode) -> (usize, String) {
-        let constant_idx = self.chunk.code.get(offset + 1)
-            .and_then(|v| if let chunk::VectorType::Constant(idx) = v { Some(*idx) } else { None })
-            .ok_or_else(|| "Invalid constant index".to_string())
-            .unwrap();
+    fn format_constant_instruction(&self, offset: usize, op: &chunk::OpCode) -> String {
+        let constant_idx = self.chunk.read_short(offset + 1) as usize;
+        let constant_str = match constant_idx < self.chunk.constants.len() {
+            true => self.format_constant(constant_idx),
+            false => "Invalid constant index".to_string(),
+        };
 
-        let constant = &self.chunk.constants[constant_idx];
-        let constant_str = self.format_constant(constant_idx);
-        
-        (offset + 2, format!("{} {} {} | {}",
+        format!("{} {} {} | {}",
             self.colorize_offset(offset),
             self.colorize_op(op),
             self.colorize_constant_idx(constant_idx),
-            self.colorize_constant_str(&constant_str)))
+            self.colorize_constant_str(&constant_str))
     }
 
-    fn format_jump_instruction(&self, offset: usize, op: &chunk::OpCode) -> (usize, String) {
-        let current_loc = self.get_constant_value(offset + 1);
-        let jump_offset = self.get_constant_value(offset + 2);
-        
-        (offset + 3, format!("{} {} | {}->{}",
+    /// An instruction with a single operand, a byte or a short
+    fn format_operand_instruction(&self, offset: usize, op: &chunk::OpCode) -> String {
+        let operand = match op.operands() {
+            [2, ..] => self.chunk.read_short(offset + 1) as usize,
+            _ => self.chunk.read_byte(offset + 1) as usize,
+        };
+
+        format!("{} {} {}",
             self.colorize_offset(offset),
             self.colorize_op(op),
-            self.colorize_jump_loc(&current_loc),
-            self.colorize_jump_offset(&jump_offset)))
+            self.colorize_constant_idx(operand))
+    }
+
+    fn format_call_instruction(&self, offset: usize, op: &chunk::OpCode) -> String {
+        let instruction = self.format_constant_instruction(offset, op);
+        let arg_count = self.chunk.read_byte(offset + 3);
+
+        format!("{} ({} args)", instruction, arg_count)
+    }
+
+    fn format_global_instruction(&self, offset: usize, op: &chunk::OpCode) -> String {
+        let instruction = self.format_operand_instruction(offset, op);
+        let slot = self.chunk.read_short(offset + 1) as usize;
+        let name = match self.chunk.global_names.get(slot) {
+            Some(name) => self.interner.lookup(*name).to_string(),
+            None => "Invalid global slot".to_string(),
+        };
+
+        format!("{} | {}", instruction, self.colorize_constant_str(&name))
+    }
+
+    fn format_jump_instruction(&self, offset: usize, op: &chunk::OpCode) -> String {
+        let target = self.chunk.read_short(offset + 1);
+
+        format!("{} {} | ->{}",
+            self.colorize_offset(offset),
+            self.colorize_op(op),
+            self.colorize_jump_offset(&target.to_string()))
     }
 
     pub fn format_constant(&self, idx: usize) -> String {
         let constant = &self.chunk.constants[idx];
         match constant {
-            ValueType::String(s) | ValueType::Identifier(s) => {
-                format!("intr->{}", self.interner.lookup(*s))
-            },
+            ValueType::String(s) => format!("intr->\"{}\"", self.interner.lookup(*s)),
+            ValueType::Identifier(s) => format!("intr->{}", self.interner.lookup(*s)),
             _ => constant.display(&self.interner),
         }
     }
 
-    fn get_constant_value(&self, offset: usize) -> String {
-        self.chunk.code.get(offset)
-            .and_then(|v| if let chunk::VectorType::Constant(idx) = v {
-                Some(self.chunk.constants[*idx].display(&self.interner))
-            } else { None })
-            .unwrap_or_else(|| "Invalid constant".to_string())
+    /// The line of the first instruction compiled from it, `|` for the others and
+    /// the ones without a line (line 0, like the implicit return of the script)
+    fn format_line(&self, offset: usize) -> String {
+        match self.chunk.starts_line(offset) {
+            Some(line) if line > 0 => format!("{:4}", line),
+            _ => "   |".to_string(),
+        }
     }
 
     fn format_header(&self) -> String {
@@ -148,14 +213,6 @@ impl Debug {
         }
     }
 
-    fn colorize_jump_loc(&self, loc: &str) -> String {
-        if self.use_colors {
-            loc.purple().magenta().italic().to_string()
-        } else {
-            loc.to_string()
-        }
-    }
-
     fn colorize_jump_offset(&self, offset: &str) -> String {
         if self.use_colors {
             offset.purple().magenta().italic().to_string()
@@ -184,6 +241,8 @@ impl Debug {
 trait OpCodeExt {
     fn is_simple(&self) -> bool;
     fn uses_constant(&self) -> bool;
+    fn uses_operand(&self) -> bool;
+    fn uses_global(&self) -> bool;
     fn is_jump(&self) -> bool;
 }
 
@@ -194,23 +253,35 @@ impl OpCodeExt for chunk::OpCode {
             chunk::OpCode::OpSubtract | chunk::OpCode::OpMultiply | chunk::OpCode::OpDivide |
             chunk::OpCode::OpPower | chunk::OpCode::OpNil | chunk::OpCode::OpTrue |
             chunk::OpCode::OpFalse | chunk::OpCode::OpNot | chunk::OpCode::OpEqualEqual |
-            chunk::OpCode::OpGreater | chunk::OpCode::OpLess | chunk::OpCode::OpPrint |
-            chunk::OpCode::OpPop
+            chunk::OpCode::OpGreater | chunk::OpCode::OpLess | chunk::OpCode::OpPop |
+            chunk::OpCode::OpToString | chunk::OpCode::OpMatMul | chunk::OpCode::OpEndTry |
+            chunk::OpCode::OpThrow | chunk::OpCode::OpRethrow | chunk::OpCode::OpModulo |
+            chunk::OpCode::OpIndex
         )
     }
 
     fn uses_constant(&self) -> bool {
+        matches!(self, chunk::OpCode::OpConstant | chunk::OpCode::OpGetField)
+    }
+
+    fn uses_global(&self) -> bool {
+        matches!(self,
+            chunk::OpCode::OpDefineGlobal | chunk::OpCode::OpGetGlobal | chunk::OpCode::OpSetGlobal
+        )
+    }
+
+    fn uses_operand(&self) -> bool {
         matches!(self,
-            chunk::OpCode::OpConstant | chunk::OpCode::OpDefineGlobal |
-            chunk::OpCode::OpGetGlobal | chunk::OpCode::OpSetGlobal |
-            chunk::OpCode::OpDefineLocal | chunk::OpCode::OpGetLocal |
-            chunk::OpCode::OpSetLocal
+            chunk::OpCode::OpBuildArray | chunk::OpCode::OpCallValue | chunk::OpCode::OpPrint |
+            chunk::OpCode::OpConstantSmall |
+            chunk::OpCode::OpDefineLocal | chunk::OpCode::OpGetLocal | chunk::OpCode::OpSetLocal
         )
     }
 
     fn is_jump(&self) -> bool {
         matches!(self,
-            chunk::OpCode::OpJump | chunk::OpCode::OpJumpIfFalse | chunk::OpCode::OpLoop
+            chunk::OpCode::OpJump | chunk::OpCode::OpJumpIfFalse | chunk::OpCode::OpLoop |
+            chunk::OpCode::OpTry | chunk::OpCode::OpFinally
         )
     }
 }
\ No newline at end of file
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+use crate::scanner::Position;
+
+/// How bad a problem is, only errors stop a program from compiling
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in the source, editors can show all of them at once
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub severity: Severity,
+}
+
+impl Diagnostic {
+    pub fn error(position: Position, message: impl Into<String>) -> Self {
+        Self {
+            line: position.line,
+            column: position.column,
+            message: message.into(),
+            severity: Severity::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "[line {}:{}] {}: {}",
+            self.line, self.column, severity, self.message
+        )
+    }
+}
+
+/// One diagnostic per line, how the CLI shows them
+pub fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -13,6 +13,24 @@ pub struct Interner {
 }
 
 impl Interner {
+    /// An interner with room for `capacity` strings before it reallocates
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            map: HashMap::with_capacity(capacity),
+            vec: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Interns every string of `names`, their indices are in the same order
+    pub fn intern_all(&mut self, names: &[&str]) -> Vec<StringObjIdx> {
+        self.map.reserve(names.len());
+        self.vec.reserve(names.len());
+        names
+            .iter()
+            .map(|name| self.intern_string(name.to_string()))
+            .collect()
+    }
+
     pub fn intern_string(&mut self, name: String) -> StringObjIdx {
         if let Some(&idx) = self.map.get(&name) {
             return idx;
@@ -25,6 +43,29 @@ impl Interner {
     }
 
     pub fn lookup(&self, idx: StringObjIdx) -> &str {
-        self.vec[idx as usize].as_str()
+        self.vec[idx].as_str()
+    }
+
+    /// Forgets every string, keeping the allocations
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.vec.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_all() {
+        let mut interner = Interner::with_capacity(4);
+        let a = interner.intern_string("a".to_string());
+
+        let indices = interner.intern_all(&["tensor", "a", "relu", "tensor"]);
+        assert_eq!(indices, vec![1, a, 2, 1]);
+        assert_eq!(interner.lookup(1), "tensor");
+        assert_eq!(interner.lookup(2), "relu");
+        assert_eq!(interner.intern_string("relu".to_string()), 2);
     }
 }
@@ -2,16 +2,18 @@ pub mod ast;
 pub mod chunk;
 pub mod compiler;
 pub mod debug;
+pub mod diagnostic;
 pub mod interner;
+pub mod native;
 pub mod scanner;
 pub mod tensor;
 pub mod value;
 pub mod vm;
 
-use crate::vm::Result::{CompileErr, Ok, RuntimeErr};
+use crate::vm::Result::{Changed, CompileErr, Ok, Paused, RuntimeErr};
 use crate::{ast::Parser, scanner::Lexer};
 
-use ast::{ast_to_ascii, ASTNode};
+use ast::ast_to_ascii;
 use wasm_bindgen::prelude::*;
 
 
@@ -27,7 +29,15 @@ pub fn run_source(src: &str) -> Vec<String> {
     // println!("-------------");
 
     let mut compiler = compiler::Compiler::new();
-    let (bytecode, interner) = compiler.compile(out.clone());
+    let (bytecode, interner) = match compiler.compile(out.clone()) {
+        std::result::Result::Ok(compiled) => compiled,
+        Err(diagnostics) => {
+            return vec![format!(
+                "CompileError({:?})",
+                diagnostic::format_diagnostics(&diagnostics)
+            )]
+        }
+    };
     // println!("{:?}", bytecode);
 
     let debug = debug::Debug::new("test", bytecode.clone(), interner.clone());
@@ -37,7 +47,7 @@ pub fn run_source(src: &str) -> Vec<String> {
     let result = vm.run();
 
     match result {
-        Ok(v) => {
+        Ok(v, _) => {
             // join string to /n
             let mut result = String::new();
             for i in v.iter() {
@@ -56,6 +66,8 @@ pub fn run_source(src: &str) -> Vec<String> {
         CompileErr(e) => vec![format!("CompileError({:?})", e), disassemble_output],
         // RuntimeErr(e) => format!("RuntimeError({:?})", e),
         RuntimeErr(e) => vec![format!("RuntimeError({:?})", e), disassemble_output],
+        // no breakpoints or watches are set, the program runs to the end
+        Paused(_) | Changed { .. } => unreachable!(),
     }
 }
 
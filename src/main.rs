@@ -1,17 +1,27 @@
-mod ast;
-mod chunk;
-mod compiler;
-mod debug;
-mod interner;
-mod scanner;
-mod tensor;
-mod value;
-mod vm;
-
-use crate::{ast::Parser, scanner::Lexer};
-use ast::ast_to_ascii;
+use std::{
+    io::{self, BufRead, IsTerminal, Write},
+    path::PathBuf,
+};
+
 use clap::Parser as ClapParser;
-use vm::Result;
+use colored::Colorize;
+use grad::{
+    ast::{ast_to_ascii, Parser},
+    chunk::Chunk,
+    compiler, debug,
+    diagnostic::format_diagnostics,
+    interner::Interner,
+    scanner::{Lexer, LexingError, TokenType},
+    value::ValueType,
+    vm::{self, Capabilities, Result, VmConfig},
+};
+
+const DEFAULT_PROMPT: &str = "> ";
+const DEFAULT_PROMPT_CONT: &str = "... ";
+
+// exit codes of a script, from sysexits.h: EX_DATAERR and EX_SOFTWARE
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
 
 #[derive(ClapParser, Debug)]
 #[command(version, about, long_about = None)]
@@ -22,17 +32,181 @@ struct Args {
 
     #[clap(short, long)]
     debug: bool,
+
+    /// Print the bytecode of the script, with the line of each instruction, instead of running it
+    #[clap(long)]
+    disassemble: bool,
+
+    /// Disable every native and tensor method, only pure computation is allowed
+    #[clap(long)]
+    no_std: bool,
+
+    /// Don't define the helper functions of the prelude, like `abs` and `square`
+    #[clap(long)]
+    no_prelude: bool,
+
+    /// Print how often each tensor method ran, and the shape it last ran on, when the program ends
+    #[clap(long)]
+    profile_tensor_ops: bool,
+
+    /// Seed the random number generator, so `randn` and `shuffle` give the same values on every run
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Stop the program with an error after this many instructions
+    #[clap(long)]
+    max_steps: Option<usize>,
+
+    /// Deepest chain of nested calls a program can make
+    #[clap(long, default_value_t = vm::FRAMES_MAX)]
+    max_frames: usize,
+
+    /// Values the stack can hold
+    #[clap(long, default_value_t = vm::STACK_MAX)]
+    stack_size: usize,
+
+    /// Check the bytecode for errors before running it (always done in debug builds)
+    #[clap(long)]
+    verify: bool,
+
+    /// Make redefining a global with `let` an error
+    #[clap(long)]
+    strict: bool,
+
+    /// Deepest nesting of arrays a program can build
+    #[clap(long, default_value_t = vm::MAX_VALUE_DEPTH)]
+    max_value_depth: usize,
+
+    /// Inline the globals defined once to a constant and never assigned (scripts only)
+    #[clap(long)]
+    ast_optimize: bool,
+
+    /// Color the errors and the --debug output: auto (when printing to a terminal), always or never
+    #[clap(long, value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Same as --color never
+    #[clap(long, conflicts_with = "color")]
+    no_color: bool,
+
+    /// REPL prompt, overrides $GRAD_PROMPT (default "> ")
+    #[clap(long)]
+    prompt: Option<String>,
+
+    /// REPL prompt for continuation lines, overrides $GRAD_PROMPT_CONT (default "... ")
+    #[clap(long)]
+    prompt_cont: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+/// How the VM runs a program, from the command line flags
+#[derive(Debug, Clone)]
+pub struct VmOptions {
+    verify: bool,
+    strict: bool,
+    capabilities: Capabilities,
+    // compile with `Compiler::set_optimize`, a REPL line could redefine what
+    // an earlier one inlined so the REPL doesn't
+    ast_optimize: bool,
+    // color the disassembly
+    color: bool,
+    max_value_depth: usize,
+    // define the functions of `vm::PRELUDE`
+    prelude: bool,
+    // print `VM::tensor_op_report` when the program ends
+    profile_tensor_ops: bool,
+    // the limits of the VM and the seed of its random number generator
+    config: VmConfig,
+    // the script being run, its imports are relative to it
+    source_path: Option<PathBuf>,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            verify: false,
+            strict: false,
+            capabilities: Capabilities::ALL,
+            ast_optimize: false,
+            color: false,
+            max_value_depth: vm::MAX_VALUE_DEPTH,
+            prelude: true,
+            profile_tensor_ops: false,
+            config: VmConfig::default(),
+            source_path: None,
+        }
+    }
+}
+
+impl VmOptions {
+    fn build_vm(&self, chunk: Chunk, interner: Interner) -> vm::VM {
+        let mut vm = vm::VM::with_config(chunk, interner, self.capabilities, self.config);
+        // verification is on by default in debug builds, the flag only turns it on
+        if self.verify {
+            vm.set_verify(true);
+        }
+        vm.set_strict(self.strict);
+        vm.set_max_value_depth(self.max_value_depth);
+        if !self.prelude {
+            vm.set_prelude(false);
+        }
+        vm.set_profile_tensor_ops(self.profile_tensor_ops);
+        vm.set_error_lines(true);
+        vm
+    }
 }
 
 fn main() {
     let args = Args::parse();
+    let color = match (args.no_color, args.color) {
+        (true, _) | (_, ColorMode::Never) => false,
+        (_, ColorMode::Always) => true,
+        (_, ColorMode::Auto) => io::stdout().is_terminal() && io::stderr().is_terminal(),
+    };
+    // everything printed with `colored` (errors, the trace) follows the flag
+    colored::control::set_override(color);
+
+    let options = VmOptions {
+        verify: args.verify,
+        strict: args.strict,
+        ast_optimize: args.ast_optimize,
+        color,
+        max_value_depth: args.max_value_depth,
+        prelude: !args.no_prelude,
+        profile_tensor_ops: args.profile_tensor_ops,
+        config: VmConfig {
+            stack_size: args.stack_size,
+            max_frames: args.max_frames,
+            max_steps: args.max_steps,
+            seed: args.seed,
+        },
+        source_path: None,
+        capabilities: match args.no_std {
+            true => Capabilities::NONE,
+            false => Capabilities::ALL,
+        },
+    };
 
     // Check if args.script is provided
     if args.script.is_empty() {
-        // run as a repl
-        // run_repl();
+        let prompt = resolve_prompt(
+            args.prompt,
+            std::env::var("GRAD_PROMPT").ok(),
+            DEFAULT_PROMPT,
+        );
+        let prompt_cont = resolve_prompt(
+            args.prompt_cont,
+            std::env::var("GRAD_PROMPT_CONT").ok(),
+            DEFAULT_PROMPT_CONT,
+        );
 
-        panic!("REPL is not supported yet.");
+        run_repl(&prompt, &prompt_cont, args.debug, options);
     } else {
         // read file
 
@@ -41,54 +215,357 @@ fn main() {
             Err(e) => panic!("Error reading file: {}", e),
         };
 
-        run_source(&src, args.debug);
+        let options = VmOptions {
+            source_path: Some(PathBuf::from(&args.script)),
+            ..options
+        };
+        let result = match args.disassemble {
+            true => disassemble_source(&src, &options).map_or_else(
+                |err| err,
+                |listing| {
+                    println!("{}", listing);
+                    Result::Ok(Vec::new(), ValueType::Nil)
+                },
+            ),
+            false => run_source_with(&src, args.debug, options),
+        };
+        if let err @ (Result::CompileErr(_) | Result::RuntimeErr(_)) = &result {
+            print_error(err);
+        }
+        std::process::exit(exit_code(&result));
     }
 }
 
-pub fn run_source(src: &str, debug: bool) -> Result {
-    let mut lexer = Lexer::new(src.to_string());
+/// The exit code of a script that ended with `result`, 0 unless it failed
+fn exit_code(result: &Result) -> i32 {
+    match result {
+        Result::CompileErr(_) => EXIT_COMPILE_ERROR,
+        Result::RuntimeErr(_) => EXIT_RUNTIME_ERROR,
+        _ => 0,
+    }
+}
 
-    if debug {
-        println!("============= Tokens =============");
-        for token in lexer.tokens.iter().rev() {
-            println!("{:?}", token);
+/// Errors are red when colors are on
+fn print_error(err: &Result) {
+    eprintln!("{}", err.to_string().red());
+}
+
+/// A prompt passed as a flag wins over the environment variable, which wins over the default
+fn resolve_prompt(flag: Option<String>, env: Option<String>, default: &str) -> String {
+    flag.or(env).unwrap_or_else(|| default.to_string())
+}
+
+/// Every input is compiled and run on its own, against the globals of the previous ones
+fn run_repl(prompt: &str, prompt_cont: &str, debug: bool, options: VmOptions) {
+    let stdin = io::stdin();
+    let mut source = String::new();
+
+    let mut compiler = compiler::Compiler::new();
+    compiler.set_capabilities(options.capabilities);
+    let mut vm = options.build_vm(Chunk::new(), Interner::default());
+    vm.set_profile_errors(debug);
+    // the chunk of the last input that compiled, for `:dis`
+    let mut last: Option<Chunk> = None;
+
+    loop {
+        print!(
+            "{}",
+            if source.is_empty() {
+                prompt
+            } else {
+                prompt_cont
+            }
+        );
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break, // EOF
+            Ok(_) => source.push_str(&line),
+            Err(e) => panic!("Error reading input: {}", e),
         }
-    };
 
-    let out = Parser::new(&mut lexer).parse().unwrap();
+        if source == line {
+            if let Some(output) = repl_command(&line, last.as_ref(), &vm.interner, options.color) {
+                println!("{}", output);
+                source.clear();
+                continue;
+            }
+        }
 
-    if debug {
-        println!("============= AST =============");
+        // keep reading until all the brackets are closed
+        if !is_complete(&source) {
+            continue;
+        }
+
+        if debug {
+            print_debug(&source);
+        }
+
+        match compiler.compile_source(&source, &mut vm.interner) {
+            Ok(chunk) => {
+                if debug {
+                    let mut debugger =
+                        debug::Debug::new("repl", chunk.clone(), vm.interner.clone());
+                    debugger.set_color_usage(options.color);
+                    println!("{}", debugger.disassemble());
+                }
+
+                last = Some(chunk.clone());
+                vm.load(chunk);
+                if let err @ Result::RuntimeErr(_) = vm.run() {
+                    print_error(&err);
+                }
+            }
+            Err(diagnostics) => print_error(&Result::CompileErr(format_diagnostics(&diagnostics))),
+        }
+        source.clear();
+    }
+
+    if let Some(report) = vm.tensor_op_report() {
+        eprintln!("{}", report);
+    }
+}
+
+/// The output of a REPL command like `:dis`, `None` when `line` isn't one
+fn repl_command(
+    line: &str,
+    last: Option<&Chunk>,
+    interner: &Interner,
+    color: bool,
+) -> Option<String> {
+    let command = line.trim().strip_prefix(':')?;
+    Some(match (command, last) {
+        ("dis", Some(chunk)) => {
+            let mut debugger = debug::Debug::new("repl", chunk.clone(), interner.clone());
+            debugger.set_color_usage(color);
+            debugger.disassemble()
+        }
+        ("dis", None) => "Nothing compiled yet, enter a line first to see its bytecode".to_string(),
+        (command, _) => format!("Unknown command ':{}', the commands are :dis", command),
+    })
+}
+
+fn is_complete(src: &str) -> bool {
+    let lexer = Lexer::new(src.to_string());
+    let depth = lexer
+        .tokens
+        .iter()
+        .fold(0, |depth, token| match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth + 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth - 1,
+            _ => depth,
+        });
+    // a `"""` string goes on until its closing `"""`, a `"` one is an error
+    let unterminated = lexer.tokens.first().is_some_and(|token| {
+        token.token_type == TokenType::Error(LexingError::UnterminatedString)
+            && src[token.span.start..].starts_with(r#"""""#)
+    });
+
+    depth <= 0 && !unterminated
+}
+
+pub fn run_source(src: &str, debug: bool) -> Result {
+    run_source_with(src, debug, VmOptions::default())
+}
+
+/// Prints the tokens and the ast of `src`
+fn print_debug(src: &str) {
+    let mut lexer = Lexer::new(src.to_string());
+    println!("{}", "============= Tokens =============".dimmed());
+    for token in lexer.tokens.iter().rev() {
+        println!("{:?}", token);
+    }
+
+    if let Ok(out) = Parser::new(&mut lexer).parse() {
+        println!("{}", "============= AST =============".dimmed());
         let mut ast_output = String::new();
         for stmt in out.iter() {
             ast_output.push_str(&ast_to_ascii(stmt, 0));
         }
         println!("{}", ast_output);
     }
+}
 
+/// Compiles `src` like a script run with `options`
+fn compile_with(
+    src: &str,
+    options: &VmOptions,
+    interner: &mut Interner,
+) -> std::result::Result<Chunk, Result> {
     let mut compiler = compiler::Compiler::new();
-    let (bytecode, interner) = compiler.compile(out);
+    compiler.set_optimize(options.ast_optimize);
+    compiler.set_capabilities(options.capabilities);
+    if let Some(path) = &options.source_path {
+        compiler.set_source_path(path);
+    }
+    compiler
+        .compile_source(src, interner)
+        .map_err(|diagnostics| Result::CompileErr(format_diagnostics(&diagnostics)))
+}
+
+/// The listing of the bytecode of `src` and of the functions it defines, for `--disassemble`
+pub fn disassemble_source(src: &str, options: &VmOptions) -> std::result::Result<String, Result> {
+    let mut interner = Interner::default();
+    let bytecode = compile_with(src, options, &mut interner)?;
+
+    let mut debugger = debug::Debug::new("script", bytecode, interner);
+    debugger.set_color_usage(options.color);
+    Ok(debugger.disassemble())
+}
 
+pub fn run_source_with(src: &str, debug: bool, options: VmOptions) -> Result {
     if debug {
-        println!("============= Bytecode =============");
+        print_debug(src);
+    }
+
+    let mut interner = Interner::default();
+    let bytecode = match compile_with(src, &options, &mut interner) {
+        Ok(bytecode) => bytecode,
+        Err(err) => return err,
+    };
+
+    if debug {
+        println!("{}", "============= Bytecode =============".dimmed());
         println!("{:?}", bytecode);
     }
 
-    let debugger = debug::Debug::new("test", bytecode.clone(), interner.clone());
+    let mut debugger = debug::Debug::new("test", bytecode.clone(), interner.clone());
+    debugger.set_color_usage(options.color);
 
     if debug {
         println!("{}", debugger.disassemble());
     }
 
-    let mut vm = vm::VM::init(bytecode, interner);
+    let mut vm = options.build_vm(bytecode, interner);
+    vm.set_profile_errors(debug);
     let result = vm.run();
-
-    return result;
+    if let Some(report) = vm.tensor_op_report() {
+        eprintln!("{}", report);
+    }
+    result
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{run_source, tensor::Tensor, value::ValueType, vm::Result};
+    use crate::{
+        disassemble_source, exit_code, is_complete, repl_command, resolve_prompt, run_source,
+        run_source_with, VmOptions, DEFAULT_PROMPT,
+    };
+    use grad::value::ValueType;
+    use grad::vm::{Capabilities, Result};
+    use grad::{compiler::Compiler, interner::Interner};
+
+    #[test]
+    fn test_resolve_prompt() {
+        // unset
+        assert_eq!(resolve_prompt(None, None, DEFAULT_PROMPT), "> ");
+
+        // set through the environment
+        let env = Some("grad> ".to_string());
+        assert_eq!(resolve_prompt(None, env.clone(), DEFAULT_PROMPT), "grad> ");
+
+        // the flag takes precedence over the environment
+        let flag = Some(">>> ".to_string());
+        assert_eq!(resolve_prompt(flag, env, DEFAULT_PROMPT), ">>> ");
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&run_source("print(1);", false)), 0);
+        assert_eq!(exit_code(&run_source("let = 1;", false)), 65);
+        assert_eq!(exit_code(&run_source("print(1 + true);", false)), 70);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let src = "let a = 1;\nprint(a + 2);";
+        let listing = disassemble_source(src, &VmOptions::default()).unwrap();
+        let lines: Vec<_> = listing.lines().collect();
+
+        assert_eq!(lines[0], "======== script ========");
+        let ops: Vec<_> = lines[1..lines.len() - 1]
+            .iter()
+            .map(|line| line.split_whitespace().nth(2).unwrap())
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                "OP_CONSTANT_SMALL",
+                "OP_DEFINE_GLOBAL",
+                "OP_GET_GLOBAL",
+                "OP_CONSTANT_SMALL",
+                "OP_ADD",
+                "OP_PRINT",
+                "OP_RETURN"
+            ]
+        );
+        // the line is shown on the first instruction of each line, the global's name resolved
+        assert!(lines[1].starts_with("   1 0000 OP_CONSTANT_SMALL"));
+        assert!(lines[2].starts_with("   | "));
+        assert!(lines[2].ends_with("| a"));
+        assert!(lines[3].starts_with("   2 "));
+
+        assert!(matches!(
+            disassemble_source("let = 1;", &VmOptions::default()),
+            Err(Result::CompileErr(_))
+        ));
+    }
+
+    #[test]
+    fn test_repl_dis() {
+        let mut interner = Interner::default();
+        assert_eq!(
+            repl_command(":dis\n", None, &interner, false),
+            Some("Nothing compiled yet, enter a line first to see its bytecode".to_string())
+        );
+
+        let chunk = Compiler::new()
+            .compile_source("print(1);", &mut interner)
+            .unwrap();
+        let listing = repl_command(":dis\n", Some(&chunk), &interner, false).unwrap();
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines[0], "======== repl ========");
+        assert!(lines[1].contains("OP_CONSTANT_SMALL"));
+        assert!(lines[2].contains("OP_PRINT"));
+
+        assert_eq!(
+            repl_command(":quit", Some(&chunk), &interner, false),
+            Some("Unknown command ':quit', the commands are :dis".to_string())
+        );
+        assert_eq!(
+            repl_command("print(1);", Some(&chunk), &interner, false),
+            None
+        );
+    }
+
+    #[test]
+    fn test_repl_continuation() {
+        assert!(is_complete("let a = 1;"));
+        assert!(!is_complete("if (a > 1) {"));
+        assert!(!is_complete("print([1,"));
+        assert!(is_complete("if (a > 1) {\n print(a);\n}"));
+        assert!(!is_complete("let s = \"\"\"first line"));
+        assert!(is_complete("let s = \"\"\"first line\nsecond line\"\"\";"));
+        // a `"` string missing its quote is an error, not a continuation
+        assert!(is_complete("let s = \"first line"));
+    }
+
+    #[test]
+    fn test_print_nested_array() {
+        let src = r#"
+        let a = [1, 2.5];
+        print([a, [true, [false]], []]);
+        "#;
+
+        assert_eq!(
+            run_source(src, false),
+            Result::Ok(
+                vec!["[[1, 2.5], [true, [false]], []]".to_string()],
+                ValueType::Nil
+            )
+        );
+    }
 
     #[test]
     fn test_micrograd_example() {
@@ -108,47 +585,124 @@ mod tests {
                         print(g) // prints 24.7041, the outcome of this forward pass
                         "#;
 
-        let out = run_source(&src, false);
-
-        // assert_eq!(
-        //     out,
-        //     Result::Ok(vec![ValueType::Tensor(Tensor::from(24.70408163265306))])
-        // );
-    }
-
-    // #[test]
-    // fn test_scopes() {
-    //     let src = r#"
-    //     let a = 4;
-    //     {
-    //         let b = 5;
-    //         print(b);
-    //         {
-    //             let c = 10;
-    //             print(c);
-    //             let b = 353;
-    //             print(b);
-    //         }
-    //         print(b);
-    //         b = 11;
-    //         print(b);
-    //         a = 12;
-    //     }
-    //     print(a);
-    //     "#;
-
-    //     let out = run_source(&src, false);
-
-    //     assert_eq!(
-    //         out,
-    //         Result::Ok(vec![
-    //             ValueType::Tensor(Tensor::from(5.0)),
-    //             ValueType::Tensor(Tensor::from(10.0)),
-    //             ValueType::Tensor(Tensor::from(353.0)),
-    //             ValueType::Tensor(Tensor::from(5.0)),
-    //             ValueType::Tensor(Tensor::from(11.0)),
-    //             ValueType::Tensor(Tensor::from(12.0))
-    //         ])
-    //     );
-    // }
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec!["24.70408163265306".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_strict_redefinition() {
+        let src = "let x = 1; let x = 2; print(x);";
+        assert_eq!(
+            run_source(src, false),
+            Result::Ok(vec!["2".to_string()], ValueType::Nil)
+        );
+
+        let strict = VmOptions {
+            strict: true,
+            ..VmOptions::default()
+        };
+        assert_eq!(
+            run_source_with(src, false, strict.clone()),
+            Result::RuntimeErr("[line 1] 'x' already declared".to_string())
+        );
+        // assigning isn't declaring
+        assert_eq!(
+            run_source_with("let x = 1; x = 2; print(x);", false, strict),
+            Result::Ok(vec!["2".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_runtime_error_line() {
+        let src = "let a = 1;\nprint(a);\nprint(b);\n";
+        assert_eq!(
+            run_source(src, false),
+            Result::RuntimeErr("[line 3] Undefined variable 'b'".to_string())
+        );
+
+        // the line in a function is the line of its body
+        let src = "fn f(x) {\n  let y = x;\n  return y + true;\n}\nprint(f(1));";
+        assert_eq!(
+            run_source(src, false),
+            Result::RuntimeErr(
+                "[line 3] Operands must be numbers or tensors. Got: number and bool".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_prelude() {
+        let src = "print(lerp(0, 10, 0.5), abs(-3), square(1.5));";
+        assert_eq!(
+            run_source(src, false),
+            Result::Ok(vec!["5 3 2.25".to_string()], ValueType::Nil)
+        );
+
+        let no_prelude = VmOptions {
+            prelude: false,
+            ..VmOptions::default()
+        };
+        assert_eq!(
+            run_source_with(src, false, no_prelude),
+            Result::RuntimeErr("[line 1] Undefined function 'lerp'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_no_std_tensor_literal() {
+        // a literal isn't built at compile time either, like any other argument
+        let no_std = VmOptions {
+            capabilities: Capabilities::NONE,
+            ..VmOptions::default()
+        };
+        for src in ["print(tensor([1, 2]) + 1);", "let a = 1; print(tensor(a));"] {
+            assert_eq!(
+                run_source_with(src, false, no_std.clone()),
+                Result::RuntimeErr(
+                    "[line 1] Undefined function 'tensor', builtins are disabled (--no-std)"
+                        .to_string()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_scopes() {
+        let src = r#"
+        let a = 4;
+        {
+            let b = 5;
+            print(b);
+            {
+                let c = 10;
+                print(c);
+                let b = 353;
+                print(b);
+            }
+            print(b);
+            b = 11;
+            print(b);
+            a = 12;
+        }
+        print(a);
+        { let d = 1; }
+        { let e = 2; print(e); }
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(
+                ["5", "10", "353", "5", "11", "12", "2"]
+                    .map(String::from)
+                    .to_vec(),
+                ValueType::Nil
+            )
+        );
+    }
 }
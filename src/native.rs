@@ -0,0 +1,782 @@
+// Functions implemented in rust that grad code can call like `tensor(2.0)`
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    rc::Rc,
+};
+
+use crate::{
+    interner::Interner,
+    tensor::{self, PrintOptions, Tensor},
+    value::ValueType,
+};
+
+thread_local! {
+    // state of the random number generator, seeded differently on every run
+    static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish());
+}
+
+/// A native gets the arguments of the call and returns the value it leaves on the stack,
+/// the interner is there for natives that make or read strings
+pub type NativeFn = fn(&[ValueType], &mut Interner) -> Result<ValueType, String>;
+
+/// A native and the names of its parameters, which can be passed by keyword
+/// like `randn(rows=2, cols=3)`
+pub struct Native {
+    pub name: &'static str,
+    pub params: &'static [&'static str],
+    /// takes any number of arguments, `params` only names the first ones. Other
+    /// natives are called with exactly one argument per parameter
+    pub variadic: bool,
+    pub function: NativeFn,
+}
+
+impl Native {
+    /// Calls the native after checking the number of arguments it got
+    pub fn call(&self, args: &[ValueType], interner: &mut Interner) -> Result<ValueType, String> {
+        if !self.variadic && args.len() != self.params.len() {
+            return Err(match self.params.len() {
+                0 => format!("{} takes no arguments", self.name),
+                arity => format!(
+                    "{} expects {} arguments, got {}",
+                    self.name,
+                    arity,
+                    args.len()
+                ),
+            });
+        }
+        (self.function)(args, interner)
+    }
+}
+
+/// A tensor method gets its receiver and the arguments of the call, `x.f(a)` calls
+/// `f` with `x` and `[a]`
+pub type TensorMethodFn = fn(&Tensor, &[ValueType]) -> Result<ValueType, String>;
+
+/// A method called on a tensor like `x.relu()`, numbers are lifted to tensors
+pub struct TensorMethod {
+    pub name: &'static str,
+    /// the arguments it takes besides the receiver, the ones at the end are optional
+    pub params: &'static [&'static str],
+    pub function: TensorMethodFn,
+}
+
+impl TensorMethod {
+    /// Calls the method after checking it didn't get too many arguments
+    pub fn call(&self, tensor: &Tensor, args: &[ValueType]) -> Result<ValueType, String> {
+        if args.len() > self.params.len() {
+            return Err(match self.params.len() {
+                0 => format!("{} takes no arguments", self.name),
+                1 => format!("{} takes at most 1 argument", self.name),
+                arity => format!("{} takes at most {} arguments", self.name, arity),
+            });
+        }
+        (self.function)(tensor, args)
+    }
+}
+
+/// Every tensor method. Each leaves exactly one value on the stack, the ones
+/// without a natural result return nil, so a call statement can always be popped
+pub const TENSOR_METHODS: &[TensorMethod] = &[
+    TensorMethod {
+        name: "relu",
+        params: &[],
+        function: |tensor, _| Ok(ValueType::Tensor(tensor.relu())),
+    },
+    TensorMethod {
+        name: "sigmoid",
+        params: &[],
+        function: |tensor, _| Ok(ValueType::Tensor(tensor.sigmoid())),
+    },
+    TensorMethod {
+        name: "tanh",
+        params: &[],
+        function: |tensor, _| Ok(ValueType::Tensor(tensor.tanh())),
+    },
+    TensorMethod {
+        name: "sum",
+        params: &[],
+        function: |tensor, _| Ok(ValueType::Tensor(tensor.sum())),
+    },
+    TensorMethod {
+        name: "mean",
+        params: &[],
+        function: |tensor, _| Ok(ValueType::Tensor(tensor.mean())),
+    },
+    TensorMethod {
+        name: "backward",
+        params: &["gradient"],
+        function: backward,
+    },
+    TensorMethod {
+        name: "grad",
+        params: &[],
+        function: |tensor, _| Ok(ValueType::Tensor(tensor.gradient())),
+    },
+    // `grad` of a tensor no gradient reached is zeros too, `has_grad` tells them
+    // apart from a gradient that is genuinely zero
+    TensorMethod {
+        name: "has_grad",
+        params: &[],
+        function: |tensor, _| Ok(ValueType::Boolean(tensor.has_gradient())),
+    },
+    TensorMethod {
+        name: "zero_grad",
+        params: &[],
+        function: |tensor, _| {
+            tensor.clear_gradient();
+            Ok(ValueType::Nil)
+        },
+    },
+];
+
+/// `backward` returns the tensor itself, so it can be chained as `loss.backward().grad()`.
+/// `t.backward(gradient)` starts from the upstream gradient of a tensor that isn't a scalar
+fn backward(tensor: &Tensor, args: &[ValueType]) -> Result<ValueType, String> {
+    match args {
+        [] => tensor.backward()?,
+        [seed] => match seed.to_tensor() {
+            Some(seed) => tensor.backward_with(&seed)?,
+            None => {
+                return Err(format!(
+                    "backward expects a tensor gradient, got {}",
+                    seed.type_name()
+                ))
+            }
+        },
+        _ => unreachable!(),
+    }
+    Ok(ValueType::Tensor(tensor.clone()))
+}
+
+/// Every native available to a program
+pub const NATIVES: &[Native] = &[
+    Native {
+        name: "tensor",
+        params: &["data"],
+        variadic: true,
+        function: tensor,
+    },
+    Native {
+        name: "randn",
+        params: &["rows", "cols"],
+        variadic: true,
+        function: randn,
+    },
+    Native {
+        name: "log_softmax",
+        params: &["logits"],
+        variadic: false,
+        function: log_softmax,
+    },
+    Native {
+        name: "onehot",
+        params: &["index", "num_classes"],
+        variadic: false,
+        function: onehot,
+    },
+    Native {
+        name: "batches",
+        params: &["data", "batch_size"],
+        variadic: false,
+        function: batches,
+    },
+    Native {
+        name: "shuffle",
+        params: &["data"],
+        variadic: false,
+        function: shuffle,
+    },
+    Native {
+        name: "graph_size",
+        params: &[],
+        variadic: false,
+        function: graph_size,
+    },
+    Native {
+        name: "free_graph",
+        params: &["tensor"],
+        variadic: false,
+        function: free_graph,
+    },
+    Native {
+        name: "export_graph",
+        params: &["tensor", "path"],
+        variadic: false,
+        function: export_graph,
+    },
+    Native {
+        name: "all",
+        params: &["tensor"],
+        variadic: false,
+        function: all,
+    },
+    Native {
+        name: "any",
+        params: &["tensor"],
+        variadic: false,
+        function: any,
+    },
+    Native {
+        name: "approx_eq",
+        params: &["a", "b", "eps"],
+        variadic: false,
+        function: approx_eq,
+    },
+    Native {
+        name: "set_print_options",
+        params: &["sep", "row_sep"],
+        variadic: false,
+        function: set_print_options,
+    },
+    Native {
+        name: "set_grad",
+        params: &["tensor", "gradient"],
+        variadic: false,
+        function: set_grad,
+    },
+    Native {
+        name: "min",
+        params: &["a", "b"],
+        variadic: false,
+        function: min,
+    },
+    Native {
+        name: "max",
+        params: &["a", "b"],
+        variadic: false,
+        function: max,
+    },
+    Native {
+        name: "clamp",
+        params: &["x", "lo", "hi"],
+        variadic: false,
+        function: clamp,
+    },
+    Native {
+        name: "str",
+        params: &[],
+        variadic: true,
+        function: str,
+    },
+    Native {
+        name: "format",
+        params: &["template"],
+        variadic: true,
+        function: format,
+    },
+    Native {
+        name: "len",
+        params: &["string"],
+        variadic: false,
+        function: len,
+    },
+    Native {
+        name: "char_at",
+        params: &["string", "index"],
+        variadic: false,
+        function: char_at,
+    },
+];
+
+/// Puts the keyword arguments of a call to `native` in the place of the parameter
+/// they name. `args` are the positional arguments followed by one value per name
+pub fn bind_keywords(
+    native: &Native,
+    args: &[ValueType],
+    names: &[&str],
+) -> Result<Vec<ValueType>, String> {
+    let positional = args.len() - names.len();
+    let mut bound: Vec<Option<ValueType>> = args[..positional].iter().cloned().map(Some).collect();
+
+    for (name, value) in names.iter().zip(&args[positional..]) {
+        let index = native
+            .params
+            .iter()
+            .position(|param| param == name)
+            .ok_or_else(|| format!("{} got an unknown keyword argument '{}'", native.name, name))?;
+
+        if bound.len() <= index {
+            bound.resize(index + 1, None);
+        }
+        if bound[index].is_some() {
+            return Err(format!(
+                "{} got multiple values for '{}'",
+                native.name, name
+            ));
+        }
+        bound[index] = Some(value.clone());
+    }
+
+    // a keyword can't leave a hole before it
+    bound
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| {
+            value.ok_or_else(|| {
+                format!(
+                    "{} missing argument '{}'",
+                    native.name, native.params[index]
+                )
+            })
+        })
+        .collect()
+}
+
+/// `tensor(2.0)` or `tensor([[1, 2], [3, 4]])` makes a leaf tensor, a tensor
+/// argument is copied without its graph. More arguments are the rows of the
+/// tensor, `tensor(1, 2, 3)` is `tensor([1, 2, 3])`
+fn tensor(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    let mut data = Vec::new();
+    let shape = match args {
+        [] => return Err("tensor takes at least 1 argument".to_string()),
+        [ValueType::Tensor(t)] => return Ok(ValueType::Tensor(t.detach())),
+        [arg] => flatten(arg, &mut data)?,
+        rows => flatten_rows(rows, &mut data)?,
+    };
+    Ok(ValueType::Tensor(Tensor::from_vec(data, shape)))
+}
+
+/// The tensor of a number or a nested array of numbers, like `tensor(value)`
+pub fn tensor_of(value: &ValueType) -> Result<Tensor, String> {
+    let mut data = Vec::new();
+    let shape = flatten(value, &mut data)?;
+    Ok(Tensor::from_vec(data, shape))
+}
+
+/// Pushes the numbers of a (nested) array into `data` and returns its shape
+fn flatten(value: &ValueType, data: &mut Vec<f64>) -> Result<Vec<usize>, String> {
+    match value {
+        ValueType::Integer(n) => {
+            data.push(*n as f64);
+            Ok(Vec::new())
+        }
+        ValueType::Float(n) => {
+            data.push(*n);
+            Ok(Vec::new())
+        }
+        ValueType::Array(elements) => flatten_rows(&elements.borrow(), data),
+        value => Err(format!("tensor expects numbers, got {}", value.type_name())),
+    }
+}
+
+/// `flatten` for each row, the rows must all have the same shape
+fn flatten_rows(rows: &[ValueType], data: &mut Vec<f64>) -> Result<Vec<usize>, String> {
+    let mut inner = None;
+    for row in rows {
+        let shape = flatten(row, data)?;
+        match &inner {
+            Some(inner) if inner != &shape => {
+                return Err(format!(
+                    "tensor rows must have the same shape, got {:?} and {:?}",
+                    inner, shape
+                ))
+            }
+            _ => inner = Some(shape),
+        }
+    }
+
+    let mut shape = vec![rows.len()];
+    shape.extend(inner.unwrap_or_default());
+    Ok(shape)
+}
+
+/// `randn(2, 3)` is a 2x3 tensor sampled from the standard normal distribution
+fn randn(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    let shape = args
+        .iter()
+        .map(|arg| match arg {
+            ValueType::Integer(n) if *n >= 0 => Ok(*n as usize),
+            ValueType::Integer(n) => Err(format!("randn got a negative dimension {}", n)),
+            arg => Err(format!(
+                "randn expects integer dimensions, got {}",
+                arg.type_name()
+            )),
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+
+    let data = (0..shape.iter().product()).map(|_| normal()).collect();
+    Ok(ValueType::Tensor(Tensor::from_vec(data, shape)))
+}
+
+/// `onehot(2, 4)` is `tensor([0, 0, 1, 0])`, a leaf tensor like the targets of
+/// a classifier
+fn onehot(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    let (ValueType::Integer(index), ValueType::Integer(classes)) = (&args[0], &args[1]) else {
+        return Err(format!(
+            "onehot expects integers, got {} and {}",
+            args[0].type_name(),
+            args[1].type_name()
+        ));
+    };
+    if *index < 0 || index >= classes {
+        return Err(format!(
+            "onehot index {} is out of range for {} classes",
+            index, classes
+        ));
+    }
+
+    let mut data = vec![0.0; *classes as usize];
+    data[*index as usize] = 1.0;
+    Ok(ValueType::Tensor(Tensor::from_vec(
+        data,
+        vec![*classes as usize],
+    )))
+}
+
+/// `batches(data, 32)` splits the rows (the first dimension) of `data` into an
+/// array of tensors of 32 rows, the last one has the rows left. The batches are
+/// copies of the data, not connected to its graph
+fn batches(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    let ValueType::Tensor(data) = &args[0] else {
+        return Err(format!(
+            "batches expects a tensor, got {}",
+            args[0].type_name()
+        ));
+    };
+    let size = match &args[1] {
+        ValueType::Integer(n) if *n > 0 => *n as usize,
+        ValueType::Integer(n) => return Err(format!("batches got a batch size of {}", n)),
+        arg => {
+            return Err(format!(
+                "batches expects an integer batch size, got {}",
+                arg.type_name()
+            ))
+        }
+    };
+    let shape = data.shape();
+    let Some((&rows, row_shape)) = shape.split_first() else {
+        return Err("batches can't split a scalar".to_string());
+    };
+
+    let values = data.data();
+    let row_len = row_shape.iter().product::<usize>();
+    let batches = (0..rows)
+        .step_by(size)
+        .map(|start| {
+            let end = (start + size).min(rows);
+            let mut batch_shape = vec![end - start];
+            batch_shape.extend_from_slice(row_shape);
+            let batch = values[start * row_len..end * row_len].to_vec();
+            ValueType::Tensor(Tensor::from_vec(batch, batch_shape))
+        })
+        .collect();
+    Ok(ValueType::Array(Rc::new(RefCell::new(batches))))
+}
+
+/// `log_softmax(logits)` is `logits - logsumexp(logits)` along the last dimension,
+/// see `Tensor::log_softmax`
+fn log_softmax(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    match args[0].to_tensor() {
+        Some(logits) => Ok(ValueType::Tensor(logits.log_softmax())),
+        None => Err(format!(
+            "log_softmax expects a tensor, got {}",
+            args[0].type_name()
+        )),
+    }
+}
+
+/// `shuffle(data)` is a copy of `data` with its rows in a random order, without
+/// its graph. Reproducible with `seed`
+fn shuffle(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    let ValueType::Tensor(data) = &args[0] else {
+        return Err(format!(
+            "shuffle expects a tensor, got {}",
+            args[0].type_name()
+        ));
+    };
+    let shape = data.shape();
+    let Some((&rows, row_shape)) = shape.split_first() else {
+        return Err("shuffle can't shuffle a scalar".to_string());
+    };
+
+    // Fisher-Yates over the rows
+    let mut order: Vec<usize> = (0..rows).collect();
+    for i in (1..rows).rev() {
+        let j = (next_u64() % (i as u64 + 1)) as usize;
+        order.swap(i, j);
+    }
+
+    let values = data.data();
+    let row_len = row_shape.iter().product::<usize>();
+    let shuffled = order
+        .iter()
+        .flat_map(|row| values[row * row_len..(row + 1) * row_len].iter().copied())
+        .collect();
+    Ok(ValueType::Tensor(Tensor::from_vec(shuffled, shape)))
+}
+
+/// Restarts the random number generator of this thread from `seed`, so `randn`
+/// and `shuffle` give the same values on every run
+pub fn seed(seed: u64) {
+    RNG.with(|state| state.set(seed));
+}
+
+// splitmix64, good enough to initialize weights
+fn next_u64() -> u64 {
+    RNG.with(|state| {
+        let next = state.get().wrapping_add(0x9E3779B97F4A7C15);
+        state.set(next);
+
+        let mut z = next;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    })
+}
+
+/// Uniform in [0, 1)
+fn uniform() -> f64 {
+    (next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Standard normal sample, using the Box-Muller transform
+fn normal() -> f64 {
+    let (u1, u2) = (1.0 - uniform(), uniform());
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// `graph_size()` is the number of autograd nodes alive, a value that keeps growing
+/// across iterations of a training loop means the graph isn't being freed
+fn graph_size(_: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    Ok(ValueType::Integer(Tensor::graph_size() as i64))
+}
+
+/// `free_graph(w)` detaches `w` from the graph that computed it, see `Tensor::free_graph`
+fn free_graph(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    match &args[0] {
+        ValueType::Tensor(t) => {
+            t.free_graph();
+            Ok(ValueType::Nil)
+        }
+        arg => Err(format!(
+            "free_graph expects a tensor, got {}",
+            arg.type_name()
+        )),
+    }
+}
+
+/// `export_graph(loss, "graph.dot")` writes the graph that computed `loss` to a
+/// file in Graphviz DOT format, see `Tensor::to_dot`
+fn export_graph(args: &[ValueType], interner: &mut Interner) -> Result<ValueType, String> {
+    let (ValueType::Tensor(tensor), ValueType::String(path)) = (&args[0], &args[1]) else {
+        return Err(format!(
+            "export_graph expects a tensor and a path, got {} and {}",
+            args[0].type_name(),
+            args[1].type_name()
+        ));
+    };
+    let path = interner.lookup(*path).to_string();
+    std::fs::write(&path, tensor.to_dot())
+        .map_err(|e| format!("export_graph can't write '{}': {}", path, e))?;
+    Ok(ValueType::Nil)
+}
+
+/// `all(t > 0)` is true when every element of the mask is nonzero
+fn all(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    match args[0].to_tensor() {
+        Some(t) => Ok(ValueType::Boolean(t.all())),
+        None => Err(format!("all expects a tensor, got {}", args[0].type_name())),
+    }
+}
+
+/// `any(t > 0)` is true when some element of the mask is nonzero
+fn any(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    match args[0].to_tensor() {
+        Some(t) => Ok(ValueType::Boolean(t.any())),
+        None => Err(format!("any expects a tensor, got {}", args[0].type_name())),
+    }
+}
+
+/// `approx_eq(a, b, eps)` is whether `|a - b| <= eps`, for floats that are equal
+/// up to rounding. With a tensor it is the mask of the elements that are
+fn approx_eq(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    let eps = match &args[2] {
+        ValueType::Integer(n) => *n as f64,
+        ValueType::Float(n) => *n,
+        arg => {
+            return Err(format!(
+                "approx_eq expects a number for eps, got {}",
+                arg.type_name()
+            ))
+        }
+    };
+    let (Some(a), Some(b)) = (args[0].to_tensor(), args[1].to_tensor()) else {
+        return Err(format!(
+            "approx_eq expects numbers or tensors, got {} and {}",
+            args[0].type_name(),
+            args[1].type_name()
+        ));
+    };
+
+    let mask = a.approx_eq(&b, eps)?;
+    match (&args[0], &args[1]) {
+        (ValueType::Tensor(_), _) | (_, ValueType::Tensor(_)) => Ok(ValueType::Tensor(mask)),
+        _ => Ok(ValueType::Boolean(mask.all())),
+    }
+}
+
+/// `set_print_options(" ", "; ")` displays the tensors printed after it like
+/// `[[1 2]; [3 4]]`, `set_print_options(", ", ", ")` goes back to the default
+fn set_print_options(args: &[ValueType], interner: &mut Interner) -> Result<ValueType, String> {
+    let separator = |arg: &ValueType| match arg {
+        ValueType::String(idx) => Ok(interner.lookup(*idx).to_string()),
+        arg => Err(format!(
+            "set_print_options expects string separators, got {}",
+            arg.type_name()
+        )),
+    };
+
+    tensor::set_print_options(PrintOptions {
+        element_separator: separator(&args[0])?,
+        row_separator: separator(&args[1])?,
+    });
+    Ok(ValueType::Nil)
+}
+
+/// `set_grad(t, g)` overwrites the gradient of `t` with `g`, which has the shape
+/// of `t` (a number is a scalar gradient)
+fn set_grad(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    let ValueType::Tensor(tensor) = &args[0] else {
+        return Err(format!(
+            "set_grad expects a tensor, got {}",
+            args[0].type_name()
+        ));
+    };
+    let Some(gradient) = args[1].to_tensor() else {
+        return Err(format!(
+            "set_grad expects a tensor gradient, got {}",
+            args[1].type_name()
+        ));
+    };
+    tensor.set_gradient(&gradient)?;
+    Ok(ValueType::Nil)
+}
+
+/// The value of a number argument of the scalar native `name`
+fn number(name: &str, arg: &ValueType) -> Result<f64, String> {
+    match arg {
+        ValueType::Integer(n) => Ok(*n as f64),
+        ValueType::Float(n) => Ok(*n),
+        arg => Err(format!("{} expects numbers, got {}", name, arg.type_name())),
+    }
+}
+
+/// `min(2, 1.5)` is the smaller number as it was passed, an integer stays one
+fn min(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    match number("min", &args[1])? < number("min", &args[0])? {
+        true => Ok(args[1].clone()),
+        false => Ok(args[0].clone()),
+    }
+}
+
+/// `max(2, 1.5)` is the larger number as it was passed
+fn max(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    match number("max", &args[1])? > number("max", &args[0])? {
+        true => Ok(args[1].clone()),
+        false => Ok(args[0].clone()),
+    }
+}
+
+/// `clamp(x, lo, hi)` is `x` moved into the range `[lo, hi]`
+fn clamp(args: &[ValueType], _: &mut Interner) -> Result<ValueType, String> {
+    let x = number("clamp", &args[0])?;
+    let (lo, hi) = (number("clamp", &args[1])?, number("clamp", &args[2])?);
+    if lo > hi {
+        return Err(format!("clamp got an empty range, {} > {}", lo, hi));
+    }
+
+    if x < lo {
+        Ok(args[1].clone())
+    } else if x > hi {
+        Ok(args[2].clone())
+    } else {
+        Ok(args[0].clone())
+    }
+}
+
+/// `str(a, b)` is the values separated by spaces as a string, each shown like
+/// `format` and `${...}` show it, so `str("a", 1)` is `"a 1"`
+fn str(args: &[ValueType], interner: &mut Interner) -> Result<ValueType, String> {
+    let texts: Vec<String> = args.iter().map(|arg| arg.to_text(interner)).collect();
+    Ok(ValueType::String(interner.intern_string(texts.join(" "))))
+}
+
+/// `format("{} + {} = {}", a, b, a + b)` replaces each `{}` of the template with
+/// the next argument, shown like `${...}` shows it in a string
+fn format(args: &[ValueType], interner: &mut Interner) -> Result<ValueType, String> {
+    let Some((ValueType::String(template), values)) = args.split_first() else {
+        return Err(format!(
+            "format expects a string template, got {}",
+            args.first().map_or("nothing", |arg| arg.type_name())
+        ));
+    };
+
+    let pieces: Vec<&str> = interner.lookup(*template).split("{}").collect();
+    if pieces.len() - 1 != values.len() {
+        return Err(format!(
+            "format got {} placeholders but {} arguments",
+            pieces.len() - 1,
+            values.len()
+        ));
+    }
+
+    let mut text = pieces[0].to_string();
+    for (value, piece) in values.iter().zip(&pieces[1..]) {
+        text.push_str(&value.to_text(interner));
+        text.push_str(piece);
+    }
+    Ok(ValueType::String(interner.intern_string(text)))
+}
+
+/// The text of a string argument of the native `name`
+fn text(name: &str, arg: &ValueType, interner: &Interner) -> Result<String, String> {
+    match arg {
+        ValueType::String(idx) => Ok(interner.lookup(*idx).to_string()),
+        arg => Err(format!(
+            "{} expects a string, got {}",
+            name,
+            arg.type_name()
+        )),
+    }
+}
+
+/// `len("héllo")` is the number of characters of the string, not of bytes
+fn len(args: &[ValueType], interner: &mut Interner) -> Result<ValueType, String> {
+    let count = text("len", &args[0], interner)?.chars().count();
+    Ok(ValueType::Integer(count as i64))
+}
+
+/// `char_at("héllo", 1)` is the character at the index as a string, "é"
+fn char_at(args: &[ValueType], interner: &mut Interner) -> Result<ValueType, String> {
+    let text = text("char_at", &args[0], interner)?;
+    let ValueType::Integer(index) = args[1] else {
+        return Err(format!(
+            "char_at expects an integer index, got {}",
+            args[1].type_name()
+        ));
+    };
+
+    let count = text.chars().count();
+    let Some(c) = usize::try_from(index)
+        .ok()
+        .and_then(|index| text.chars().nth(index))
+    else {
+        return Err(format!(
+            "char_at index {} is out of range for a string of {} characters",
+            index, count
+        ));
+    };
+    Ok(ValueType::String(interner.intern_string(c.to_string())))
+}
+
+/// The values separated by spaces, how `print` shows its arguments
+pub fn display_all(values: &[ValueType], interner: &Interner) -> String {
+    values
+        .iter()
+        .map(|value| value.display(interner))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,12 +1,117 @@
 use logos::Logos;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Clone, Default)]
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum LexingError {
     NumberParseError,
+    /// a `"""` string missing its closing `"""`
+    UnterminatedString,
+    /// a `\` in a string followed by a character that isn't an escape
+    InvalidEscape(char),
     #[default]
     Other,
 }
 
+/// Reads a `"""` string up to its closing `"""`, newlines included
+fn triple_quoted(lex: &mut logos::Lexer<TokenType>) -> Result<(), LexingError> {
+    match lex.remainder().find(r#"""""#) {
+        Some(end) => {
+            lex.bump(end + 3);
+            Ok(())
+        }
+        None => {
+            lex.bump(lex.remainder().len());
+            Err(LexingError::UnterminatedString)
+        }
+    }
+}
+
+/// Reads a `"` string up to its closing `"`, past the strings inside its `${...}`s
+fn quoted(lex: &mut logos::Lexer<TokenType>) -> Result<(), LexingError> {
+    match string_length(lex.remainder()) {
+        Some(length) => {
+            lex.bump(length + 1);
+            Ok(())
+        }
+        None => {
+            lex.bump(lex.remainder().len());
+            Err(LexingError::UnterminatedString)
+        }
+    }
+}
+
+/// Length of the content of a `"` string, `text` starting after its opening
+/// quote. A `${` that isn't closed is part of the text, the parser reports it
+fn string_length(text: &str) -> Option<usize> {
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        i += c.len_utf8();
+        match c {
+            '"' => return Some(i - 1),
+            '\\' => i += text[i..].chars().next().map_or(0, char::len_utf8),
+            '$' if text[i..].starts_with('{') => {
+                i += interpolation_length(&text[i + 1..]).map_or(0, |length| length + 2)
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Length of the expression of a `${...}`, `text` starting after the `${`. Its
+/// braces nest, and a `}` inside one of its strings doesn't close it
+pub(crate) fn interpolation_length(text: &str) -> Option<usize> {
+    let (mut i, mut depth) = (0, 0);
+    while let Some(c) = text[i..].chars().next() {
+        match c {
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            '{' => depth += 1,
+            '"' => i += string_length(&text[i + 1..])? + 1,
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    None
+}
+
+/// Decodes the escapes of a `"` string, quotes included. `\$` is kept for the
+/// parser, to tell a literal `${` from an interpolation, and the expressions of
+/// the `${...}`s are kept verbatim, the parser scans them with their own strings
+fn unescape(lexeme: &str) -> Result<String, LexingError> {
+    let mut text = String::with_capacity(lexeme.len());
+    let mut i = 0;
+    while let Some(c) = lexeme[i..].chars().next() {
+        i += c.len_utf8();
+        if c == '$' && lexeme[i..].starts_with('{') {
+            let length = interpolation_length(&lexeme[i + 1..]).map_or(0, |length| length + 2);
+            text.push('$');
+            text.push_str(&lexeme[i..i + length]);
+            i += length;
+            continue;
+        }
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        let escape = lexeme[i..].chars().next();
+        i += escape.map_or(0, char::len_utf8);
+        match escape {
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some('r') => text.push('\r'),
+            Some('b') => text.push('\u{8}'),
+            Some('f') => text.push('\u{c}'),
+            Some('\\') => text.push('\\'),
+            Some('"') => text.push('"'),
+            Some('$') => text.push_str("\\$"),
+            Some(c) => return Err(LexingError::InvalidEscape(c)),
+            None => return Err(LexingError::Other),
+        }
+    }
+    Ok(text)
+}
+
 #[derive(Logos, Debug, PartialEq, Clone, Copy)]
 #[logos(skip r"[ \t\r\n\f]+")]
 #[logos(error = LexingError)]
@@ -36,6 +141,9 @@ pub enum TokenType {
     #[token(".")]
     DOT,
 
+    #[token("...")]
+    ELLIPSIS, // spread
+
     #[token("-")]
     MINUS,
 
@@ -51,6 +159,9 @@ pub enum TokenType {
     #[token("/")]
     SLASH,
 
+    #[token("%")]
+    PERCENT, // remainder
+
     #[token("*")]
     STAR,
 
@@ -109,7 +220,8 @@ pub enum TokenType {
     #[regex(r"-?(?:0|[1-9]\d*)\.\d+", |lex| lex.slice().parse::<f64>().unwrap())]
     FloatNumber(f64),
 
-    #[regex(r#""([^"\\]|\\["\\bnfrt]|u[a-fA-F0-9]{4})*""#)]
+    #[token("\"", quoted)]
+    #[token(r#"""""#, triple_quoted)]
     String,
 
     // boolean ion single Boolean(bool)
@@ -120,6 +232,9 @@ pub enum TokenType {
     #[token("and")]
     AND,
 
+    #[token("as")]
+    AS,
+
     #[token("else")]
     ELSE,
 
@@ -132,6 +247,9 @@ pub enum TokenType {
     #[token("if")]
     IF,
 
+    #[token("import")]
+    IMPORT,
+
     #[token("nil")]
     NIL,
 
@@ -144,18 +262,55 @@ pub enum TokenType {
     #[token("return")]
     RETURN,
 
+    #[token("defer")]
+    DEFER,
+
+    #[token("try")]
+    TRY,
+
+    #[token("catch")]
+    CATCH,
+
+    #[token("finally")]
+    FINALLY,
+
+    #[token("throw")]
+    THROW,
+
     #[token("let")]
     LET,
 
     #[token("while")]
     WHILE,
 
+    #[token("unless")]
+    UNLESS,
+
+    #[token("until")]
+    UNTIL,
+
+    #[token("break")]
+    BREAK,
+
+    #[token("continue")]
+    CONTINUE,
+
     // NOTE: Common Regex - https://github.com/maciejhirsz/logos/issues/133
     #[regex(r#"//[^\n]*"#, logos::skip)]
     COMMENT,
 
     #[end]
     EOF, // end of file
+
+    // source that isn't a token, the lexeme is the message. Nothing after it is scanned
+    Error(LexingError),
+}
+
+/// 1-based line and column of a token in the source
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -164,10 +319,15 @@ pub struct Token {
     pub lexeme: String,
     // pub literal: Option<ValueType>,
     pub span: std::ops::Range<usize>,
+    pub position: Position,
 }
 
 pub struct Lexer {
     pub tokens: Vec<Token>,
+    // where the source ends, the position of the EOF token
+    end: Position,
+    // position of the token `next` returned last
+    pub previous: Position,
 }
 
 impl Lexer {
@@ -175,49 +335,94 @@ impl Lexer {
         let mut lexer = TokenType::lexer(&source);
         let mut tokens = Vec::new();
 
+        let (mut line, mut line_start, mut scanned) = (1, 0, 0);
+        let mut position_of = |offset: usize| {
+            for (i, c) in source[scanned..offset].char_indices() {
+                if c == '\n' {
+                    line += 1;
+                    line_start = scanned + i + 1;
+                }
+            }
+            scanned = offset;
+
+            Position {
+                line,
+                column: source[line_start..offset].chars().count() + 1,
+            }
+        };
+
         loop {
-            let token = match lexer.next() {
-                Some(Ok(token)) => token,
-                Some(Err(e)) => {
-                    println!("Error: {:?}", e);
-                    break;
+            let scanned = match lexer.next() {
+                // a triple-quoted string is kept like a `"` string, with its content verbatim
+                Some(Ok(TokenType::String)) if lexer.slice().starts_with(r#"""""#) => {
+                    let slice = lexer.slice();
+                    Ok((TokenType::String, format!("\"{}\"", &slice[3..slice.len() - 3])))
                 }
+                Some(Ok(TokenType::String)) => unescape(lexer.slice()).map(|text| (TokenType::String, text)),
+                Some(Ok(token)) => Ok((token, lexer.slice().to_string())),
+                Some(Err(e)) => Err(e),
                 None => break,
             };
+            let (token, lexeme) = match scanned {
+                Ok(scanned) => scanned,
+                Err(e) => {
+                    let message = match e {
+                        LexingError::UnterminatedString => "Unterminated string".to_string(),
+                        LexingError::InvalidEscape(c) => format!("Invalid escape '\\{}' in string", c),
+                        LexingError::NumberParseError => format!("Invalid number '{}'", lexer.slice()),
+                        LexingError::Other => format!("Unexpected character '{}'", lexer.slice()),
+                    };
+                    (TokenType::Error(e), message)
+                }
+            };
+            let is_error = matches!(token, TokenType::Error(_));
 
             tokens.push(Token {
                 token_type: token,
-                lexeme: lexer.slice().to_string(),
+                lexeme,
                 // literal: value,
                 span: lexer.span(),
+                position: position_of(lexer.span().start),
             });
+            if is_error {
+                break;
+            }
         }
 
         tokens.reverse();
+        let end = position_of(source.len());
 
-        Lexer { tokens }
+        Lexer {
+            tokens,
+            end,
+            previous: Position::default(),
+        }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Token {
-        self.tokens.pop().unwrap_or(Token {
+        let token = self.tokens.pop().unwrap_or_else(|| self.eof());
+        self.previous = token.position;
+        token
+    }
+
+    /// Whether the next token starts on a later line than the one `next` returned last
+    pub fn next_on_new_line(&self) -> bool {
+        self.peek().position.line > self.previous.line
+    }
+
+    pub fn peek(&self) -> Token {
+        self.tokens.last().cloned().unwrap_or_else(|| self.eof())
+    }
+
+    fn eof(&self) -> Token {
+        Token {
             token_type: TokenType::EOF,
             lexeme: String::new(),
             // literal: None,
             span: 0..0,
-        })
-    }
-
-    pub fn peek(&self) -> Token {
-        self.tokens
-            .last()
-            .clone()
-            .unwrap_or(&Token {
-                token_type: TokenType::EOF,
-                lexeme: String::new(),
-                // literal: None,
-                span: 0..0,
-            })
-            .clone()
+            position: self.end,
+        }
     }
 
     pub fn peek_n_type(&self, n: usize) -> Vec<TokenType> {
@@ -5,23 +5,78 @@
 
 use std::{
     cell::{Ref, RefCell},
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     rc::Rc,
 };
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+thread_local! {
+    // number of tensors alive, every operation adds a node to the graph
+    static LIVE_TENSORS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    // how tensors are displayed on this thread
+    static PRINT_OPTIONS: RefCell<PrintOptions> = RefCell::new(PrintOptions::default());
+}
+
+/// Separators of the displayed tensors, `[[1, 2], [3, 4]]` by default
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintOptions {
+    /// between the numbers of the innermost rows
+    pub element_separator: String,
+    /// between the rows, like `[1, 2]` and `[3, 4]`
+    pub row_separator: String,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        PrintOptions {
+            element_separator: ", ".to_string(),
+            row_separator: ", ".to_string(),
+        }
+    }
+}
+
+/// Changes how the tensors of this thread are displayed from now on
+pub fn set_print_options(options: PrintOptions) {
+    PRINT_OPTIONS.with(|current| *current.borrow_mut() = options);
+}
+
 #[derive(Clone, Eq, PartialEq)]
 pub struct Tensor(Rc<RefCell<TensorInternal>>);
 
 impl std::fmt::Display for Tensor {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.borrow().data)
+        let tensor = self.borrow();
+        let text = PRINT_OPTIONS
+            .with(|options| format_nested(&tensor.data, &tensor.shape, &options.borrow()));
+        write!(f, "{}", text)
     }
 }
 
 // debug print
 impl std::fmt::Debug for Tensor {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.borrow().data)
+        write!(f, "{}", self)
+    }
+}
+
+// NOTE: only the shape and data are serialized, a deserialized tensor is a fresh leaf without any graph
+impl Serialize for Tensor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tensor = self.borrow();
+        (&tensor.shape, &tensor.data).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tensor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (shape, data) = <(Vec<usize>, Vec<f64>)>::deserialize(deserializer)?;
+        if data.len() != shape.iter().product::<usize>() {
+            return Err(serde::de::Error::custom(
+                "tensor data doesn't match its shape",
+            ));
+        }
+        Ok(Tensor::from_vec(data, shape))
     }
 }
 
@@ -34,167 +89,585 @@ impl Tensor {
     }
 
     pub fn new(tensor: TensorInternal) -> Self {
+        LIVE_TENSORS.with(|count| count.set(count.get() + 1));
         Tensor(Rc::new(RefCell::new(tensor)))
     }
 
+    /// A leaf tensor with `data` laid out row-major in `shape`, `[]` is a scalar
+    pub fn from_vec(data: Vec<f64>, shape: Vec<usize>) -> Tensor {
+        assert_eq!(data.len(), shape.iter().product::<usize>());
+        Tensor::new(TensorInternal::new(
+            data,
+            shape,
+            None,
+            None,
+            Vec::new(),
+            None,
+        ))
+    }
+
+    pub fn data(&self) -> Vec<f64> {
+        self.borrow().data.clone()
+    }
+
+    pub fn shape(&self) -> Vec<usize> {
+        self.borrow().shape.clone()
+    }
+
+    /// A leaf with the same data, not connected to the graph of `self`
+    pub fn detach(&self) -> Tensor {
+        Tensor::from_vec(self.data(), self.shape())
+    }
+
+    /// Turns the tensor into a leaf, keeping its data and gradient
+    ///
+    /// Every operation on a tensor adds a node that holds on to its inputs, so a
+    /// parameter updated in a loop (`w = w - lr * w.grad()`) keeps the graph of
+    /// every previous iteration alive. Once `backward` has run and the update is
+    /// done, freeing the graph of the parameter lets the intermediate nodes be
+    /// dropped, the leaves they were computed from are untouched
+    pub fn free_graph(&self) {
+        let mut tensor = self.borrow_mut();
+        tensor.previous.clear();
+        tensor.propagate = None;
+        tensor.operation = None;
+    }
+
+    /// Number of graph nodes alive on this thread, leaves included
+    pub fn graph_size() -> usize {
+        LIVE_TENSORS.with(|count| count.get())
+    }
+
     pub fn adjust(&self, factor: f64) {
         let mut value = self.borrow_mut();
-        value.data += factor * value.gradient;
+        let value = &mut *value;
+        for (data, gradient) in value.data.iter_mut().zip(&value.gradient) {
+            *data += factor * gradient;
+        }
+    }
+
+    pub fn add(&self, other: &Tensor) -> Result<Tensor, String> {
+        let prop_fn: PropagateFn = |value| propagate_binary(value, |_, _, _| (1.0, 1.0));
+        elementwise(self, other, "+", |a, b| a + b, prop_fn)
     }
 
-    pub fn pow(&self, other: &Tensor) -> Tensor {
-        let result = self.borrow().data.powf(other.borrow().data);
+    pub fn sub(&self, other: &Tensor) -> Result<Tensor, String> {
+        self.add(&-other.clone())
+    }
+
+    pub fn mul(&self, other: &Tensor) -> Result<Tensor, String> {
+        let prop_fn: PropagateFn = |value| propagate_binary(value, |a, b, _| (b, a));
+        elementwise(self, other, "*", |a, b| a * b, prop_fn)
+    }
 
+    // TODO: does this work?
+    pub fn div(&self, other: &Tensor) -> Result<Tensor, String> {
+        self.mul(&other.pow(&Tensor::from(-1))?)
+    }
+
+    pub fn pow(&self, other: &Tensor) -> Result<Tensor, String> {
+        // NOTE: the gradient only flows to the base, exponents are constants
         let prop_fn: PropagateFn = |value| {
-            let mut base = value.previous[0].borrow_mut();
-            let power = value.previous[1].borrow();
-            base.gradient += power.data * (base.data.powf(power.data - 1.0)) * value.gradient;
+            propagate_binary(value, |base, power, _| {
+                (power * base.powf(power - 1.0), 0.0)
+            })
         };
+        elementwise(self, other, "^", f64::powf, prop_fn)
+    }
 
-        Tensor::new(TensorInternal::new(
-            result,
+    /// Matrix product of an `n x k` and a `k x m` matrix
+    pub fn matmul(&self, other: &Tensor) -> Result<Tensor, String> {
+        let (first, second) = (self.borrow(), other.borrow());
+        let (n, k, m) = match (first.shape.as_slice(), second.shape.as_slice()) {
+            ([n, k], [k2, m]) if k == k2 => (*n, *k, *m),
+            _ => {
+                return Err(format!(
+                    "Can't multiply matrices of shape {:?} and {:?}",
+                    first.shape, second.shape
+                ))
+            }
+        };
+        let data = matmul(&first.data, &second.data, n, k, m, false, false);
+
+        // dA = G @ B^T and dB = A^T @ G, for the gradient G of the output
+        let prop_fn: PropagateFn = |value| {
+            let (first_gradient, second_gradient) = {
+                let first = value.previous[0].borrow();
+                let second = value.previous[1].borrow();
+                let (n, k, m) = (first.shape[0], first.shape[1], second.shape[1]);
+                (
+                    matmul(&value.gradient, &second.data, n, m, k, false, true),
+                    matmul(&first.data, &value.gradient, k, n, m, true, false),
+                )
+            };
+            value.previous[0].borrow_mut().accumulate(&first_gradient);
+            value.previous[1].borrow_mut().accumulate(&second_gradient);
+        };
+
+        Ok(Tensor::new(TensorInternal::new(
+            data,
+            vec![n, m],
             None,
-            Some("^".to_string()),
+            Some("@".to_string()),
             vec![self.clone(), other.clone()],
             Some(prop_fn),
-        ))
+        )))
     }
 
     pub fn tanh(&self) -> Tensor {
-        let result = self.borrow().data.tanh();
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |_, out| 1.0 - out.powf(2.0));
+        unary(self, "tanh", f64::tanh, prop_fn)
+    }
 
-        let prop_fn: PropagateFn = |value| {
-            let mut previous = value.previous[0].borrow_mut();
-            previous.gradient += (1.0 - value.data.powf(2.0)) * value.gradient;
-        };
+    pub fn sigmoid(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |_, out| out * (1.0 - out));
+        unary(self, "sigmoid", |x| 1.0 / (1.0 + (-x).exp()), prop_fn)
+    }
 
-        Tensor::new(TensorInternal::new(
-            result,
-            None,
-            Some("tanh".to_string()),
-            vec![self.clone()],
-            Some(prop_fn),
-        ))
+    /// The sum of the elements, as a scalar
+    pub fn sum(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_reduction(value, |_| 1.0);
+        reduction(self, "sum", |_| 1.0, prop_fn)
+    }
+
+    /// The mean of the elements, as a scalar
+    pub fn mean(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_reduction(value, |n| 1.0 / n as f64);
+        reduction(self, "mean", |n| 1.0 / n as f64, prop_fn)
     }
 
     pub fn relu(&self) -> Tensor {
-        let result = self.borrow().data.max(0.0);
+        let prop_fn: PropagateFn =
+            |value| propagate_unary(value, |_, out| (out > 0.0) as i32 as f64);
+        unary(self, "relu", |x| x.max(0.0), prop_fn)
+    }
+
+    /// `x - logsumexp(x)` along the last dimension, each row of a matrix is
+    /// normalized on its own. Subtracting the largest element before `exp` keeps
+    /// large logits from overflowing, unlike taking the log of a softmax
+    pub fn log_softmax(&self) -> Tensor {
+        let tensor = self.borrow();
+        let row_len = tensor.shape.last().copied().unwrap_or(1).max(1);
+        let mut data = Vec::with_capacity(tensor.data.len());
+        for row in tensor.data.chunks(row_len) {
+            let max = row.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let log_total = row.iter().map(|x| (x - max).exp()).sum::<f64>().ln();
+            data.extend(row.iter().map(|x| x - max - log_total));
+        }
 
+        // dy_i/dx_j = [i == j] - softmax_j, and softmax is `exp` of the output
         let prop_fn: PropagateFn = |value| {
-            let mut previous = value.previous[0].borrow_mut();
-            previous.gradient += (value.data > 0.0) as i32 as f64 * value.gradient;
+            let row_len = value.shape.last().copied().unwrap_or(1).max(1);
+            let gradient: Vec<f64> = value
+                .data
+                .chunks(row_len)
+                .zip(value.gradient.chunks(row_len))
+                .flat_map(|(out, gradient)| {
+                    let total: f64 = gradient.iter().sum();
+                    out.iter()
+                        .zip(gradient)
+                        .map(move |(y, g)| g - y.exp() * total)
+                })
+                .collect();
+            value.previous[0].borrow_mut().accumulate(&gradient);
         };
 
         Tensor::new(TensorInternal::new(
-            result,
+            data,
+            tensor.shape.clone(),
             None,
-            Some("relu".to_string()),
+            Some("log_softmax".to_string()),
             vec![self.clone()],
             Some(prop_fn),
         ))
     }
 
-    pub fn gradient(&self) -> f64 {
-        self.borrow().gradient
+    /// Mask of the elements greater than the (broadcast) elements of `other`,
+    /// 1 where the comparison holds and 0 elsewhere. Masks aren't differentiable,
+    /// they are leaves
+    pub fn greater(&self, other: &Tensor) -> Result<Tensor, String> {
+        compare(self, other, |a, b| a > b)
+    }
+
+    /// Mask of the elements less than the elements of `other`, see `greater`
+    pub fn less(&self, other: &Tensor) -> Result<Tensor, String> {
+        compare(self, other, |a, b| a < b)
+    }
+
+    /// Mask of the elements within `eps` of the elements of `other`, see `greater`
+    pub fn approx_eq(&self, other: &Tensor, eps: f64) -> Result<Tensor, String> {
+        compare(self, other, |a, b| (a - b).abs() <= eps)
+    }
+
+    /// Mask of the zero elements, `!mask` flips a mask
+    pub fn not(&self) -> Tensor {
+        let tensor = self.borrow();
+        let data = tensor
+            .data
+            .iter()
+            .map(|x| (*x == 0.0) as i32 as f64)
+            .collect();
+        Tensor::from_vec(data, tensor.shape.clone())
+    }
+
+    /// Whether every element is nonzero, true for an empty tensor
+    pub fn all(&self) -> bool {
+        self.borrow().data.iter().all(|x| *x != 0.0)
+    }
+
+    /// Whether any element is nonzero, false for an empty tensor
+    pub fn any(&self) -> bool {
+        self.borrow().data.iter().any(|x| *x != 0.0)
+    }
+
+    /// The gradient as a tensor of the same shape, not connected to any graph
+    pub fn gradient(&self) -> Tensor {
+        let tensor = self.borrow();
+        Tensor::from_vec(tensor.gradient.clone(), tensor.shape.clone())
+    }
+
+    /// Whether a `backward` reached the tensor since its gradient was last cleared,
+    /// a zero gradient of a tensor that isn't in the graph of the loss is unset
+    pub fn has_gradient(&self) -> bool {
+        self.borrow().gradient_set
+    }
+
+    /// Replaces the gradient with `gradient`, which must have the same shape.
+    /// The gradient counts as set, and a later `backward` accumulates into it
+    pub fn set_gradient(&self, gradient: &Tensor) -> Result<(), String> {
+        let (shape, gradient_shape) = (self.shape(), gradient.shape());
+        if shape != gradient_shape {
+            return Err(format!(
+                "set_grad got a gradient of shape {:?} for a tensor of shape {:?}",
+                gradient_shape, shape
+            ));
+        }
+
+        let data = gradient.data();
+        let mut tensor = self.borrow_mut();
+        tensor.gradient = data;
+        tensor.gradient_set = true;
+        Ok(())
     }
 
     pub fn clear_gradient(&self) {
-        self.borrow_mut().gradient = 0.0;
+        let mut tensor = self.borrow_mut();
+        tensor.gradient.fill(0.0);
+        tensor.gradient_set = false;
+    }
+
+    /// Propagates the gradient of a scalar through the graph that computed it,
+    /// the gradients of the nodes in the graph are accumulated into
+    pub fn backward(&self) -> Result<(), String> {
+        let shape = self.shape();
+        if !shape.is_empty() {
+            return Err(format!(
+                "backward needs a scalar, got a tensor of shape {:?}",
+                shape
+            ));
+        }
+
+        self.backward_seeded(vec![1.0]);
+        Ok(())
     }
 
-    pub fn backward(&self) {
-        let mut visited: HashSet<Tensor> = HashSet::new();
+    /// Like `backward` for a tensor of any shape, `seed` is the gradient of the
+    /// final result with respect to each element and must have the same shape
+    pub fn backward_with(&self, seed: &Tensor) -> Result<(), String> {
+        let (shape, seed_shape) = (self.shape(), seed.shape());
+        if shape != seed_shape {
+            return Err(format!(
+                "backward got a gradient of shape {:?} for a tensor of shape {:?}",
+                seed_shape, shape
+            ));
+        }
 
-        self.borrow_mut().gradient = 1.0;
-        self.backward_internal(&mut visited, self);
+        self.backward_seeded(seed.data());
+        Ok(())
     }
 
-    fn backward_internal(&self, visited: &mut HashSet<Tensor>, tensor: &Tensor) {
-        if !visited.contains(&tensor) {
-            visited.insert(tensor.clone());
+    fn backward_seeded(&self, seed: Vec<f64>) {
+        let mut root = self.borrow_mut();
+        root.gradient = seed;
+        root.gradient_set = true;
+        drop(root);
 
+        // every node propagates after all the nodes using it have propagated into it
+        for tensor in self.topological_order().iter().rev() {
             let borrowed_value = tensor.borrow();
             if let Some(prop_fn) = borrowed_value.propagate {
                 prop_fn(&borrowed_value);
             }
+        }
+    }
+
+    /// The graph that computed the tensor in Graphviz DOT format, one node per
+    /// tensor labeled with its operation (or `leaf`) and shape, and an edge from
+    /// every input to the node computed from it
+    pub fn to_dot(&self) -> String {
+        let order = self.topological_order();
+        let ids: HashMap<*const RefCell<TensorInternal>, usize> = order
+            .iter()
+            .enumerate()
+            .map(|(id, tensor)| (Rc::as_ptr(tensor), id))
+            .collect();
 
-            for child_id in &tensor.borrow().previous {
-                self.backward_internal(visited, child_id);
+        let mut dot = String::from("digraph {\n");
+        for (id, tensor) in order.iter().enumerate() {
+            let tensor = tensor.borrow();
+            let operation = tensor.operation.as_deref().unwrap_or("leaf");
+            dot += &format!(
+                "  node{} [label=\"{} {:?}\"];\n",
+                id, operation, tensor.shape
+            );
+        }
+        for (id, tensor) in order.iter().enumerate() {
+            for input in &tensor.borrow().previous {
+                dot += &format!("  node{} -> node{};\n", ids[&Rc::as_ptr(input)], id);
             }
         }
+        dot.push('}');
+        dot.push('\n');
+        dot
     }
-}
 
-fn add(a: &Tensor, b: &Tensor) -> Tensor {
-    let result = a.borrow().data + b.borrow().data;
+    /// Nodes of the graph, every node comes after the nodes it was computed from
+    fn topological_order(&self) -> Vec<Tensor> {
+        // NOTE: nodes are keyed by address, their contents (and hash) change while propagating
+        let mut visited: HashSet<*const RefCell<TensorInternal>> = HashSet::new();
+        let mut order = Vec::new();
 
-    let prop_fn: PropagateFn = |value| {
-        let mut first = value.previous[0].borrow_mut();
-        let mut second = value.previous[1].borrow_mut();
+        // `true` once the children of the node have been pushed
+        let mut stack = vec![(self.clone(), false)];
+        while let Some((tensor, expanded)) = stack.pop() {
+            if expanded {
+                order.push(tensor);
+                continue;
+            }
+            if !visited.insert(Rc::as_ptr(&tensor)) {
+                continue;
+            }
+
+            let previous = tensor.borrow().previous.clone();
+            stack.push((tensor, true));
+            for child in previous {
+                if !visited.contains(&Rc::as_ptr(&child)) {
+                    stack.push((child, false));
+                }
+            }
+        }
+
+        order
+    }
+}
 
-        first.gradient += value.gradient;
-        second.gradient += value.gradient;
+/// Shape of the result of an elementwise op, broadcasting like NumPy: the shapes
+/// are aligned on their last dimension, and a dimension of 1 (or a missing one)
+/// is repeated along the other operand's
+fn broadcast_shape(a: &TensorInternal, b: &TensorInternal) -> Result<Vec<usize>, String> {
+    let len = a.shape.len().max(b.shape.len());
+    let dim = |shape: &[usize], k: usize| match (shape.len() + k).checked_sub(len) {
+        Some(k) => shape[k],
+        None => 1,
     };
 
-    Tensor::new(TensorInternal::new(
-        result,
+    (0..len)
+        .map(|k| match (dim(&a.shape, k), dim(&b.shape, k)) {
+            (x, y) if x == y || y == 1 => Ok(x),
+            (1, y) => Ok(y),
+            _ => Err(format!(
+                "Can't broadcast tensors of shape {:?} and {:?}",
+                a.shape, b.shape
+            )),
+        })
+        .collect()
+}
+
+/// Index in a tensor of shape `shape` of the element broadcast to index `i` of
+/// the result of shape `out`
+fn broadcast_index(i: usize, out: &[usize], shape: &[usize]) -> usize {
+    if shape == out {
+        return i;
+    }
+
+    let (mut index, mut stride, mut rest) = (0, 1, i);
+    for (k, &out_dim) in out.iter().enumerate().rev() {
+        let coordinate = rest % out_dim;
+        rest /= out_dim;
+        // the dimension of `shape` aligned with `k`, missing ones are broadcast
+        let Some(k) = (shape.len() + k).checked_sub(out.len()) else {
+            break;
+        };
+        if shape[k] != 1 {
+            index += coordinate * stride;
+        }
+        stride *= shape[k];
+    }
+    index
+}
+
+fn elementwise(
+    a: &Tensor,
+    b: &Tensor,
+    op: &str,
+    f: fn(f64, f64) -> f64,
+    propagate: PropagateFn,
+) -> Result<Tensor, String> {
+    let (first, second) = (a.borrow(), b.borrow());
+    let shape = broadcast_shape(&first, &second)?;
+    let data = (0..shape.iter().product())
+        .map(|i| f(first.at(i, &shape), second.at(i, &shape)))
+        .collect();
+
+    Ok(Tensor::new(TensorInternal::new(
+        data,
+        shape,
         None,
-        Some("+".to_string()),
+        Some(op.to_string()),
         vec![a.clone(), b.clone()],
-        Some(prop_fn),
-    ))
+        Some(propagate),
+    )))
 }
 
-fn mul(a: &Tensor, b: &Tensor) -> Tensor {
-    let result = a.borrow().data * b.borrow().data;
+/// A leaf mask of `f` applied to the broadcast elements of `a` and `b`
+fn compare(a: &Tensor, b: &Tensor, f: impl Fn(f64, f64) -> bool) -> Result<Tensor, String> {
+    let (first, second) = (a.borrow(), b.borrow());
+    let shape = broadcast_shape(&first, &second)?;
+    let data = (0..shape.iter().product())
+        .map(|i| f(first.at(i, &shape), second.at(i, &shape)) as i32 as f64)
+        .collect();
 
-    let prop_fn: PropagateFn = |value| {
-        let mut first = value.previous[0].borrow_mut();
-        let mut second = value.previous[1].borrow_mut();
+    Ok(Tensor::from_vec(data, shape))
+}
 
-        first.gradient += second.data * value.gradient;
-        second.gradient += first.data * value.gradient;
+/// The `n x m` product of row-major matrices, `a` is `n x k` (or its transpose
+/// is, when `transpose_a`) and `b` is `k x m` (or its transpose is)
+fn matmul(
+    a: &[f64],
+    b: &[f64],
+    n: usize,
+    k: usize,
+    m: usize,
+    transpose_a: bool,
+    transpose_b: bool,
+) -> Vec<f64> {
+    let a_at = |i: usize, p: usize| {
+        if transpose_a {
+            a[p * n + i]
+        } else {
+            a[i * k + p]
+        }
+    };
+    let b_at = |p: usize, j: usize| {
+        if transpose_b {
+            b[j * k + p]
+        } else {
+            b[p * m + j]
+        }
     };
+    (0..n * m)
+        .map(|index| {
+            let (i, j) = (index / m, index % m);
+            (0..k).map(|p| a_at(i, p) * b_at(p, j)).sum()
+        })
+        .collect()
+}
+
+fn unary(a: &Tensor, op: &str, f: fn(f64) -> f64, propagate: PropagateFn) -> Tensor {
+    let tensor = a.borrow();
+    let data = tensor.data.iter().map(|x| f(*x)).collect();
 
     Tensor::new(TensorInternal::new(
-        result,
+        data,
+        tensor.shape.clone(),
         None,
-        Some("*".to_string()),
-        vec![a.clone(), b.clone()],
-        Some(prop_fn),
+        Some(op.to_string()),
+        vec![a.clone()],
+        Some(propagate),
     ))
 }
 
-impl std::ops::Add for Tensor {
-    type Output = Self;
+/// `local` gives the derivatives of an element of the output with respect to the
+/// elements of both inputs, a broadcast input sums the gradients of the elements
+/// it was repeated to
+fn propagate_binary(value: &Ref<TensorInternal>, local: fn(f64, f64, f64) -> (f64, f64)) {
+    // NOTE: both inputs can be the same tensor (`a * a`), so they are only borrowed
+    // mutably one at a time, after computing the gradients
+    let (first_gradient, second_gradient) = {
+        let first = value.previous[0].borrow();
+        let second = value.previous[1].borrow();
 
-    fn add(self, other: Self) -> Self {
-        add(&self, &other)
-    }
+        let mut first_gradient = vec![0.0; first.data.len()];
+        let mut second_gradient = vec![0.0; second.data.len()];
+        for i in 0..value.data.len() {
+            let (a, b) = (
+                broadcast_index(i, &value.shape, &first.shape),
+                broadcast_index(i, &value.shape, &second.shape),
+            );
+            let (da, db) = local(first.data[a], second.data[b], value.data[i]);
+            first_gradient[a] += da * value.gradient[i];
+            second_gradient[b] += db * value.gradient[i];
+        }
+        (first_gradient, second_gradient)
+    };
+
+    value.previous[0].borrow_mut().accumulate(&first_gradient);
+    value.previous[1].borrow_mut().accumulate(&second_gradient);
 }
 
-impl std::ops::Sub for Tensor {
-    type Output = Self;
+/// A scalar of the sum of the elements of `a` times `scale` of their count
+fn reduction(a: &Tensor, op: &str, scale: fn(usize) -> f64, propagate: PropagateFn) -> Tensor {
+    let tensor = a.borrow();
+    let total = tensor.data.iter().sum::<f64>() * scale(tensor.data.len());
 
-    fn sub(self, other: Self) -> Self {
-        add(&self, &(-other))
-    }
+    Tensor::new(TensorInternal::new(
+        vec![total],
+        Vec::new(),
+        None,
+        Some(op.to_string()),
+        vec![a.clone()],
+        Some(propagate),
+    ))
 }
 
-impl std::ops::Mul for Tensor {
-    type Output = Self;
+/// Every element of the input gets the gradient of the scalar output times `scale`
+/// of the number of elements
+fn propagate_reduction(value: &Ref<TensorInternal>, scale: fn(usize) -> f64) {
+    let gradient = {
+        let len = value.previous[0].borrow().data.len();
+        vec![value.gradient[0] * scale(len); len]
+    };
 
-    fn mul(self, other: Self) -> Self {
-        mul(&self, &other)
-    }
+    value.previous[0].borrow_mut().accumulate(&gradient);
 }
 
-impl std::ops::Div for Tensor {
-    type Output = Self;
+/// `local` gives the derivative of an element of the output from the input and output element
+fn propagate_unary(value: &Ref<TensorInternal>, local: fn(f64, f64) -> f64) {
+    let gradient: Vec<f64> = {
+        let previous = value.previous[0].borrow();
+        (0..value.data.len())
+            .map(|i| local(previous.data[i], value.data[i]) * value.gradient[i])
+            .collect()
+    };
+
+    value.previous[0].borrow_mut().accumulate(&gradient);
+}
 
-    fn div(self, other: Self) -> Self {
-        // TODO: does this work?
-        mul(&self, &other.pow(&Tensor::from(-1)))
+/// `[[1, 2], [3, 4]]` for a 2x2 tensor, a scalar is printed as a plain number
+fn format_nested(data: &[f64], shape: &[usize], options: &PrintOptions) -> String {
+    match shape {
+        [] => format!("{}", data[0]),
+        [length, rest @ ..] => {
+            let stride = rest.iter().product::<usize>();
+            let items: Vec<String> = (0..*length)
+                .map(|i| format_nested(&data[i * stride..(i + 1) * stride], rest, options))
+                .collect();
+            let separator = match rest {
+                [] => &options.element_separator,
+                _ => &options.row_separator,
+            };
+            format!("[{}]", items.join(separator))
+        }
     }
 }
 
@@ -202,16 +675,11 @@ impl std::ops::Neg for Tensor {
     type Output = Self;
 
     fn neg(self) -> Self {
-        mul(&self, &Tensor::from(-1))
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |_, _| -1.0);
+        unary(&self, "neg", |x| -x, prop_fn)
     }
 }
 
-// impl PartialEq for Tensor {
-//     fn eq(&self, other: &Self) -> bool {
-//         self.data == other.data
-//     }
-// }
-
 // impl PartialOrd for Tensor {
 //     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
 //         self.data.partial_cmp(&other.data)
@@ -234,18 +702,21 @@ impl std::ops::Deref for Tensor {
 
 impl<T: Into<f64>> From<T> for Tensor {
     fn from(t: T) -> Tensor {
-        Tensor::new(TensorInternal::new(t.into(), None, None, Vec::new(), None))
+        Tensor::from_vec(vec![t.into()], Vec::new())
     }
 }
 
 ////////////////////////////////////////////////////
-/// ////////////////////////////////////////////////
+// ////////////////////////////////////////////////
 
 type PropagateFn = fn(value: &Ref<TensorInternal>);
 
 pub struct TensorInternal {
-    data: f64,
-    gradient: f64,
+    data: Vec<f64>,
+    shape: Vec<usize>,
+    gradient: Vec<f64>,
+    // some gradient was propagated into `gradient`, even if it's all zeros
+    gradient_set: bool,
     label: Option<String>,
     operation: Option<String>,
     previous: Vec<Tensor>,
@@ -254,26 +725,48 @@ pub struct TensorInternal {
 
 impl TensorInternal {
     fn new(
-        data: f64,
+        data: Vec<f64>,
+        shape: Vec<usize>,
         label: Option<String>,
         op: Option<String>,
         prev: Vec<Tensor>,
         propagate: Option<PropagateFn>,
     ) -> TensorInternal {
         TensorInternal {
+            gradient: vec![0.0; data.len()],
+            gradient_set: false,
             data,
-            gradient: 0.0,
+            shape,
             label,
             operation: op,
             previous: prev,
             propagate,
         }
     }
+
+    /// Element `i` of the operand broadcast to the shape `out`
+    fn at(&self, i: usize, out: &[usize]) -> f64 {
+        self.data[broadcast_index(i, out, &self.shape)]
+    }
+
+    fn accumulate(&mut self, gradient: &[f64]) {
+        self.gradient_set = true;
+        for (total, g) in self.gradient.iter_mut().zip(gradient) {
+            *total += g;
+        }
+    }
+}
+
+impl Drop for TensorInternal {
+    fn drop(&mut self) {
+        LIVE_TENSORS.with(|count| count.set(count.get() - 1));
+    }
 }
 
 impl PartialEq for TensorInternal {
     fn eq(&self, other: &Self) -> bool {
         self.data == other.data
+            && self.shape == other.shape
             && self.gradient == other.gradient
             && self.label == other.label
             && self.operation == other.operation
@@ -285,8 +778,10 @@ impl Eq for TensorInternal {}
 
 impl std::hash::Hash for TensorInternal {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.data.to_bits().hash(state);
-        self.gradient.to_bits().hash(state);
+        for x in self.data.iter().chain(&self.gradient) {
+            x.to_bits().hash(state);
+        }
+        self.shape.hash(state);
         self.label.hash(state);
         self.operation.hash(state);
         self.previous.hash(state);
@@ -297,6 +792,7 @@ impl std::fmt::Debug for TensorInternal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ValueInternal")
             .field("data", &self.data)
+            .field("shape", &self.shape)
             .field("gradient", &self.gradient)
             .field("label", &self.label)
             .field("operation", &self.operation)
@@ -304,3 +800,266 @@ impl std::fmt::Debug for TensorInternal {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scalar_broadcasts_and_receives_gradient() {
+        let s = Tensor::from(2.0);
+        let t = Tensor::from_vec(vec![1.0, 2.0, 3.0, 4.0], vec![2, 2]);
+
+        let y = s.mul(&t).unwrap();
+        assert_eq!(y.to_string(), "[[2, 4], [6, 8]]");
+
+        y.backward_seeded(vec![1.0; 4]);
+        // the scalar is used by every element, so it gets the sum of their gradients
+        assert_eq!(s.gradient().data(), vec![10.0]);
+        assert_eq!(t.gradient().data(), vec![2.0; 4]);
+    }
+
+    #[test]
+    fn test_shared_node_is_propagated_once() {
+        let a = Tensor::from(3.0);
+        let b = a.mul(&a).unwrap();
+        let c = b.add(&b).unwrap();
+        c.backward().unwrap();
+
+        // c = 2a^2
+        assert_eq!(a.gradient().data(), vec![12.0]);
+    }
+
+    #[test]
+    fn test_zero_gradient_is_set() {
+        let x = Tensor::from(-2.0);
+        let w = Tensor::from(3.0);
+        let unused = Tensor::from(1.0);
+        let loss = x.relu().mul(&w).unwrap();
+        loss.backward().unwrap();
+
+        // the relu of a negative number lets no gradient through, but `x` is in the graph
+        assert_eq!(x.gradient().data(), vec![0.0]);
+        assert!(x.has_gradient());
+        assert_eq!(w.gradient().data(), vec![0.0]);
+        assert!(w.has_gradient());
+        assert_eq!(unused.gradient().data(), vec![0.0]);
+        assert!(!unused.has_gradient());
+
+        x.clear_gradient();
+        assert!(!x.has_gradient());
+    }
+
+    #[test]
+    fn test_backward_with_seed() {
+        let a = Tensor::from_vec(vec![1.0, 2.0, 3.0, 4.0], vec![2, 2]);
+        let b = a.mul(&a).unwrap();
+
+        let seed = Tensor::from_vec(vec![1.0, 0.0, 0.5, 2.0], vec![2, 2]);
+        b.backward_with(&seed).unwrap();
+        // d(a^2)/da = 2a, times the upstream gradient
+        assert_eq!(a.gradient().data(), vec![2.0, 0.0, 3.0, 16.0]);
+
+        let seed = Tensor::from_vec(vec![1.0; 4], vec![4]);
+        assert_eq!(
+            b.backward_with(&seed).unwrap_err(),
+            "backward got a gradient of shape [4] for a tensor of shape [2, 2]"
+        );
+    }
+
+    #[test]
+    fn test_set_gradient() {
+        let a = Tensor::from_vec(vec![1.0, 2.0], vec![2]);
+        a.set_gradient(&Tensor::from_vec(vec![0.5, -1.0], vec![2]))
+            .unwrap();
+        assert!(a.has_gradient());
+        assert_eq!(a.gradient().data(), vec![0.5, -1.0]);
+
+        // a backward adds to the injected gradient
+        a.mul(&Tensor::from(3.0))
+            .unwrap()
+            .backward_with(&Tensor::from_vec(vec![1.0, 1.0], vec![2]))
+            .unwrap();
+        assert_eq!(a.gradient().data(), vec![3.5, 2.0]);
+
+        assert_eq!(
+            a.set_gradient(&Tensor::from(1.0)).unwrap_err(),
+            "set_grad got a gradient of shape [] for a tensor of shape [2]"
+        );
+    }
+
+    #[test]
+    fn test_log_softmax() {
+        let logits = [1.0, 2.0, 3.0, -1.0, 0.0, 0.5];
+        let x = Tensor::from_vec(logits.to_vec(), vec![2, 3]);
+        let y = x.log_softmax();
+
+        // the log of the softmax of each row
+        for (row, out) in logits.chunks(3).zip(y.data().chunks(3)) {
+            let total: f64 = row.iter().map(|x| x.exp()).sum();
+            for (x, y) in row.iter().zip(out) {
+                assert!((y - (x.exp() / total).ln()).abs() < 1e-12);
+            }
+        }
+
+        // exp(1000) overflows, the naive log(softmax) is NaN
+        let large = Tensor::from_vec(vec![1000.0, 1000.0], vec![2]).log_softmax();
+        assert!((large.data()[0] - 0.5f64.ln()).abs() < 1e-12);
+
+        // gradient check of sum(w * log_softmax(x)) against central differences
+        let w = [0.3, -1.0, 2.0, 1.0, 0.0, -0.5];
+        y.backward_with(&Tensor::from_vec(w.to_vec(), vec![2, 3]))
+            .unwrap();
+        let loss = |logits: Vec<f64>| -> f64 {
+            let y = Tensor::from_vec(logits, vec![2, 3]).log_softmax();
+            y.data().iter().zip(&w).map(|(y, w)| y * w).sum()
+        };
+        let h = 1e-6;
+        for (i, analytic) in x.gradient().data().iter().enumerate() {
+            let (mut plus, mut minus) = (logits.to_vec(), logits.to_vec());
+            plus[i] += h;
+            minus[i] -= h;
+            let numeric = (loss(plus) - loss(minus)) / (2.0 * h);
+            assert!(
+                (analytic - numeric).abs() < 1e-6,
+                "{} {}",
+                analytic,
+                numeric
+            );
+        }
+    }
+
+    #[test]
+    fn test_activation_gradients() {
+        let inputs = [-2.0, -0.5, 0.0, 0.3, 1.5];
+        let activations: [fn(&Tensor) -> Tensor; 2] = [Tensor::sigmoid, Tensor::tanh];
+        for activation in activations {
+            let x = Tensor::from_vec(inputs.to_vec(), vec![5]);
+            let y = activation(&x);
+            y.backward_with(&Tensor::from_vec(vec![1.0; 5], vec![5]))
+                .unwrap();
+
+            // central differences of each element
+            let h = 1e-6;
+            let at = |x: f64| activation(&Tensor::from(x)).data()[0];
+            for (x, analytic) in inputs.iter().zip(x.gradient().data()) {
+                let numeric = (at(x + h) - at(x - h)) / (2.0 * h);
+                assert!(
+                    (analytic - numeric).abs() < 1e-6,
+                    "{} {}",
+                    analytic,
+                    numeric
+                );
+            }
+        }
+
+        let y = Tensor::from(0.0).sigmoid();
+        assert_eq!(y.data(), vec![0.5]);
+    }
+
+    #[test]
+    fn test_sum_mean() {
+        let x = Tensor::from_vec(vec![1.0, 2.0, 3.0, 6.0], vec![2, 2]);
+        let sum = x.sum();
+        let mean = x.mean();
+        assert_eq!((sum.shape(), sum.data()), (vec![], vec![12.0]));
+        assert_eq!((mean.shape(), mean.data()), (vec![], vec![3.0]));
+
+        // the gradient has the shape of the input
+        sum.backward().unwrap();
+        assert_eq!(x.gradient().shape(), vec![2, 2]);
+        assert_eq!(x.gradient().data(), vec![1.0; 4]);
+        mean.backward().unwrap();
+        assert_eq!(x.gradient().data(), vec![1.25; 4]);
+    }
+
+    #[test]
+    fn test_matmul() {
+        let a = Tensor::from_vec(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], vec![2, 3]);
+        let b = Tensor::from_vec(vec![7.0, 8.0, 9.0, 10.0, 11.0, 12.0], vec![3, 2]);
+        let c = a.matmul(&b).unwrap();
+        assert_eq!(c.shape(), vec![2, 2]);
+        assert_eq!(c.data(), vec![58.0, 64.0, 139.0, 154.0]);
+
+        // dA = G @ B^T and dB = A^T @ G
+        let seed = Tensor::from_vec(vec![1.0, 0.0, 0.0, 2.0], vec![2, 2]);
+        c.backward_with(&seed).unwrap();
+        assert_eq!(a.gradient().data(), vec![7.0, 9.0, 11.0, 16.0, 20.0, 24.0]);
+        assert_eq!(b.gradient().data(), vec![1.0, 8.0, 2.0, 10.0, 3.0, 12.0]);
+
+        assert_eq!(
+            a.matmul(&a).unwrap_err(),
+            "Can't multiply matrices of shape [2, 3] and [2, 3]"
+        );
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let a = Tensor::from_vec(vec![1.0, 2.0], vec![2]);
+        let b = Tensor::from(3.0);
+        let c = a.mul(&b).unwrap().tanh();
+
+        let dot = c.to_dot();
+        assert!(dot.starts_with("digraph {\n"));
+        assert_eq!(dot.matches("[label=").count(), 4);
+        assert_eq!(dot.matches(" -> ").count(), 3);
+        assert!(dot.contains("[label=\"leaf [2]\"]"));
+        assert!(dot.contains("[label=\"leaf []\"]"));
+        assert!(dot.contains("[label=\"* [2]\"]"));
+        assert!(dot.contains("[label=\"tanh [2]\"]"));
+
+        // a node used twice is one node with two edges out
+        let d = a.mul(&a).unwrap();
+        let dot = d.to_dot();
+        assert_eq!(dot.matches("[label=").count(), 2);
+        assert_eq!(dot.matches("node0 -> node1;").count(), 2);
+    }
+
+    #[test]
+    fn test_row_broadcast() {
+        let a = Tensor::from_vec(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], vec![2, 3]);
+        let row = Tensor::from_vec(vec![10.0, 20.0, 30.0], vec![1, 3]);
+        let c = a.mul(&row).unwrap();
+        assert_eq!(c.shape(), vec![2, 3]);
+        assert_eq!(c.data(), vec![10.0, 40.0, 90.0, 40.0, 100.0, 180.0]);
+
+        // the row's gradient sums over the rows it was repeated to
+        c.backward_with(&Tensor::from_vec(vec![1.0; 6], vec![2, 3]))
+            .unwrap();
+        assert_eq!(row.gradient().shape(), vec![1, 3]);
+        assert_eq!(row.gradient().data(), vec![5.0, 7.0, 9.0]);
+        assert_eq!(
+            a.gradient().data(),
+            vec![10.0, 20.0, 30.0, 10.0, 20.0, 30.0]
+        );
+
+        // a column against a row broadcasts both, a missing dimension is a 1
+        let column = Tensor::from_vec(vec![1.0, 2.0], vec![2, 1]);
+        let row = Tensor::from_vec(vec![10.0, 20.0, 30.0], vec![3]);
+        let sum = column.add(&row).unwrap();
+        assert_eq!(sum.shape(), vec![2, 3]);
+        assert_eq!(sum.data(), vec![11.0, 21.0, 31.0, 12.0, 22.0, 32.0]);
+        sum.backward_with(&Tensor::from_vec(vec![1.0; 6], vec![2, 3]))
+            .unwrap();
+        assert_eq!(column.gradient().data(), vec![3.0, 3.0]);
+        assert_eq!(row.gradient().data(), vec![2.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn test_incompatible_shapes() {
+        let a = Tensor::from_vec(vec![1.0, 2.0], vec![2]);
+        let b = Tensor::from_vec(vec![1.0, 2.0, 3.0], vec![3]);
+
+        assert_eq!(
+            a.add(&b).unwrap_err(),
+            "Can't broadcast tensors of shape [2] and [3]"
+        );
+        let c = Tensor::from_vec(vec![0.0; 6], vec![2, 3]);
+        assert_eq!(
+            c.sub(&Tensor::from_vec(vec![0.0; 4], vec![2, 2]))
+                .unwrap_err(),
+            "Can't broadcast tensors of shape [2, 3] and [2, 2]"
+        );
+        assert!(a.backward().is_err());
+    }
+}
@@ -1,10 +1,49 @@
+use std::{cell::RefCell, rc::Rc};
+
 use serde::{Deserialize, Serialize};
 
-use crate::{interner::StringObjIdx, tensor::Tensor};
+use crate::{chunk::Chunk, interner::StringObjIdx, tensor::Tensor};
+
+/// A compiled `fn`, its body runs in a call frame of its own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    /// values of the last parameters, used when a call leaves them out
+    pub defaults: Vec<ValueType>,
+    pub chunk: Chunk,
+}
+
+impl Function {
+    /// Number of arguments a call has to pass
+    pub fn required(&self) -> usize {
+        self.arity - self.defaults.len()
+    }
+}
 
+/// The globals of a file imported with `import "path" as name`, each member is
+/// read from the global slot it was compiled to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Module {
+    pub name: String,
+    pub members: Vec<(StringObjIdx, usize)>,
+}
+
+impl Module {
+    /// Global slot of the member `name`
+    pub fn member(&self, name: StringObjIdx) -> Option<usize> {
+        self.members
+            .iter()
+            .find(|(member, _)| *member == name)
+            .map(|(_, slot)| *slot)
+    }
+}
+
+/// Two words: every variant is at most a pointer (tensors, arrays, functions and
+/// modules are behind an `Rc`) or a 64-bit number, plus the tag
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ValueType {
-    // Tensor(Tensor), // TODO: Ideally, it should be seperate types for int and float (maybe?)
+    Tensor(Tensor), // TODO: Ideally, it should be seperate types for int and float (maybe?)
     String(StringObjIdx),
     Identifier(StringObjIdx),
     Boolean(bool),
@@ -12,9 +51,12 @@ pub enum ValueType {
     Float(f64),
     Nil,
     // Lists, Dicts, Tensors, etc.
-    JumpOffset(usize),
+    // NOTE: arrays are only built at runtime (`OpBuildArray`), never stored as constants
+    #[serde(skip)]
+    Array(Rc<RefCell<Vec<ValueType>>>),
 
-    Function(String),
+    Function(Rc<Function>),
+    Module(Rc<Module>),
 }
 
 // impl std::fmt::Display for ValueType {
@@ -33,86 +75,260 @@ pub enum ValueType {
 
 // impl custom display for ValueType which also takes interner
 impl ValueType {
+    /// Name of the type as shown to the user in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ValueType::Tensor(_) => "tensor",
+            ValueType::String(_) => "string",
+            ValueType::Identifier(_) => "identifier",
+            ValueType::Boolean(_) => "bool",
+            ValueType::Integer(_) | ValueType::Float(_) => "number",
+            ValueType::Nil => "nil",
+            ValueType::Array(_) => "array",
+            ValueType::Function(_) => "function",
+            ValueType::Module(_) => "module",
+        }
+    }
+
     pub fn display(&self, interner: &crate::interner::Interner) -> String {
+        self.display_nested(interner, &mut Vec::new())
+    }
+
+    /// The value as text inside a string, like `${...}`, `format` and `str` show
+    /// it: a string is its text without quotes, anything else is `display`ed
+    pub fn to_text(&self, interner: &crate::interner::Interner) -> String {
         match self {
-            // ValueType::Tensor(n) => format!("{}", n),
             ValueType::String(s) => interner.lookup(*s).to_string(),
+            value => value.display(interner),
+        }
+    }
+
+    /// `visiting` holds the arrays currently being printed, so an array nested
+    /// inside itself is printed as `[...]` instead of recursing forever
+    fn display_nested(
+        &self,
+        interner: &crate::interner::Interner,
+        visiting: &mut Vec<*const RefCell<Vec<ValueType>>>,
+    ) -> String {
+        match self {
+            ValueType::Array(elements) => {
+                let ptr = Rc::as_ptr(elements);
+                if visiting.contains(&ptr) {
+                    return "[...]".to_string();
+                }
+
+                visiting.push(ptr);
+                let items: Vec<String> = elements
+                    .borrow()
+                    .iter()
+                    .map(|e| e.display_nested(interner, visiting))
+                    .collect();
+                visiting.pop();
+
+                format!("[{}]", items.join(", "))
+            }
+            ValueType::Tensor(n) => format!("{}", n),
+            ValueType::String(s) => format!("\"{}\"", interner.lookup(*s)),
             ValueType::Identifier(s) => interner.lookup(*s).to_string(),
             ValueType::Boolean(b) => format!("{}", b),
             ValueType::Integer(n) => format!("{}", n),
             ValueType::Float(n) => format!("{}", n),
-            ValueType::Nil => format!("nil"),
-            ValueType::JumpOffset(j) => format!("jmp->{}", j),
-            ValueType::Function(s) => format!("fn->{}", s),
+            ValueType::Nil => "nil".to_string(),
+            ValueType::Function(function) => format!("fn->{}", function.name),
+            ValueType::Module(module) => format!("<module {}>", module.name),
         }
     }
 }
 
-// impl +,-,*,/ for ValueType
-impl std::ops::Add for ValueType {
-    type Output = Self;
+/// Applies an arithmetic operator, integers stay integers, a float makes the result a
+/// float and a number mixed with a tensor is promoted to a scalar tensor, which is
+/// broadcast against the other operand
+fn arithmetic(
+    a: ValueType,
+    b: ValueType,
+    integer: fn(i64, i64) -> i64,
+    float: fn(f64, f64) -> f64,
+    tensor: fn(&Tensor, &Tensor) -> Result<Tensor, String>,
+) -> Result<ValueType, String> {
+    match (a, b) {
+        (ValueType::Integer(a), ValueType::Integer(b)) => Ok(ValueType::Integer(integer(a, b))),
+        (ValueType::Float(a), ValueType::Float(b)) => Ok(ValueType::Float(float(a, b))),
+        (ValueType::Float(a), ValueType::Integer(b)) => Ok(ValueType::Float(float(a, b as f64))),
+        (ValueType::Integer(a), ValueType::Float(b)) => Ok(ValueType::Float(float(a as f64, b))),
+        (a, b) => match (a.to_tensor(), b.to_tensor()) {
+            (Some(a), Some(b)) => tensor(&a, &b).map(ValueType::Tensor),
+            _ => Err(format!(
+                "Operands must be numbers or tensors. Got: {} and {}",
+                a.type_name(),
+                b.type_name()
+            )),
+        },
+    }
+}
 
-    fn add(self, other: Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a + b),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a + b),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a + b),
-            (ValueType::Float(a), ValueType::Integer(b)) => ValueType::Float(a + b as f64),
-            (ValueType::Integer(a), ValueType::Float(b)) => ValueType::Float(a as f64 + b),
+impl ValueType {
+    /// What `if` and `while` treat as false: `false`, `nil` and a tensor with a
+    /// zero element, so `if (t > 0)` runs when the whole mask holds, like `all(t > 0)`
+    pub fn is_falsy(&self) -> bool {
+        match self {
+            ValueType::Boolean(b) => !b,
+            ValueType::Nil => true,
+            ValueType::Tensor(t) => !t.all(),
+            _ => false,
+        }
+    }
+
+    /// `a > b`, a tensor compared to a tensor or a number gives a mask
+    pub fn greater(&self, other: &Self) -> Result<Self, String> {
+        self.compare(other, std::cmp::Ordering::Greater, Tensor::greater)
+    }
+
+    /// `a < b`, a tensor compared to a tensor or a number gives a mask
+    pub fn less(&self, other: &Self) -> Result<Self, String> {
+        self.compare(other, std::cmp::Ordering::Less, Tensor::less)
+    }
 
-            (a, b) => panic!("Operands must be numbers. Got: {:?} and {:?}", a, b),
+    fn compare(
+        &self,
+        other: &Self,
+        ordering: std::cmp::Ordering,
+        tensor: fn(&Tensor, &Tensor) -> Result<Tensor, String>,
+    ) -> Result<Self, String> {
+        let is_number = |value: &Self| matches!(value, ValueType::Integer(_) | ValueType::Float(_));
+        let error = || {
+            Err(format!(
+                "Can't compare {} and {}",
+                self.type_name(),
+                other.type_name()
+            ))
+        };
+        if !matches!(self, ValueType::Tensor(_)) && !matches!(other, ValueType::Tensor(_)) {
+            // only numbers are ordered, NaN is neither less nor greater than anything
+            if !is_number(self) || !is_number(other) {
+                return error();
+            }
+            return Ok(ValueType::Boolean(
+                self.partial_cmp(other) == Some(ordering),
+            ));
+        }
+
+        match (self.to_tensor(), other.to_tensor()) {
+            (Some(a), Some(b)) => tensor(&a, &b).map(ValueType::Tensor),
+            _ => error(),
         }
     }
+
+    /// How many arrays deep the value nests, 0 for anything but an array
+    pub fn depth(&self) -> usize {
+        match self {
+            ValueType::Array(elements) => {
+                1 + elements.borrow().iter().map(Self::depth).max().unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Tensors as they are, numbers as a new scalar tensor
+    pub fn to_tensor(&self) -> Option<Tensor> {
+        match self {
+            ValueType::Tensor(t) => Some(t.clone()),
+            ValueType::Integer(n) => Some(Tensor::from(*n as f64)),
+            ValueType::Float(n) => Some(Tensor::from(*n)),
+            _ => None,
+        }
+    }
+}
+
+// impl +,-,*,/ for ValueType
+impl std::ops::Add for ValueType {
+    type Output = Result<Self, String>;
+
+    fn add(self, other: Self) -> Self::Output {
+        arithmetic(self, other, |a, b| a + b, |a, b| a + b, Tensor::add)
+    }
 }
 
 impl std::ops::Sub for ValueType {
-    type Output = Self;
+    type Output = Result<Self, String>;
 
-    fn sub(self, other: Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a - b),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a - b),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a - b),
-            _ => panic!("Operands must be numbers."),
-        }
+    fn sub(self, other: Self) -> Self::Output {
+        arithmetic(self, other, |a, b| a - b, |a, b| a - b, Tensor::sub)
     }
 }
 
 impl std::ops::Mul for ValueType {
-    type Output = Self;
+    type Output = Result<Self, String>;
 
-    fn mul(self, other: Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a * b),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a * b),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a * b),
-            _ => panic!("Operands must be numbers."),
-        }
+    fn mul(self, other: Self) -> Self::Output {
+        arithmetic(self, other, |a, b| a * b, |a, b| a * b, Tensor::mul)
     }
 }
 
 impl std::ops::Div for ValueType {
-    type Output = Self;
+    type Output = Result<Self, String>;
 
-    fn div(self, other: Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a / b),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a / b),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a / b),
-            _ => panic!("Operands must be numbers."),
+    /// A zero number as the divisor is an error instead of `inf` or `NaN`, a tensor
+    /// divisor is divided by elementwise whatever it holds
+    fn div(self, other: Self) -> Self::Output {
+        let dividend = matches!(
+            self,
+            ValueType::Integer(_) | ValueType::Float(_) | ValueType::Tensor(_)
+        );
+        let zero = match other {
+            ValueType::Integer(b) => b == 0,
+            ValueType::Float(b) => b == 0.0,
+            _ => false,
+        };
+        if dividend && zero {
+            return Err("Division by zero".to_string());
+        }
+        arithmetic(self, other, i64::wrapping_div, |a, b| a / b, Tensor::div)
+    }
+}
+
+/// The remainder has the sign of the dividend, like C's `fmod`, for numbers only
+impl std::ops::Rem for ValueType {
+    type Output = Result<Self, String>;
+
+    fn rem(self, other: Self) -> Self::Output {
+        match (&self, &other) {
+            (ValueType::Integer(_) | ValueType::Float(_), ValueType::Integer(0)) => {
+                Err("Modulo by zero".to_string())
+            }
+            (ValueType::Integer(_) | ValueType::Float(_), ValueType::Float(b)) if *b == 0.0 => {
+                Err("Modulo by zero".to_string())
+            }
+            (
+                ValueType::Integer(_) | ValueType::Float(_),
+                ValueType::Integer(_) | ValueType::Float(_),
+            ) => arithmetic(
+                self,
+                other,
+                i64::wrapping_rem,
+                |a, b| a % b,
+                |_, _| unreachable!(),
+            ),
+            (a, b) => Err(format!(
+                "Operands of % must be numbers. Got: {} and {}",
+                a.type_name(),
+                b.type_name()
+            )),
         }
     }
 }
 
 impl std::ops::Neg for ValueType {
-    type Output = Self;
+    type Output = Result<Self, String>;
 
-    fn neg(self) -> Self {
+    fn neg(self) -> Self::Output {
         match self {
-            // ValueType::Tensor(n) => ValueType::Tensor(-n),
-            ValueType::Integer(n) => ValueType::Integer(-n),
-            ValueType::Float(n) => ValueType::Float(-n),
-            _ => panic!("Operand must be a number."),
+            ValueType::Tensor(n) => Ok(ValueType::Tensor(-n)),
+            ValueType::Integer(n) => Ok(ValueType::Integer(-n)),
+            ValueType::Float(n) => Ok(ValueType::Float(-n)),
+            value => Err(format!(
+                "Operand must be a number or a tensor. Got: {}",
+                value.type_name()
+            )),
         }
     }
 }
@@ -121,10 +337,10 @@ impl std::ops::Not for ValueType {
     type Output = Self;
 
     fn not(self) -> Self {
+        // a tensor is negated elementwise, anything else is truthy like in `if`
         match self {
-            ValueType::Boolean(b) => ValueType::Boolean(!b),
-            ValueType::Nil => ValueType::Boolean(true), // NOTE: nil is falsey, should likely be removed perhaps ?
-            _ => panic!("Operand must be a boolean."),
+            ValueType::Tensor(t) => ValueType::Tensor(t.not()),
+            value => ValueType::Boolean(value.is_falsy()),
         }
     }
 }
@@ -132,7 +348,7 @@ impl std::ops::Not for ValueType {
 impl std::cmp::PartialEq for ValueType {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => a == b,
+            (ValueType::Tensor(a), ValueType::Tensor(b)) => a == b,
             (ValueType::Integer(a), ValueType::Integer(b)) => a == b,
             (ValueType::Float(a), ValueType::Float(b)) => a == b,
             (ValueType::Boolean(a), ValueType::Boolean(b)) => a == b,
@@ -149,6 +365,8 @@ impl std::cmp::PartialOrd for ValueType {
             // _ => None,
             (ValueType::Integer(a), ValueType::Integer(b)) => a.partial_cmp(b),
             (ValueType::Float(a), ValueType::Float(b)) => a.partial_cmp(b),
+            (ValueType::Integer(a), ValueType::Float(b)) => (*a as f64).partial_cmp(b),
+            (ValueType::Float(a), ValueType::Integer(b)) => a.partial_cmp(&(*b as f64)),
             _ => None,
         }
     }
@@ -156,13 +374,78 @@ impl std::cmp::PartialOrd for ValueType {
 
 // impl powf value
 impl ValueType {
-    pub fn pow(&self, other: &Self) -> Self {
+    /// `a @ b`, the matrix product of two tensors
+    pub fn matmul(&self, other: &Self) -> Result<Self, String> {
         match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a.pow(b)),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a.pow(*b as u32)),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a.powf(*b)),
-            (ValueType::Float(a), ValueType::Integer(b)) => ValueType::Float(a.powf(*b as f64)),
-            (a, b) => panic!("{}", format!("Operands must be numbers. Got: {:?} and {:?}", a, b)),
+            (ValueType::Tensor(a), ValueType::Tensor(b)) => a.matmul(b).map(ValueType::Tensor),
+            (a, b) => Err(format!(
+                "Operands of @ must be tensors. Got: {} and {}",
+                a.type_name(),
+                b.type_name()
+            )),
         }
     }
+
+    pub fn pow(&self, other: &Self) -> Result<Self, String> {
+        match (self, other) {
+            (ValueType::Integer(a), ValueType::Integer(b)) => {
+                Ok(ValueType::Integer(a.pow(*b as u32)))
+            }
+            (a, b) => arithmetic(
+                a.clone(),
+                b.clone(),
+                |_, _| unreachable!(),
+                f64::powf,
+                Tensor::pow,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interner::Interner;
+
+    #[test]
+    fn test_value_is_two_words() {
+        assert_eq!(
+            std::mem::size_of::<ValueType>(),
+            2 * std::mem::size_of::<usize>()
+        );
+
+        // a clone shares the tensor, it doesn't copy it
+        let tensor = Tensor::from(2.0);
+        let value = ValueType::Tensor(tensor.clone());
+        let ValueType::Tensor(copy) = value.clone() else {
+            unreachable!()
+        };
+        copy.backward().unwrap();
+        assert!(tensor.has_gradient());
+    }
+
+    #[test]
+    fn test_display_self_referential_array() {
+        let interner = Interner::default();
+
+        let array = Rc::new(RefCell::new(vec![ValueType::Integer(1)]));
+        let inner = ValueType::Array(Rc::new(RefCell::new(vec![ValueType::Array(array.clone())])));
+        array.borrow_mut().push(inner);
+        array.borrow_mut().push(ValueType::Array(array.clone()));
+
+        let value = ValueType::Array(array.clone());
+        assert_eq!(value.display(&interner), "[1, [[...]], [...]]");
+
+        // break the cycle so the test doesn't leak
+        array.borrow_mut().clear();
+    }
+
+    #[test]
+    fn test_display_shared_array_is_not_a_cycle() {
+        let interner = Interner::default();
+
+        let shared = ValueType::Array(Rc::new(RefCell::new(vec![ValueType::Boolean(true)])));
+        let value = ValueType::Array(Rc::new(RefCell::new(vec![shared.clone(), shared])));
+        assert_eq!(value.display(&interner), "[[true], [true]]");
+    }
 }
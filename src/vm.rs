@@ -1,352 +1,1406 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    rc::Rc,
+};
 use thiserror::Error;
 
 use crate::{
-    chunk::{self, Chunk, VectorType},
+    chunk::{self, Chunk},
+    compiler,
     interner::{Interner, StringObjIdx},
-    tensor::Tensor,
-    value::ValueType,
+    native::{self, bind_keywords, display_all, Native, NativeFn, NATIVES, TENSOR_METHODS},
+    tensor::{self, PrintOptions},
+    value::{Function, ValueType},
 };
 
-const STACK_MAX: usize = 256;
+/// Default for `VmConfig::max_frames`, the deepest chain of nested calls a program can make
+pub const FRAMES_MAX: usize = 64;
+/// Default for `VmConfig::stack_size`
+pub const STACK_MAX: usize = FRAMES_MAX * 16;
+/// Default for `VM::set_max_value_depth`
+pub const MAX_VALUE_DEPTH: usize = 256;
+/// Functions written in the language that every program can call, unless the
+/// VM is told otherwise with `set_prelude`
+pub const PRELUDE: &str = include_str!("prelude.ai");
 
+/// Where to resume the caller once a function returns
 struct CallFrame {
+    function: Option<Rc<Function>>,
+    ip: usize,
+    slot_base: usize,
+}
+
+/// A `try` being run, what a runtime error in its body unwinds to
+struct Handler {
+    function: Option<Rc<Function>>,
+    // the number of call frames when the `try` started
+    frames: usize,
+    // where the catch block starts
     ip: usize,
+    slot_base: usize,
     stack_top: usize,
+    // a `finally`, which gets whether the error was thrown too
+    finally: bool,
 }
 
 pub struct VM {
     pub chunk: Chunk,
 
+    // the function being run, `None` for the top-level script in `chunk`
+    function: Option<Rc<Function>>,
     // instruction pointer
     ip: usize,
+    // stack index of the first local (the first argument) of the current function
+    slot_base: usize,
 
     // TODO - implement JIT instead of stack perhaps ?
-    // NOTE - using a fixed size array for the stack instead of a Vec
-    stack: [ValueType; STACK_MAX],
+    // NOTE - the stack is allocated once with `VmConfig::stack_size` entries and never grows
+    stack: Box<[ValueType]>,
     stack_top: usize,
 
     pub interner: Interner,
 
-    globals: HashMap<StringObjIdx, ValueType>,
+    // globals by the slot the compiler gave them, `None` until they are defined
+    globals: Vec<Option<ValueType>>,
+    natives: HashMap<StringObjIdx, &'static Native>,
+    // the natives an embedder added with `register_native`, called before the
+    // builtin ones of the same name
+    registered: HashMap<StringObjIdx, NativeFn>,
+    // ids of the tensor methods by name, like the natives only the ones
+    // `capabilities` allows
+    methods: HashMap<&'static str, usize>,
+    // by method id, the slot of the global named like the method, where a
+    // function of the program takes the method's place. Found again on `load`
+    method_slots: Vec<Option<usize>>,
+    // the functions of `PRELUDE` by name, a call finds them when no global has the name
+    prelude: HashMap<StringObjIdx, Rc<Function>>,
+    capabilities: Capabilities,
+    // most `print`s a run can collect, unlimited when `None`
+    output_limit: Option<usize>,
+    // check the chunk with `Chunk::verify` before running it
+    verify: bool,
+    // `let` of a global that is already defined is an error
+    strict: bool,
+    // what the `print`s of the program printed so far
+    outputs: Vec<String>,
+    // where the `print`s write their lines, stdout when `None`
+    output: Option<Box<dyn Write>>,
+    // source lines `run` pauses at
+    breakpoints: HashSet<usize>,
+    // `run` returned `Paused`, the next one goes on past the breakpoint
+    paused: bool,
+    // deepest nesting of arrays a program can build, displaying or comparing
+    // values recurses this deep
+    max_value_depth: usize,
+    // runtime errors tell how many instructions ran and for how long
+    profile_errors: bool,
+    // runtime errors start with the line of the instruction that failed
+    error_lines: bool,
+    // instructions run since the program was loaded
+    instructions: usize,
+    // calls of each tensor method, only counted once `set_profile_tensor_ops` is on
+    tensor_ops: Option<BTreeMap<&'static str, TensorOpStats>>,
+    // when the program started running, only measured for `profile_errors`
+    started: Option<std::time::Instant>,
+    // globals whose changes stop `run`
+    watched: HashSet<String>,
+    // the watched global the last instruction changed, with its previous value
+    changed: Option<(usize, Option<ValueType>)>,
 
+    // callers of the function being run, calls and returns push and pop frames
+    // here instead of recursing, so only `max_frames` bounds the recursion depth
     call_frames: Vec<CallFrame>,
-    frame_index: usize,
+    max_frames: usize,
+    // most instructions a run can execute, unlimited when `None`
+    max_steps: Option<usize>,
+    // the `try`s being run, innermost last
+    handlers: Vec<Handler>,
+    // the value of the `throw` whose error is being raised
+    thrown: Option<ValueType>,
+}
+
+/// What a program can call besides its own code, `--no-std` disables everything
+/// so a script can only do pure computation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    /// natives like `tensor` and `randn`
+    pub natives: bool,
+    /// tensor methods like `relu` and `backward`
+    pub tensor_methods: bool,
+}
+
+impl Capabilities {
+    pub const ALL: Capabilities = Capabilities {
+        natives: true,
+        tensor_methods: true,
+    };
+    pub const NONE: Capabilities = Capabilities {
+        natives: false,
+        tensor_methods: false,
+    };
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::ALL
+    }
+}
+
+/// The limits of a VM and the seed of its random numbers, see `VM::with_config`.
+/// The default is what `VM::init` uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmConfig {
+    /// values the stack can hold, pushing more is a stack overflow
+    pub stack_size: usize,
+    /// deepest chain of nested calls
+    pub max_frames: usize,
+    /// most instructions a run can execute, so an endless loop stops with an
+    /// error. Unlimited when `None`
+    pub max_steps: Option<usize>,
+    /// seed of the random number generator (see `VM::set_seed`), random when `None`
+    pub seed: Option<u64>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            stack_size: STACK_MAX,
+            max_frames: FRAMES_MAX,
+            max_steps: None,
+            seed: None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Error)]
 pub enum Result {
+    /// what the `print`s printed and the value of the last statement when it
+    /// is an expression, nil otherwise
     #[error("Ok")]
-    Ok(Vec<String>),
+    Ok(Vec<String>, ValueType),
 
     #[error("Compile error : {0}")]
     CompileErr(String),
 
     #[error("Runtime error : {0}")]
     RuntimeErr(String),
+
+    /// stopped at a breakpoint on this line, `run` again to resume
+    #[error("Paused at line {0}")]
+    Paused(usize),
+
+    /// stopped after a watched global changed, `old` is `None` when it was
+    /// just defined. `run` again to resume
+    #[error("'{name}' changed to {new}")]
+    Changed {
+        name: String,
+        old: Option<String>,
+        new: String,
+    },
+}
+
+/// How often a tensor method ran, see `VM::set_profile_tensor_ops`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TensorOpStats {
+    pub calls: usize,
+    /// shape of the tensor it was last called on
+    pub shape: Vec<usize>,
+}
+
+/// What `VM::step` did
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// ran the instruction, the program goes on
+    Ran(chunk::OpCode),
+    /// ran the return ending the script, the program is done
+    Halted,
+    /// the instruction failed, the program stopped with this error
+    Error(String),
 }
 
 impl VM {
     pub fn init(chunk: Chunk, interner: Interner) -> VM {
+        VM::with_capabilities(chunk, interner, Capabilities::ALL)
+    }
+
+    /// A VM where only what `capabilities` allows is callable, natives that are
+    /// disabled aren't registered at all
+    pub fn with_capabilities(chunk: Chunk, interner: Interner, capabilities: Capabilities) -> VM {
+        VM::with_config(chunk, interner, capabilities, VmConfig::default())
+    }
+
+    /// A VM with the limits of `config` instead of the default ones
+    pub fn with_config(
+        chunk: Chunk,
+        mut interner: Interner,
+        capabilities: Capabilities,
+        config: VmConfig,
+    ) -> VM {
+        if let Some(seed) = config.seed {
+            native::seed(seed);
+        }
+        let mut natives = HashMap::new();
+        register_natives(&mut natives, &mut interner, capabilities);
+        let methods = match capabilities.tensor_methods {
+            true => TENSOR_METHODS
+                .iter()
+                .enumerate()
+                .map(|(id, method)| (method.name, id))
+                .collect(),
+            false => HashMap::new(),
+        };
+
+        let globals = vec![None; chunk.global_names.len()];
+        let prelude = compile_prelude(&mut interner);
+
         // TODO: serialize and cache chunk and interner and save it as a file hash
-        VM {
+        let mut vm = VM {
             chunk,
+            function: None,
             ip: 0,
-            stack: core::array::from_fn(|_| ValueType::Nil),
+            slot_base: 0,
+            stack: vec![ValueType::Nil; config.stack_size].into_boxed_slice(),
             stack_top: 0,
             interner,
-            globals: HashMap::new(),
+            globals,
+            natives,
+            registered: HashMap::new(),
+            methods,
+            prelude,
+            capabilities,
+            output_limit: None,
+            verify: cfg!(debug_assertions),
+            strict: false,
+            outputs: Vec::new(),
+            output: None,
+            breakpoints: HashSet::new(),
+            paused: false,
+            max_value_depth: MAX_VALUE_DEPTH,
+            profile_errors: false,
+            error_lines: false,
+            tensor_ops: None,
+            instructions: 0,
+            started: None,
+            watched: HashSet::new(),
+            changed: None,
             call_frames: Vec::new(),
-            frame_index: 0,
+            max_frames: config.max_frames,
+            max_steps: config.max_steps,
+            handlers: Vec::new(),
+            thrown: None,
+            method_slots: Vec::new(),
+        };
+        vm.find_method_slots();
+        vm
+    }
+
+    /// Caps the number of `print`s a run collects, a run printing more stops with
+    /// an error, so a runaway loop can't fill the memory of an embedder
+    pub fn set_output_limit(&mut self, limit: Option<usize>) {
+        self.output_limit = limit;
+    }
+
+    /// Writes the lines of the `print`s to `output` instead of stdout. A buffered
+    /// writer is flushed whenever `run` returns, whether the program ended or failed
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Some(Box::new(output));
+    }
+
+    /// Makes `function` callable by `name` from programs, like `tensor` or `randn`,
+    /// whatever the capabilities. It gets every argument of the call, checking
+    /// them is up to it. A native with the same name is replaced
+    pub fn register_native(&mut self, name: &str, function: NativeFn) {
+        let name = self.interner.intern_string(name.to_string());
+        self.registered.insert(name, function);
+    }
+
+    /// Whether `run` verifies the chunk before running it, on by default in debug builds
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// In strict mode, defining a global that already exists is an error instead
+    /// of replacing its value
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Whether the functions of `PRELUDE` can be called, on by default
+    pub fn set_prelude(&mut self, prelude: bool) {
+        self.prelude = match prelude {
+            true => compile_prelude(&mut self.interner),
+            false => HashMap::new(),
+        };
+    }
+
+    /// Building an array nested deeper than `depth` (`[[1]]` is 2 deep) is an error
+    pub fn set_max_value_depth(&mut self, depth: usize) {
+        self.max_value_depth = depth;
+    }
+
+    /// How the tensors are printed, like the `set_print_options` native. The
+    /// options are shared by the VMs of a thread
+    pub fn set_print_options(&mut self, options: PrintOptions) {
+        tensor::set_print_options(options);
+    }
+
+    /// Adds the number of instructions run and the time spent to the runtime
+    /// errors, like "... (after 1200 instructions, 3.10ms)"
+    pub fn set_profile_errors(&mut self, profile_errors: bool) {
+        self.profile_errors = profile_errors;
+    }
+
+    /// Starts the runtime errors with the source line of the instruction that
+    /// failed, like "[line 3] Undefined variable 'b'"
+    pub fn set_error_lines(&mut self, error_lines: bool) {
+        self.error_lines = error_lines;
+    }
+
+    /// Seeds the random number generator of `randn` and `shuffle`, which is
+    /// shared by the VMs of a thread
+    pub fn set_seed(&mut self, seed: u64) {
+        native::seed(seed);
+    }
+
+    /// Counts the calls of each tensor method and the shape it last ran on, see
+    /// `tensor_op_report`. Turning it on starts the counts over
+    pub fn set_profile_tensor_ops(&mut self, profile: bool) {
+        self.tensor_ops = profile.then(BTreeMap::new);
+    }
+
+    /// The counts of `set_profile_tensor_ops`, `None` when it is off
+    pub fn tensor_op_stats(&self) -> Option<&BTreeMap<&'static str, TensorOpStats>> {
+        self.tensor_ops.as_ref()
+    }
+
+    /// The counts of `set_profile_tensor_ops` as a table, one method per line
+    pub fn tensor_op_report(&self) -> Option<String> {
+        let stats = self.tensor_ops.as_ref()?;
+        let mut report = format!("{:<12} {:>8}  last shape", "tensor op", "calls");
+        for (name, op) in stats {
+            report.push_str(&format!("\n{:<12} {:>8}  {:?}", name, op.calls, op.shape));
+        }
+        Some(report)
+    }
+
+    /// Makes `chunk` the next program to run, for a program compiled a part at a
+    /// time (like REPL lines) against this VM's `interner`. The globals are kept,
+    /// the stack and call frames start over
+    pub fn load(&mut self, chunk: Chunk) {
+        self.globals.resize(chunk.global_names.len(), None);
+        self.chunk = chunk;
+        self.find_method_slots();
+        self.function = None;
+        self.ip = 0;
+        self.slot_base = 0;
+        self.stack_top = 0;
+        self.call_frames.clear();
+        self.handlers.clear();
+        self.outputs.clear();
+        self.paused = false;
+        self.instructions = 0;
+        self.started = None;
+    }
+
+    /// Clears what a program left behind, so an unrelated one can run next: the
+    /// globals, the stack, the call frames, the instruction pointer, the print
+    /// options and the tensor op counts, and every interned string (except the
+    /// natives' and the prelude's names) when `clear_interner` is set. The chunk
+    /// stays, give the VM the next one with `load`. Nothing is reallocated
+    pub fn reset(&mut self, clear_interner: bool) {
+        self.globals.clear();
+        self.stack.fill(ValueType::Nil);
+        self.stack_top = 0;
+        self.function = None;
+        self.ip = 0;
+        self.slot_base = 0;
+        self.call_frames.clear();
+        self.handlers.clear();
+        self.outputs.clear();
+        self.paused = false;
+        self.instructions = 0;
+        self.started = None;
+        if let Some(stats) = &mut self.tensor_ops {
+            stats.clear();
+        }
+        tensor::set_print_options(PrintOptions::default());
+
+        if clear_interner {
+            // the registered natives are kept, under their names interned again
+            let registered: Vec<(String, NativeFn)> = self
+                .registered
+                .drain()
+                .map(|(name, function)| (self.interner.lookup(name).to_string(), function))
+                .collect();
+            self.interner.clear();
+            self.natives.clear();
+            register_natives(&mut self.natives, &mut self.interner, self.capabilities);
+            for (name, function) in registered {
+                let name = self.interner.intern_string(name);
+                self.registered.insert(name, function);
+            }
+            if !self.prelude.is_empty() {
+                self.prelude = compile_prelude(&mut self.interner);
+            }
         }
     }
 
     pub fn run(&mut self) -> Result {
-        let mut print_outputs: Vec<String> = Vec::new();
+        let result = self.run_chunk();
+        match self.output.as_mut().map(|output| output.flush()) {
+            Some(Err(e)) if matches!(result, Result::Ok(..)) => {
+                Result::RuntimeErr(format!("Can't write the output: {}", e))
+            }
+            _ => result,
+        }
+    }
+
+    fn run_chunk(&mut self) -> Result {
+        if self.verify {
+            if let Err(e) = self.chunk.verify() {
+                return Result::CompileErr(format!("Invalid bytecode: {}", e));
+            }
+        }
+
+        if self.profile_errors && self.started.is_none() {
+            self.started = Some(std::time::Instant::now());
+        }
+
+        loop {
+            // the line is only looked up when there are breakpoints to stop at
+            if !std::mem::take(&mut self.paused) && !self.breakpoints.is_empty() {
+                let line = self.current_chunk().starts_line(self.ip);
+                if let Some(line) = line.filter(|line| self.breakpoints.contains(line)) {
+                    self.paused = true;
+                    return Result::Paused(line);
+                }
+            }
+
+            match self.step() {
+                Step::Ran(_) => {
+                    if let Some((slot, old)) = self.changed.take() {
+                        let new = self.globals[slot].as_ref().unwrap();
+                        return Result::Changed {
+                            name: self.global_name(slot).to_string(),
+                            old: old.map(|old| old.display(&self.interner)),
+                            new: new.display(&self.interner),
+                        };
+                    }
+                }
+                Step::Halted => {
+                    // the compiler leaves the value of a last expression statement
+                    let value = match self.stack_top {
+                        0 => ValueType::Nil,
+                        _ => self.pop().unwrap_or(ValueType::Nil),
+                    };
+                    return Result::Ok(std::mem::take(&mut self.outputs), value);
+                }
+                Step::Error(e) if self.catch(&e) => {}
+                Step::Error(mut e) => {
+                    if self.error_lines {
+                        e = self.locate_error(e);
+                    }
+                    if self.profile_errors {
+                        let elapsed = self.started.map(|started| started.elapsed());
+                        e = format!(
+                            "{} (after {} instructions, {:.2?})",
+                            e,
+                            self.instructions,
+                            elapsed.unwrap_or_default()
+                        );
+                    }
+                    return Result::RuntimeErr(e);
+                }
+            }
+        }
+    }
+
+    /// Unwinds to the innermost `try` with the thrown value or the message of the
+    /// error on the stack, false when no `try` is running
+    fn catch(&mut self, e: &str) -> bool {
+        let thrown = self.thrown.take();
+        let Some(handler) = self.handlers.pop() else {
+            return false;
+        };
+        self.call_frames.truncate(handler.frames);
+        self.function = handler.function;
+        self.ip = handler.ip;
+        self.slot_base = handler.slot_base;
+        self.stack_top = handler.stack_top;
+
+        let was_thrown = thrown.is_some();
+        let value =
+            thrown.unwrap_or_else(|| ValueType::String(self.interner.intern_string(e.to_string())));
+        if self.push(value).is_err() {
+            return false;
+        }
+        !handler.finally || self.push(ValueType::Boolean(was_thrown)).is_ok()
+    }
+
+    /// Raises `value` as the error of a `throw`
+    fn throw(&mut self, value: ValueType) -> Step {
+        let text = match &value {
+            ValueType::String(idx) => self.interner.lookup(*idx).to_string(),
+            value => value.display(&self.interner),
+        };
+        self.thrown = Some(value);
+        Step::Error(format!("Uncaught {}", text))
+    }
 
+    /// `[line 3] e` where line 3 has the instruction that failed
+    fn locate_error(&self, e: String) -> String {
+        match self.current_chunk().line_of(self.ip.saturating_sub(1)) {
+            Some(line) => format!("[line {}] {}", line, e),
+            None => e,
+        }
+    }
+
+    /// Makes `run` pause before running the code of `line`, each time the line is
+    /// reached (like every iteration of a loop)
+    pub fn add_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn remove_breakpoint(&mut self, line: usize) {
+        self.breakpoints.remove(&line);
+    }
+
+    /// Makes `run` stop right after the global `name` is defined or set to a
+    /// different value
+    pub fn watch(&mut self, name: &str) {
+        self.watched.insert(name.to_string());
+    }
+
+    pub fn unwatch(&mut self, name: &str) {
+        self.watched.remove(name);
+    }
+
+    /// Runs the next instruction only, for a debugger stepping through a program.
+    /// The chunk isn't verified, the `print`s are collected until the program halts
+    pub fn step(&mut self) -> Step {
+        // a push past the end of the stack or a pop (or peek) of an empty stack stops the
+        // program with an error, instead of panicking on the index
         macro_rules! push {
             ($value:expr) => {
-                self.push($value)
+                if let Err(e) = self.push($value) {
+                    return Step::Error(e);
+                }
             };
         }
 
         macro_rules! pop {
             () => {
-                self.pop()
+                match self.pop() {
+                    Ok(value) => value,
+                    Err(e) => return Step::Error(e),
+                }
+            };
+        }
+
+        macro_rules! peek {
+            ($distance:expr) => {
+                match self.peek($distance) {
+                    Ok(value) => value,
+                    Err(e) => return Step::Error(e),
+                }
             };
         }
 
         macro_rules! opcode {
             ($op:ident) => {
-                chunk::VectorType::Code(chunk::OpCode::$op)
+                chunk::OpCode::$op
             };
         }
 
+        // the constant whose index is the next operand
         macro_rules! get_constant {
-            ($index:expr) => {
-                match $index {
-                    chunk::VectorType::Constant(idx) => self.read_constant(idx as usize),
-                    _ => {
-                        return Result::RuntimeErr(format!("Invalid constant '{}'", $index));
+            () => {{
+                let index = self.read_short();
+                self.read_constant(index)
+            }};
+        }
+
+        macro_rules! read_slot {
+            () => {
+                match self.read_short() {
+                    slot if slot < self.globals.len() => slot,
+                    slot => {
+                        return Step::Error(format!("Invalid global slot '{}'", slot));
                     }
                 }
             };
         }
 
-        loop {
-            let instruction = self.read_byte();
-
-            match instruction {
-                opcode!(OpReturn) => {
-                    return Result::Ok(print_outputs);
-                }
-                opcode!(OpAdd) => {
-                    if let ValueType::String(_) = self.peek(0) {
-                        self.concatenate();
-                    } else {
-                        let b = pop!();
-                        let a = pop!();
-                        push!(a + b);
+        // arithmetic errors (mismatched types or shapes) stop the program
+        macro_rules! binary_op {
+            ($op:tt) => {{
+                let b = pop!();
+                let a = pop!();
+                match a $op b {
+                    Ok(value) => push!(value),
+                    Err(e) => return Step::Error(e),
+                }
+            }};
+        }
+
+        // running off the end of the code or into a byte that isn't an opcode
+        // (like an operand, after a jump into the middle of an instruction)
+        // is a bug in the chunk, it stops the program instead of going on
+        self.changed = None;
+        self.instructions += 1;
+        if let Some(max_steps) = self.max_steps.filter(|max| self.instructions > *max) {
+            return Step::Error(format!(
+                "Step limit exceeded, more than {} instructions",
+                max_steps
+            ));
+        }
+        let byte = self.current_chunk().code.get(self.ip).copied();
+        let Some(Ok(instruction)) = byte.map(chunk::OpCode::try_from) else {
+            return Step::Error("invalid instruction".to_string());
+        };
+        self.ip += 1;
+
+        match instruction {
+            opcode!(OpReturn) => {
+                let Some(frame) = self.call_frames.pop() else {
+                    // the end of the script
+                    return Step::Halted;
+                };
+
+                // drop the arguments and locals, and resume the caller. The
+                // `try`s the function returned from are over
+                let frames = self.call_frames.len();
+                self.handlers.retain(|handler| handler.frames <= frames);
+                let result = pop!();
+                self.stack_top = self.slot_base;
+                self.function = frame.function;
+                self.ip = frame.ip;
+                self.slot_base = frame.slot_base;
+                push!(result);
+            }
+            opcode!(OpAdd) => {
+                if let ValueType::String(_) = peek!(0) {
+                    if let Err(e) = self.concatenate() {
+                        return Step::Error(e);
                     }
+                } else {
+                    binary_op!(+);
+                }
+            }
+            opcode!(OpSubtract) => binary_op!(-),
+            opcode!(OpMultiply) => binary_op!(*),
+            opcode!(OpDivide) => binary_op!(/),
+            opcode!(OpModulo) => binary_op!(%),
+            opcode!(OpPower) => {
+                let b = pop!();
+                let a = pop!();
+                match a.pow(&b) {
+                    Ok(value) => push!(value),
+                    Err(e) => return Step::Error(e),
+                }
+            }
+            opcode!(OpMatMul) => {
+                let b = pop!();
+                let a = pop!();
+                match a.matmul(&b) {
+                    Ok(value) => push!(value),
+                    Err(e) => return Step::Error(e),
+                }
+            }
+            opcode!(OpNegate) => {
+                let value = pop!();
+                match -value {
+                    Ok(value) => push!(value),
+                    Err(e) => return Step::Error(e),
+                }
+            }
+            opcode!(OpNil) => push!(ValueType::Nil),
+            opcode!(OpTrue) => push!(ValueType::Boolean(true)),
+            opcode!(OpFalse) => push!(ValueType::Boolean(false)),
+            opcode!(OpNot) => {
+                let value = pop!();
+                push!(!value)
+            }
+            opcode!(OpEqualEqual) => {
+                let b = pop!();
+                let a = pop!();
+                push!(ValueType::Boolean(a == b));
+            }
+            opcode!(OpGreater) => {
+                let b = pop!();
+                let a = pop!();
+                match a.greater(&b) {
+                    Ok(value) => push!(value),
+                    Err(e) => return Step::Error(e),
+                }
+            }
+            opcode!(OpLess) => {
+                let b = pop!();
+                let a = pop!();
+                match a.less(&b) {
+                    Ok(value) => push!(value),
+                    Err(e) => return Step::Error(e),
+                }
+            }
+            opcode!(OpToString) => {
+                let text = pop!().to_text(&self.interner);
+                let text = self.interner.intern_string(text);
+                push!(ValueType::String(text));
+            }
+            // every argument of the `print` is printed on one line
+            opcode!(OpPrint) => {
+                let count = self.read_byte() as usize;
+                if count > self.stack_top {
+                    return Step::Error("Stack underflow".to_string());
                 }
-                opcode!(OpSubtract) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(a - b);
-                }
-                opcode!(OpMultiply) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(a * b);
-                }
-                opcode!(OpDivide) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(a / b);
-                }
-                opcode!(OpPower) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(a.pow(&b));
-                }
-                opcode!(OpNegate) => {
-                    let value = pop!();
-                    push!(-value);
-                }
-                opcode!(OpNil) => push!(ValueType::Nil),
-                opcode!(OpTrue) => push!(ValueType::Boolean(true)),
-                opcode!(OpFalse) => push!(ValueType::Boolean(false)),
-                opcode!(OpNot) => {
-                    let value = pop!();
-                    push!(!value)
-                }
-                opcode!(OpEqualEqual) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(ValueType::Boolean(a == b));
-                }
-                // TODO: Not working for now
-                opcode!(OpGreater) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(ValueType::Boolean(a > b));
-                }
-                opcode!(OpLess) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(ValueType::Boolean(a < b));
-                }
-                opcode!(OpPrint) => {
-                    let value = pop!();
-
-                    print_outputs.push(value.display(&self.interner));
-                    println!("{}", value.display(&self.interner));
-                }
-                opcode!(OpPop) => {
-                    pop!();
-                }
-                opcode!(OpConstant) => {
-                    let constant = get_constant!(self.read_byte());
-                    push!(constant);
-                }
-                opcode!(OpJumpIfFalse) => {
-                    self.read_byte();
-                    let offset = self.read_byte();
-                    let value = self.peek(0);
-
-                    if let ValueType::Boolean(false) = value {
-                        if let VectorType::Constant(idx) = offset {
-                            if let ValueType::JumpOffset(offset) = self.read_constant(idx as usize)
-                            {
-                                self.ip = offset;
-                            }
+                let line = display_all(
+                    &self.stack[self.stack_top - count..self.stack_top],
+                    &self.interner,
+                );
+                self.stack_top -= count;
+
+                if self
+                    .output_limit
+                    .is_some_and(|limit| self.outputs.len() >= limit)
+                {
+                    return Step::Error("output limit exceeded".to_string());
+                }
+
+                match self.output.as_mut() {
+                    Some(output) => {
+                        if let Err(e) = writeln!(output, "{}", line) {
+                            return Step::Error(format!("Can't write the output: {}", e));
                         }
                     }
+                    None => println!("{}", line),
                 }
-                opcode!(OpJump) => {
-                    self.read_byte();
-                    let offset = self.read_byte();
-                    if let VectorType::Constant(idx) = offset {
-                        if let ValueType::JumpOffset(offset) = self.read_constant(idx as usize) {
-                            self.ip = offset
-                        }
+                self.outputs.push(line);
+            }
+            opcode!(OpPop) => {
+                pop!();
+            }
+            opcode!(OpBuildArray) => {
+                let count = self.read_short();
+                if count > self.stack_top {
+                    return Step::Error("Stack underflow".to_string());
+                }
+
+                let elements = self.stack[self.stack_top - count..self.stack_top].to_vec();
+                self.stack_top -= count;
+                let array = ValueType::Array(Rc::new(RefCell::new(elements)));
+                // the elements are within the limit, so this doesn't recurse past it
+                if array.depth() > self.max_value_depth {
+                    return Step::Error(format!(
+                        "Arrays can't be nested more than {} deep",
+                        self.max_value_depth
+                    ));
+                }
+                push!(array);
+            }
+            opcode!(OpConstant) => {
+                let constant = get_constant!();
+                push!(constant);
+            }
+            opcode!(OpConstantSmall) => {
+                let n = self.read_byte();
+                push!(ValueType::Integer(n as i64));
+            }
+            opcode!(OpJumpIfFalse) => {
+                let target = self.read_short();
+                if peek!(0).is_falsy() {
+                    self.ip = target;
+                }
+            }
+            opcode!(OpJump) | opcode!(OpLoop) => {
+                self.ip = self.read_short();
+            }
+            opcode!(OpTry) | opcode!(OpFinally) => {
+                let ip = self.read_short();
+                self.handlers.push(Handler {
+                    function: self.function.clone(),
+                    frames: self.call_frames.len(),
+                    ip,
+                    slot_base: self.slot_base,
+                    stack_top: self.stack_top,
+                    finally: instruction == chunk::OpCode::OpFinally,
+                });
+            }
+            opcode!(OpEndTry) => {
+                self.handlers.pop();
+            }
+            opcode!(OpThrow) => {
+                let value = pop!();
+                return self.throw(value);
+            }
+            // the error a `finally` ran for goes on to the next handler
+            opcode!(OpRethrow) => {
+                let was_thrown = pop!();
+                let value = pop!();
+                if was_thrown == ValueType::Boolean(true) {
+                    return self.throw(value);
+                }
+                let ValueType::String(message) = value else {
+                    return Step::Error("Invalid error to rethrow".to_string());
+                };
+                return Step::Error(self.interner.lookup(message).to_string());
+            }
+            opcode!(OpDefineGlobal) => {
+                let slot = read_slot!();
+                if self.strict && self.globals[slot].is_some() {
+                    return Step::Error(format!("'{}' already declared", self.global_name(slot)));
+                }
+                let value = pop!();
+                self.set_global(slot, value);
+            }
+            opcode!(OpGetGlobal) => {
+                let slot = read_slot!();
+                match &self.globals[slot] {
+                    Some(value) => push!(value.clone()),
+                    None => {
+                        return Step::Error(format!(
+                            "Undefined variable '{}'",
+                            self.interner.lookup(self.chunk.global_names[slot])
+                        ));
                     }
                 }
-                opcode!(OpLoop) => {
-                    self.read_byte();
-                    let offset = self.read_byte();
-                    if let VectorType::Constant(idx) = offset {
-                        if let ValueType::JumpOffset(offset) = self.read_constant(idx as usize) {
-                            self.ip = offset
-                        }
+            }
+            opcode!(OpIndex) => {
+                let index = pop!();
+                let value = pop!();
+                match self.index(&value, &index) {
+                    Ok(element) => push!(element),
+                    Err(e) => return Step::Error(e),
+                }
+            }
+            opcode!(OpGetField) => {
+                let member = get_constant!();
+                let ValueType::Identifier(member) = member else {
+                    return Step::Error(format!(
+                        "Invalid field name '{}' ({}), expected an identifier",
+                        member.display(&self.interner),
+                        member.type_name()
+                    ));
+                };
+                let value = pop!();
+                let ValueType::Module(module) = &value else {
+                    return Step::Error(format!(
+                        "Only modules have fields, got {} ({})",
+                        value.display(&self.interner),
+                        value.type_name()
+                    ));
+                };
+                let slot = module.member(member);
+                match slot.and_then(|slot| self.globals[slot].clone()) {
+                    Some(value) => push!(value),
+                    None => {
+                        return Step::Error(format!(
+                            "Module {} has no member '{}'",
+                            module.name,
+                            self.interner.lookup(member)
+                        ));
                     }
                 }
-                opcode!(OpDefineGlobal) => {
-                    let constant = get_constant!(self.read_byte());
-                    let value = self.peek(0);
+            }
+            opcode!(OpSetGlobal) => {
+                let slot = read_slot!();
+                // assigning doesn't declare, the global needs a `let` first
+                if self.globals[slot].is_none() {
+                    return Step::Error(format!("Undefined variable '{}'", self.global_name(slot)));
+                }
+                let value = peek!(0);
+                self.set_global(slot, value);
+            }
+            // the hottest instructions of local-heavy code, the value is copied
+            // between the two stack entries directly instead of through `push`/`peek`
+            opcode!(OpGetLocal) => {
+                let slot = self.slot_base + self.read_byte() as usize;
+                if slot >= self.stack_top {
+                    return Step::Error("Stack underflow".to_string());
+                }
+                if self.stack_top == self.stack.len() {
+                    return Step::Error("Stack overflow".to_string());
+                }
+                self.stack[self.stack_top] = self.stack[slot].clone();
+                self.stack_top += 1;
+            }
+            opcode!(OpSetLocal) => {
+                let slot = self.slot_base + self.read_byte() as usize;
+                if slot >= self.stack_top {
+                    return Step::Error("Stack underflow".to_string());
+                }
+                self.stack[slot] = self.stack[self.stack_top - 1].clone();
+            }
+            opcode!(OpCall) => {
+                let slot = read_slot!();
+                let arg_count = self.read_byte() as usize;
+                if let Err(e) = self.call_global(slot, arg_count) {
+                    return Step::Error(e);
+                }
+            }
+            opcode!(OpCallSpread) => {
+                let slot = read_slot!();
+                let arg_count = self.read_byte() as usize;
+                let value = pop!();
+                let ValueType::Array(elements) = &value else {
+                    return Step::Error(format!(
+                        "Can only spread an array, got {} ({})",
+                        value.display(&self.interner),
+                        value.type_name()
+                    ));
+                };
+                let elements = elements.borrow().clone();
+                let arg_count = arg_count + elements.len();
+                for element in elements {
+                    push!(element);
+                }
+                if let Err(e) = self.call_global(slot, arg_count) {
+                    return Step::Error(e);
+                }
+            }
+            // a method the compiler found by name, see `OpCode::OpCallMethod`
+            opcode!(OpCallMethod) => {
+                let id = self.read_byte() as usize;
+                let arg_count = self.read_byte() as usize;
+                let Some(method) = TENSOR_METHODS.get(id) else {
+                    return Step::Error(format!("Invalid method id {}", id));
+                };
 
-                    if let ValueType::Identifier(idx) = constant {
-                        self.globals.insert(idx, value);
+                // without tensor methods the name is looked up like in `OpCall`
+                let result = match self.capabilities.tensor_methods {
+                    true => self.call_method(id, arg_count),
+                    false => {
+                        let str_idx = self.interner.intern_string(method.name.to_string());
+                        self.call_function_named(self.method_slots[id], str_idx, arg_count)
                     }
+                };
+                if let Err(e) = result {
+                    return Step::Error(e);
+                }
+            }
+            // only natives declare the names of their parameters
+            opcode!(OpCallNamed) => {
+                let callee = get_constant!();
+                let arg_count = self.read_byte() as usize;
+                if arg_count > self.stack_top {
+                    return Step::Error("Stack underflow".to_string());
+                }
+                let names = match get_constant!() {
+                    ValueType::Array(names) => names,
+                    _ => return Step::Error("Invalid keyword names".to_string()),
+                };
 
-                    pop!();
-                }
-                opcode!(OpGetGlobal) => {
-                    let constant = get_constant!(self.read_byte());
-                    match constant {
-                        ValueType::Identifier(idx) => {
-                            let value = self.globals.get(&idx);
-                            if let Some(value) = value {
-                                push!(value.clone());
-                            } else {
-                                return Result::RuntimeErr(format!(
-                                    "Undefined variable '{}'",
-                                    self.interner.lookup(idx)
-                                ));
-                            }
-                        }
-                        _ => {
-                            return Result::RuntimeErr(format!(
-                                "Invalid global variable '{}'",
-                                constant.display(&self.interner)
-                            ));
-                        }
+                // registered natives don't name their parameters
+                let native = match callee {
+                    ValueType::Identifier(idx) if !self.registered.contains_key(&idx) => {
+                        self.natives.get(&idx).copied()
                     }
+                    _ => None,
+                };
+                let Some(native) = native else {
+                    return Step::Error(format!(
+                        "'{}' doesn't take keyword arguments",
+                        callee.display(&self.interner)
+                    ));
+                };
+
+                let names = names.borrow();
+                let names: Vec<&str> = names
+                    .iter()
+                    .map(|name| match name {
+                        ValueType::Identifier(idx) => self.interner.lookup(*idx),
+                        _ => "",
+                    })
+                    .collect();
+                let args = &self.stack[self.stack_top - arg_count..self.stack_top];
+                let args = bind_keywords(native, args, &names);
+                self.stack_top -= arg_count;
+                match args.and_then(|args| native.call(&args, &mut self.interner)) {
+                    Ok(value) => push!(value),
+                    Err(e) => return Step::Error(e),
+                }
+            }
+            // `return f(args)` inside `f`, the arguments take the place of the
+            // current ones and the frame of the current call is reused
+            opcode!(OpTailCall) => {
+                let slot = read_slot!();
+                let arg_count = self.read_byte() as usize;
+                if arg_count > self.stack_top {
+                    return Step::Error("Stack underflow".to_string());
                 }
-                opcode!(OpSetGlobal) => {
-                    let index = self.read_byte();
-                    let constant = get_constant!(index);
 
-                    match constant {
-                        ValueType::Identifier(idx) => {
-                            let value = self.peek(0);
-                            self.globals.insert(idx, value);
-                            // TODO - only set the value if it exists
-                        }
-                        _ => {
-                            return Result::RuntimeErr(format!(
-                                "Invalid global variable '{}'",
-                                constant.display(&self.interner)
-                            ));
-                        }
-                    }
+                let name = self.chunk.global_names[slot];
+                let function = self.function_named(Some(slot), name);
+                let (Some(ValueType::Function(function)), Some(frame)) =
+                    (function, self.call_frames.pop())
+                else {
+                    return Step::Error(format!(
+                        "Undefined function '{}'",
+                        self.interner.lookup(name)
+                    ));
+                };
+
+                // the frame is reused, the compiler never emits this inside a `try`
+                let args_start = self.stack_top - arg_count;
+                for i in 0..arg_count {
+                    self.stack[self.slot_base + i] = self.stack[args_start + i].clone();
                 }
-                opcode!(OpGetLocal) => {
-                    let slot = self.read_byte();
+                self.stack_top = self.slot_base + arg_count;
 
-                    match slot {
-                        VectorType::Constant(idx) => {
-                            let value = self.stack[idx as usize].clone();
-                            push!(value);
-                        }
-                        _ => {
-                            return Result::RuntimeErr(format!("Invalid slot '{}'", slot));
-                        }
-                    }
+                self.function = frame.function;
+                self.ip = frame.ip;
+                self.slot_base = frame.slot_base;
+                if let Err(e) = self.call(function, arg_count) {
+                    return Step::Error(e);
                 }
-                opcode!(OpSetLocal) => {
-                    let slot = self.read_byte();
+            }
+            // the callee is above its arguments
+            opcode!(OpCallValue) => {
+                let arg_count = self.read_byte() as usize;
 
-                    match slot {
-                        VectorType::Constant(idx) => {
-                            let value = self.peek(0);
-                            self.stack[idx as usize] = value;
-                        }
-                        _ => {
-                            return Result::RuntimeErr(format!("Invalid slot '{}'", slot));
+                match pop!() {
+                    ValueType::Function(function) => {
+                        if let Err(e) = self.call(function, arg_count) {
+                            return Step::Error(e);
                         }
                     }
+                    value => {
+                        return Step::Error(format!(
+                            "'{}' is not callable ({})",
+                            value.display(&self.interner),
+                            value.type_name()
+                        ));
+                    }
                 }
-                // opcode!(OpCall) => {
-                //     let callee = self.read_byte();
-                //     let caller = pop!();
-
-                //     let constant = get_constant!(callee);
-                //     let str_idx = match constant {
-                //         ValueType::Identifier(idx) => idx,
-                //         _ => {
-                //             return Result::RuntimeErr("Invalid function".to_string());
-                //         }
-                //     };
-                //     let calle_str = self.interner.lookup(str_idx);
-
-                //     let tensor = match caller {
-                //         ValueType::Tensor(tensor) => tensor,
-                //         _ => {
-                //             return Result::RuntimeErr("Invalid function".to_string());
-                //         }
-                //     };
-
-                //     match calle_str {
-                //         "relu" => push!(ValueType::Tensor(Tensor::from(tensor.relu()))),
-                //         "backward" => tensor.backward(),
-                //         "grad" => push!(ValueType::Tensor(Tensor::from(tensor.gradient()))),
-                //         _ => {
-                //             return Result::RuntimeErr("Undefined function. Currently only supports relu, backward and grad".to_string());
-                //         }
-                //     }
-                // }
-                _ => {
-                    return {
-                        if let chunk::VectorType::Constant(idx) = instruction {
-                            let value = self.read_constant(idx as usize);
-                            println!("Constant: {:?}", value);
-                        }
+            }
+            // `OpDefineLocal` is never emitted, a local is defined by leaving its value on the stack
+            opcode!(OpDefineLocal) => {
+                return Step::Error("invalid instruction".to_string());
+            }
+        }
 
-                        Result::RuntimeErr(format!("Invalid opcode '{}'", instruction))
-                    };
-                }
+        Step::Ran(instruction)
+    }
+
+    /// Runs the tensor method on the receiver and arguments, the last `arg_count`
+    /// values on the stack, and leaves its result in their place
+    fn call_method(&mut self, id: usize, arg_count: usize) -> std::result::Result<(), String> {
+        if arg_count > self.stack_top {
+            return Err("Stack underflow".to_string());
+        }
+        // a function the program defines takes the place of the method of its name
+        let global = self.method_slots[id].and_then(|slot| self.globals[slot].as_ref());
+        if let Some(ValueType::Function(function)) = global {
+            return self.call(function.clone(), arg_count);
+        }
+        let method = &TENSOR_METHODS[id];
+
+        // the receiver comes before the arguments
+        if arg_count == 0 {
+            return Err(format!(
+                "{} is a tensor method, call it like x.{}()",
+                method.name, method.name
+            ));
+        }
+        let args = self.stack[self.stack_top - arg_count + 1..self.stack_top].to_vec();
+        self.stack_top -= arg_count - 1;
+        let caller = self.pop()?;
+
+        // numbers are lifted to scalar tensors, so `(a + b).relu()` works on plain numbers
+        let Some(tensor) = caller.to_tensor() else {
+            return Err(format!(
+                "{} can't be called on '{}' ({})",
+                method.name,
+                caller.display(&self.interner),
+                caller.type_name()
+            ));
+        };
+
+        if let Some(stats) = &mut self.tensor_ops {
+            let op = stats.entry(method.name).or_default();
+            op.calls += 1;
+            op.shape = tensor.shape();
+        }
+        let value = method.call(&tensor, &args)?;
+        self.push(value)
+    }
+
+    /// Calls the native, tensor method or function named like the global in
+    /// `slot` with the last `arg_count` values on the stack
+    fn call_global(&mut self, slot: usize, arg_count: usize) -> std::result::Result<(), String> {
+        if arg_count > self.stack_top {
+            return Err("Stack underflow".to_string());
+        }
+        // a function the program defines takes the place of the native of its name
+        if let Some(ValueType::Function(function)) = &self.globals[slot] {
+            return self.call(function.clone(), arg_count);
+        }
+        let str_idx = self.chunk.global_names[slot];
+        if let Some(function) = self.registered.get(&str_idx).copied() {
+            let args = self.stack[self.stack_top - arg_count..self.stack_top].to_vec();
+            self.stack_top -= arg_count;
+            let value = function(&args, &mut self.interner)?;
+            return self.push(value);
+        }
+        if let Some(native) = self.natives.get(&str_idx).copied() {
+            let args = self.stack[self.stack_top - arg_count..self.stack_top].to_vec();
+            self.stack_top -= arg_count;
+            let value = native.call(&args, &mut self.interner)?;
+            return self.push(value);
+        }
+
+        let method = self.methods.get(self.interner.lookup(str_idx)).copied();
+        match method {
+            Some(id) => self.call_method(id, arg_count),
+            None => self.call_function_named(Some(slot), str_idx, arg_count),
+        }
+    }
+
+    /// Calls the function `name`, whose global is in `slot` if the program uses
+    /// the name, or explains why there is none to call
+    fn call_function_named(
+        &mut self,
+        slot: Option<usize>,
+        name: StringObjIdx,
+        arg_count: usize,
+    ) -> std::result::Result<(), String> {
+        match self.function_named(slot, name) {
+            Some(ValueType::Function(function)) => return self.call(function, arg_count),
+            // `a()` where `a` is a global holding a value that isn't a function
+            Some(value) => {
+                return Err(format!(
+                    "'{}' is not callable ({})",
+                    value.display(&self.interner),
+                    value.type_name()
+                ))
             }
+            None => {}
+        }
+
+        let name = self.interner.lookup(name);
+        let is_method = TENSOR_METHODS.iter().any(|method| method.name == name);
+        let is_native = NATIVES.iter().any(|native| native.name == name);
+        if is_method || is_native {
+            return Err(format!(
+                "Undefined function '{}', builtins are disabled (--no-std)",
+                name
+            ));
         }
+        Err(format!("Undefined function '{}'", name))
     }
 
-    fn read_byte(&mut self) -> VectorType {
-        let byte = self.chunk.code[self.ip].clone();
+    /// Finds the globals named like the tensor methods, once per chunk instead
+    /// of on every method call
+    fn find_method_slots(&mut self) {
+        self.method_slots = TENSOR_METHODS
+            .iter()
+            .map(|method| {
+                self.chunk
+                    .global_names
+                    .iter()
+                    .position(|&n| self.interner.lookup(n) == method.name)
+            })
+            .collect();
+    }
+
+    /// What a call of `name` runs: the global in `slot`, or else the prelude
+    /// function of that name when the global is undefined
+    fn function_named(&self, slot: Option<usize>, name: StringObjIdx) -> Option<ValueType> {
+        match slot.and_then(|slot| self.globals[slot].clone()) {
+            Some(value) => Some(value),
+            None => self.prelude.get(&name).cloned().map(ValueType::Function),
+        }
+    }
+
+    /// Offset of the next instruction in the chunk being run
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// The value `distance` below the top of the stack, `None` past the bottom
+    pub fn peek_stack(&self, distance: usize) -> Option<&ValueType> {
+        self.stack[..self.stack_top].iter().rev().nth(distance)
+    }
+
+    /// The value of the global `name`, `None` if it isn't defined (yet)
+    pub fn global(&self, name: &str) -> Option<&ValueType> {
+        let slot = self
+            .chunk
+            .global_names
+            .iter()
+            .position(|&idx| self.interner.lookup(idx) == name)?;
+        self.globals.get(slot)?.as_ref()
+    }
+
+    fn global_name(&self, slot: usize) -> &str {
+        self.interner.lookup(self.chunk.global_names[slot])
+    }
+
+    /// Stores a global, noting the change when it is watched
+    fn set_global(&mut self, slot: usize, value: ValueType) {
+        let watched = !self.watched.is_empty() && self.watched.contains(self.global_name(slot));
+        let old = self.globals[slot].replace(value);
+        if watched && old.as_ref() != self.globals[slot].as_ref() {
+            self.changed = Some((slot, old));
+        }
+    }
+
+    /// Starts running `function`, its arguments are the last `arg_count` values
+    /// on the stack and become its first locals
+    fn call(
+        &mut self,
+        function: Rc<Function>,
+        arg_count: usize,
+    ) -> std::result::Result<(), String> {
+        if arg_count > self.stack_top {
+            return Err("Stack underflow".to_string());
+        }
+        if arg_count < function.required() || arg_count > function.arity {
+            return Err(match function.defaults.is_empty() {
+                true => format!(
+                    "{} expects {} arguments, got {}",
+                    function.name, function.arity, arg_count
+                ),
+                false => format!(
+                    "{} expects {} to {} arguments, got {}",
+                    function.name,
+                    function.required(),
+                    function.arity,
+                    arg_count
+                ),
+            });
+        }
+        if self.call_frames.len() == self.max_frames {
+            return Err(format!(
+                "Stack overflow, more than {} nested calls",
+                self.max_frames
+            ));
+        }
+
+        // the parameters left out take their default values
+        let slot_base = self.stack_top - arg_count;
+        for default in &function.defaults[arg_count - function.required()..] {
+            self.push(default.clone())?;
+        }
+
+        self.call_frames.push(CallFrame {
+            function: self.function.replace(function),
+            ip: self.ip,
+            slot_base: self.slot_base,
+        });
+        self.ip = 0;
+        self.slot_base = slot_base;
+        Ok(())
+    }
+
+    /// The chunk of the function being run
+    fn current_chunk(&self) -> &Chunk {
+        match &self.function {
+            Some(function) => &function.chunk,
+            None => &self.chunk,
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let byte = self.current_chunk().read_byte(self.ip);
         self.ip += 1;
-        return byte;
+        byte
+    }
+
+    fn read_short(&mut self) -> usize {
+        let short = self.current_chunk().read_short(self.ip);
+        self.ip += 2;
+        short as usize
     }
 
+    /// Tensor constants are loaded as a fresh leaf every time, so a constant
+    /// evaluated again (e.g. in a loop) doesn't share the graph and gradient of
+    /// the previous evaluation
     fn read_constant(&mut self, index: usize) -> ValueType {
-        self.chunk.constants[index].clone()
+        match &self.current_chunk().constants[index] {
+            ValueType::Tensor(t) => ValueType::Tensor(t.detach()),
+            constant => constant.clone(),
+        }
     }
 
-    fn push(&mut self, value: ValueType) {
+    fn push(&mut self, value: ValueType) -> std::result::Result<(), String> {
+        if self.stack_top == self.stack.len() {
+            return Err("Stack overflow".to_string());
+        }
         self.stack[self.stack_top] = value;
         self.stack_top += 1;
+        Ok(())
     }
 
-    fn pop(&mut self) -> ValueType {
+    fn pop(&mut self) -> std::result::Result<ValueType, String> {
+        if self.stack_top == 0 {
+            return Err("Stack underflow".to_string());
+        }
+        // moved out instead of cloned, the entry is dead above the top
         self.stack_top -= 1;
-        self.stack[self.stack_top].clone()
+        Ok(std::mem::replace(
+            &mut self.stack[self.stack_top],
+            ValueType::Nil,
+        ))
     }
 
-    fn peek(&self, distance: usize) -> ValueType {
-        self.stack[self.stack_top - 1 - distance].clone()
+    fn peek(&self, distance: usize) -> std::result::Result<ValueType, String> {
+        if distance >= self.stack_top {
+            return Err("Stack underflow".to_string());
+        }
+        Ok(self.stack[self.stack_top - 1 - distance].clone())
+    }
+
+    /// `value[index]`, an element of an array or a character of a string. Like
+    /// `char_at`, a string is indexed by Unicode scalar values, not bytes
+    fn index(
+        &mut self,
+        value: &ValueType,
+        index: &ValueType,
+    ) -> std::result::Result<ValueType, String> {
+        let ValueType::Integer(i) = *index else {
+            return Err(format!(
+                "Index must be an integer, got {}",
+                index.type_name()
+            ));
+        };
+        match value {
+            ValueType::Array(elements) => {
+                let elements = elements.borrow();
+                let element = usize::try_from(i).ok().and_then(|i| elements.get(i));
+                element.cloned().ok_or_else(|| {
+                    format!(
+                        "Index {} is out of range for an array of {} elements",
+                        i,
+                        elements.len()
+                    )
+                })
+            }
+            ValueType::String(idx) => {
+                let text = self.interner.lookup(*idx);
+                let Some(c) = usize::try_from(i).ok().and_then(|i| text.chars().nth(i)) else {
+                    return Err(format!(
+                        "Index {} is out of range for a string of {} characters",
+                        i,
+                        text.chars().count()
+                    ));
+                };
+                Ok(ValueType::String(
+                    self.interner.intern_string(c.to_string()),
+                ))
+            }
+            value => Err(format!(
+                "Only arrays and strings can be indexed, got {} ({})",
+                value.display(&self.interner),
+                value.type_name()
+            )),
+        }
     }
 
-    fn concatenate(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
+    fn concatenate(&mut self) -> std::result::Result<(), String> {
+        let b = self.pop()?;
+        let a = self.pop()?;
 
         if let ValueType::String(a) = a {
             if let ValueType::String(b) = b {
@@ -354,8 +1408,2760 @@ impl VM {
                 let a_str = self.interner.lookup(a);
                 let res = a_str.to_owned() + b_str;
                 let res_idx = self.interner.intern_string(res);
-                self.push(ValueType::String(res_idx));
+                self.push(ValueType::String(res_idx))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Registers the natives `capabilities` allows, under their interned names
+fn register_natives(
+    natives: &mut HashMap<StringObjIdx, &'static Native>,
+    interner: &mut Interner,
+    capabilities: Capabilities,
+) {
+    if capabilities.natives {
+        let names: Vec<&str> = NATIVES.iter().map(|native| native.name).collect();
+        natives.extend(interner.intern_all(&names).into_iter().zip(NATIVES));
+    }
+}
+
+/// The functions `PRELUDE` defines, by their interned names. They are taken
+/// from the compiled chunk without running it, so they can only use their
+/// parameters and their locals: the global slots a call takes would be the
+/// prelude chunk's, not the program's
+fn compile_prelude(interner: &mut Interner) -> HashMap<StringObjIdx, Rc<Function>> {
+    let chunk = compiler::compile(PRELUDE, interner).expect("the prelude compiles");
+    chunk
+        .constants
+        .into_iter()
+        .filter_map(|constant| match constant {
+            ValueType::Function(function) => Some(function),
+            _ => None,
+        })
+        .map(|function| (interner.intern_string(function.name.clone()), function))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, Compiler};
+
+    fn run(src: &str) -> (VM, Result) {
+        let mut interner = Interner::default();
+        let chunk = compile(src, &mut interner).unwrap();
+
+        let mut vm = VM::init(chunk, interner);
+        let result = vm.run();
+        (vm, result)
+    }
+
+    #[test]
+    fn test_backward_statement_keeps_stack_balanced() {
+        let (vm, result) = run(r#"
+        let x = (2.0).relu();
+        x.backward();
+        x.backward();
+        print(x.grad());
+        "#);
+
+        assert_eq!(result, Result::Ok(vec!["1".to_string()], ValueType::Nil));
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_backward_returns_tensor() {
+        let (vm, result) = run(r#"
+        let x = (3.0).relu();
+        print(x.backward());
+        print(x.backward().grad());
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(vec!["3".to_string(), "1".to_string()], ValueType::Nil)
+        );
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_builtin_calls_leave_one_value() {
+        let (vm, result) = run(r#"
+        let x = (2.0).relu();
+        x.relu();
+        x.backward();
+        x.grad();
+        x.zero_grad();
+        print(x.zero_grad());
+        print(x.grad());
+        x.backward().relu().grad();
+        "#);
+
+        let Result::Ok(outputs, value) = result else {
+            panic!("{:?}", result);
+        };
+        assert_eq!(outputs, vec!["nil".to_string(), "0".to_string()]);
+        // the last statement's value is the result of the program
+        assert_eq!(value.display(&vm.interner), "0");
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_result_value() {
+        let (vm, result) = run("1 + 2");
+        assert_eq!(result, Result::Ok(vec![], ValueType::Integer(3)));
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run("let x = 4\nprint(x)\nx = x * 2.5");
+        assert_eq!(
+            result,
+            Result::Ok(vec!["4".to_string()], ValueType::Float(10.0))
+        );
+
+        // only an expression as the last statement has a value
+        let (_, result) = run("1 + 2\nprint(3)");
+        assert_eq!(result, Result::Ok(vec!["3".to_string()], ValueType::Nil));
+        let (_, result) = run("let x = 1 + 2");
+        assert_eq!(result, Result::Ok(vec![], ValueType::Nil));
+        let (vm, result) = run("let i = 0\nwhile (i < 3) i = i + 1");
+        assert_eq!(result, Result::Ok(vec![], ValueType::Nil));
+        assert_eq!(vm.stack_top, 0);
+
+        let (vm, result) = run("\"${1 + 2}!\"");
+        let Result::Ok(_, value) = result else {
+            panic!("{:?}", result);
+        };
+        assert_eq!(value.display(&vm.interner), "\"3!\"");
+    }
+
+    #[test]
+    fn test_has_grad() {
+        let (_, result) = run(r#"
+        let x = tensor(-1.0);
+        let unused = tensor(1.0);
+        let loss = x.relu() * 2;
+        print(x.has_grad(), unused.has_grad());
+        loss.backward();
+        print(x.grad(), x.has_grad(), unused.grad(), unused.has_grad());
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["false false".to_string(), "0 true 0 false".to_string()],
+                ValueType::Nil
+            )
+        );
+    }
+
+    #[test]
+    fn test_batches() {
+        let (_, result) = run(r#"
+        let data = tensor([[1, 2], [3, 4], [5, 6], [7, 8]]);
+        print(batches(data, 2));
+        print(batches(tensor([1, 2, 3, 4, 5]), 2));
+        print(batches(data, 10));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "[[[1, 2], [3, 4]], [[5, 6], [7, 8]]]".to_string(),
+                    "[[1, 2], [3, 4], [5]]".to_string(),
+                    "[[[1, 2], [3, 4], [5, 6], [7, 8]]]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("batches(tensor([1, 2]), 0);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("batches got a batch size of 0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let src = "print(shuffle(tensor([[1, 10], [2, 20], [3, 30], [4, 40], [5, 50], [6, 60]])));";
+        let rows = |result: Result| match result {
+            Result::Ok(outputs, _) => outputs[0]
+                .trim_matches(['[', ']'])
+                .split("], [")
+                .map(str::to_string)
+                .collect::<Vec<_>>(),
+            result => panic!("{:?}", result),
+        };
+
+        let (mut vm, _) = run("");
+        vm.set_seed(7);
+        let first = rows(run(src).1);
+        vm.set_seed(7);
+        assert_eq!(rows(run(src).1), first);
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            ["1, 10", "2, 20", "3, 30", "4, 40", "5, 50", "6, 60"]
+        );
+
+        let (_, result) = run("shuffle(tensor(1));");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("shuffle can't shuffle a scalar".to_string())
+        );
+    }
+
+    #[test]
+    fn test_log_softmax() {
+        let (_, result) = run(r#"
+        let logits = tensor([[0, 0], [1000, 1000]]);
+        print(log_softmax(logits));
+        let x = tensor([0, 0]);
+        log_softmax(x).backward(tensor([1, 0]));
+        print(x.grad());
+        "#);
+        let ln_half = 0.5f64.ln();
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    format!("[[{0}, {0}], [{0}, {0}]]", ln_half),
+                    "[0.5, -0.5]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("log_softmax(\"logits\");");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("log_softmax expects a tensor, got string".to_string())
+        );
+    }
+
+    #[test]
+    fn test_division() {
+        let (_, result) = run(r#"
+        print(7 / 2, -7 / 2, 7.0 / 2, 1 / 0.5);
+        print(tensor([2, 4]) / 2, tensor([1, 2]) / tensor([0, 4]));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["3 -3 3.5 2".to_string(), "[1, 2] [inf, 0.5]".to_string()],
+                ValueType::Nil
+            )
+        );
+
+        for src in [
+            "1 / 0;",
+            "1.5 / 0;",
+            "1 / 0.0;",
+            "1 / -0.0;",
+            "tensor([1, 2]) / 0;",
+        ] {
+            let (_, result) = run(src);
+            assert_eq!(result, Result::RuntimeErr("Division by zero".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_modulo() {
+        let (vm, result) = run(r#"
+        print(7 % 3, -7 % 3, 7 % -3);
+        print(5.5 % 2, 7 % 2.5, -1.5 % 1);
+        print(1 + 10 % 4 * 3);
+        let i = 10;
+        i = i % 4;
+        print(i);
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "1 -1 1".to_string(),
+                    "1.5 2 -0.5".to_string(),
+                    "7".to_string(),
+                    "2".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        for src in ["1 % 0;", "1.5 % 0;", "1 % 0.0;"] {
+            let (_, result) = run(src);
+            assert_eq!(result, Result::RuntimeErr("Modulo by zero".to_string()));
+        }
+        let (_, result) = run("tensor(1) % 2;");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Operands of % must be numbers. Got: tensor and number".to_string())
+        );
+    }
+
+    #[test]
+    fn test_matmul() {
+        let (vm, result) = run(r#"
+        let a = tensor([[1, 2, 3], [4, 5, 6]]);
+        let b = tensor([[7, 8], [9, 10], [11, 12]]);
+        let c = a @ b;
+        print(c);
+        c.backward(tensor([[1, 1], [1, 1]]));
+        print(a.grad(), b.grad());
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "[[58, 64], [139, 154]]".to_string(),
+                    "[[15, 19, 23], [15, 19, 23]] [[5, 5], [7, 7], [9, 9]]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run("tensor([[1, 2]]) @ tensor([[1, 2]]);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Can't multiply matrices of shape [1, 2] and [1, 2]".to_string())
+        );
+        let (_, result) = run("2 @ tensor([[1]]);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Operands of @ must be tensors. Got: number and tensor".to_string())
+        );
+    }
+
+    #[test]
+    fn test_export_graph() {
+        let path = std::env::temp_dir().join(format!("grad_graph_{}.dot", std::process::id()));
+        let (_, result) = run(&format!(
+            r#"
+            let w = tensor([1, 2]);
+            let loss = (w * w + 1).relu();
+            export_graph(loss, "{}");
+            "#,
+            path.display()
+        ));
+        assert_eq!(result, Result::Ok(vec![], ValueType::Nil));
+
+        // w, w * w, the constant 1, the sum and the relu
+        let dot = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dot.matches("[label=").count(), 5);
+        assert_eq!(dot.matches(" -> ").count(), 5);
+        assert!(dot.contains("[label=\"relu [2]\"]"));
+
+        let (_, result) = run("export_graph(1, \"graph.dot\");");
+        assert_eq!(
+            result,
+            Result::RuntimeErr(
+                "export_graph expects a tensor and a path, got number and string".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_onehot() {
+        let (_, result) = run("let t = onehot(2, 4); print(t, t.has_grad(), onehot(0, 1));");
+        assert_eq!(
+            result,
+            Result::Ok(vec!["[0, 0, 1, 0] false [1]".to_string()], ValueType::Nil)
+        );
+
+        let (_, result) = run("onehot(4, 4);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("onehot index 4 is out of range for 4 classes".to_string())
+        );
+        let (_, result) = run("onehot(-1, 4);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("onehot index -1 is out of range for 4 classes".to_string())
+        );
+    }
+
+    #[test]
+    fn test_set_grad() {
+        let (_, result) = run(r#"
+        let w = tensor([1.0, 2.0]);
+        print(w.has_grad());
+        set_grad(w, tensor([0.5, -1.0]));
+        print(w.grad(), w.has_grad());
+        w.zero_grad();
+        print(w.grad());
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "false".to_string(),
+                    "[0.5, -1] true".to_string(),
+                    "[0, 0]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("set_grad(tensor([1, 2]), tensor([1, 2, 3]));");
+        assert_eq!(
+            result,
+            Result::RuntimeErr(
+                "set_grad got a gradient of shape [3] for a tensor of shape [2]".to_string()
+            )
+        );
+        let (_, result) = run("set_grad(1.0, 2.0);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("set_grad expects a tensor, got number".to_string())
+        );
+    }
+
+    #[test]
+    fn test_min_max_clamp() {
+        let (_, result) = run(r#"
+        print(clamp(-5, 0, 10), clamp(3.5, 0, 10), clamp(12, 0, 10));
+        print(min(2, 1.5), max(2, 1.5), min(-1, 4), max(-1, 4));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["0 3.5 10".to_string(), "1.5 2 -1 4".to_string()],
+                ValueType::Nil
+            )
+        );
+
+        // the program's own `max` is called instead, from where it's defined on
+        let (_, result) = run(r#"
+        print(max(1, 2));
+        fn max(a, b) { return 100; }
+        print(max(1, 2), min(1, 2));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(vec!["2".to_string(), "100 1".to_string()], ValueType::Nil)
+        );
+
+        let (_, result) = run("clamp(1, 10, 0);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("clamp got an empty range, 10 > 0".to_string())
+        );
+        let (_, result) = run("min(1, tensor(2));");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("min expects numbers, got tensor".to_string())
+        );
+    }
+
+    #[test]
+    fn test_approx_eq() {
+        let (_, result) = run(r#"
+        print(0.1 + 0.2 == 0.3, approx_eq(0.1 + 0.2, 0.3, 0.000001));
+        print(approx_eq(1.0, 1.1, 0.000001), approx_eq(1, 1.1, eps = 0.5));
+        print(approx_eq(tensor(1.0, 2.0, 3.0), tensor(1.0, 2.05, 4.0), 0.1));
+        print(approx_eq(tensor(0.5, 0.5), 0.5, 0));
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "false true".to_string(),
+                    "false true".to_string(),
+                    "[1, 1, 0]".to_string(),
+                    "[1, 1]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("approx_eq(1, \"1\", 0.1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr(
+                "approx_eq expects numbers or tensors, got number and string".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_register_native() {
+        let mut interner = Interner::default();
+        let chunk = compile("print(answer(), count(1, [2], \"three\"));", &mut interner).unwrap();
+        let mut vm = VM::with_capabilities(chunk.clone(), interner, Capabilities::NONE);
+        vm.register_native("answer", |_, _| Ok(ValueType::Integer(42)));
+        vm.register_native("count", |args, _| Ok(ValueType::Integer(args.len() as i64)));
+        assert_eq!(
+            vm.run(),
+            Result::Ok(vec!["42 3".to_string()], ValueType::Nil)
+        );
+
+        // an error of the native is a runtime error of the program
+        vm.register_native("answer", |_, _| Err("no answer".to_string()));
+        vm.load(chunk);
+        assert_eq!(vm.run(), Result::RuntimeErr("no answer".to_string()));
+
+        // they are still registered once the interner is cleared
+        vm.reset(true);
+        vm.register_native("answer", |_, _| Ok(ValueType::Integer(42)));
+        let chunk = compile("print(count(answer()));", &mut vm.interner).unwrap();
+        vm.load(chunk);
+        assert_eq!(vm.run(), Result::Ok(vec!["1".to_string()], ValueType::Nil));
+
+        // and they replace a builtin native of the same name
+        let chunk = compile("print(max(1, 2));", &mut vm.interner).unwrap();
+        let mut vm = VM::with_capabilities(chunk, vm.interner, Capabilities::ALL);
+        vm.register_native("max", |_, _| Ok(ValueType::Integer(0)));
+        assert_eq!(vm.run(), Result::Ok(vec!["0".to_string()], ValueType::Nil));
+    }
+
+    #[test]
+    fn test_unknown_builtin() {
+        let (_, result) = run("(2.0).softplus();");
+
+        assert!(matches!(result, Result::RuntimeErr(_)));
+    }
+
+    #[test]
+    fn test_builtin_arity() {
+        let (_, result) = run("let a = 2.0; print(a.relu(3));");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("relu takes no arguments".to_string())
+        );
+
+        let (vm, result) = run("let a = 2.0; print(a.relu());");
+        assert_eq!(result, Result::Ok(vec!["2".to_string()], ValueType::Nil));
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_call_non_callable() {
+        let (_, result) = run("let a = 5; a();");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("'5' is not callable (number)".to_string())
+        );
+
+        let (_, result) = run("true.relu();");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("relu can't be called on 'true' (bool)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_call_bad_global_slot() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(chunk::OpCode::OpCall);
+        chunk.write_short(5);
+        chunk.write(0);
+        chunk.write_op(chunk::OpCode::OpReturn);
+
+        let mut vm = VM::init(chunk, Interner::default());
+        assert_eq!(
+            vm.run(),
+            Result::CompileErr(
+                "Invalid bytecode: Global slot 5 at 0 is out of bounds (0 globals)".to_string()
+            )
+        );
+
+        // the VM checks it again when the chunk isn't verified
+        vm.set_verify(false);
+        assert_eq!(
+            vm.run(),
+            Result::RuntimeErr("Invalid global slot '5'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tensor_constant_is_a_fresh_leaf() {
+        let (_, result) = run(r#"
+        let i = 0;
+        while (i < 2) {
+            let t = tensor(3.0);
+            let u = t * tensor(2.0);
+            u.backward();
+            print(t.grad());
+            i = i + 1;
+        }
+        let a = 4;
+        print(tensor(a));
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["2".to_string(), "2".to_string(), "4".to_string()],
+                ValueType::Nil
+            )
+        );
+    }
+
+    #[test]
+    fn test_graph_size() {
+        let (_, result) = run(r#"
+        let before = graph_size();
+        let a = tensor(1.0);
+        let b = tensor(2.0);
+        let c = a * b + a;
+        print(graph_size() - before);
+        "#);
+
+        // a, b, a * b and (a * b) + a
+        assert_eq!(result, Result::Ok(vec!["4".to_string()], ValueType::Nil));
+
+        let (_, result) = run("graph_size(1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("graph_size takes no arguments".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tensor_values_are_shared() {
+        let (_, result) = run(r#"
+        let a = tensor([1, 2, 3]);
+        let before = graph_size();
+        let i = 0;
+        while (i < 1000) {
+            let b = a;
+            let c = [b, b];
+            i = i + 1;
+        }
+        print(graph_size() - before);
+
+        let b = a;
+        a = a + 1;
+        print(a, b);
+        let c = b;
+        c.backward(tensor([1, 1, 1]));
+        print(b.grad());
+        "#);
+
+        // copying the value around the stack doesn't copy the tensor, an
+        // operation makes a new one and the copies share the gradient
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "0".to_string(),
+                    "[2, 3, 4] [1, 2, 3]".to_string(),
+                    "[1, 1, 1]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+    }
+
+    #[test]
+    fn test_free_graph_keeps_size_bounded() {
+        let train = |free: &str| {
+            let (_, result) = run(&format!(
+                r#"
+                let w = tensor(1.0);
+                let x = tensor(3.0);
+                let i = 0;
+                while (i < 10) {{
+                    let loss = w * x;
+                    loss.backward();
+                    w = w - tensor(0.01) * w.grad();
+                    {}
+                    print(graph_size());
+                    i = i + 1;
+                }}
+                print(w);
+                "#,
+                free
+            ));
+            match result {
+                Result::Ok(out, _) => out,
+                err => panic!("{}", err),
+            }
+        };
+
+        let out = train("free_graph(w);");
+        assert!(out[1..10].iter().all(|size| size == &out[1]));
+        // the data of the parameter is kept, 1 - 10 * 0.01 * 3
+        assert!((out[10].parse::<f64>().unwrap() - 0.7).abs() < 1e-9);
+
+        let out = train("");
+        assert_ne!(out[1], out[9]);
+    }
+
+    #[test]
+    fn test_number_promoted_in_tensor_arithmetic() {
+        let (_, result) = run(r#"
+        let s = tensor(3.0);
+        let x = tensor(2.0);
+        let y = 2 * s * x + 1;
+        y.backward();
+        print(s.grad());
+        print(2 * tensor([[1, 2], [3, 4]]));
+        print(1 + 2 * 3);
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "4".to_string(),
+                    "[[2, 4], [6, 8]]".to_string(),
+                    "7".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("tensor([1, 2]) + tensor([1, 2, 3]);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Can't broadcast tensors of shape [2] and [3]".to_string())
+        );
+
+        // a row is repeated along the first axis, its gradient summed back over it
+        let (_, result) = run(r#"
+        let a = tensor([[1, 2, 3], [4, 5, 6]]);
+        let row = tensor([[1, 0, 2]]);
+        let y = (a - row) / tensor([[1], [2]]);
+        y.backward(tensor([[1, 1, 1], [1, 1, 1]]));
+        print(y, row.grad());
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["[[0, 2, 1], [1.5, 2.5, 2]] [[-1.5, -1.5, -1.5]]".to_string()],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("print(randn(2, 3) * 0 + 1);");
+        assert_eq!(
+            result,
+            Result::Ok(vec!["[[1, 1, 1], [1, 1, 1]]".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_global_repeated_access() {
+        let (vm, result) = run(r#"
+        let total = 0;
+        let step = 2;
+        let i = 0;
+        while (i < 1000) {
+            total = total + step;
+            i = i + 1;
+        }
+        print(total);
+        "#);
+
+        assert_eq!(result, Result::Ok(vec!["2000".to_string()], ValueType::Nil));
+        assert_eq!(vm.chunk.global_names.len(), 3);
+    }
+
+    #[test]
+    fn test_local_repeated_access() {
+        let (vm, result) = run(r#"
+        fn count(n, step) {
+            let total = 0;
+            let i = 0;
+            while (i < n) {
+                let twice = step * 2;
+                {
+                    let step = twice;
+                    total = total + step;
+                }
+                i = i + 1;
+            }
+            return total;
+        }
+        print(count(1000, 1));
+        print(count(3, -2));
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(vec!["2000".to_string(), "-12".to_string()], ValueType::Nil)
+        );
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_local_loop_benchmark() {
+        let (vm, result) = run(r#"
+        fn count(n) {
+            let total = 0;
+            let i = 0;
+            while (i < n) {
+                total = total + i;
+                i = i + 1;
             }
+            return total;
+        }
+        print(count(100000));
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(vec!["4999950000".to_string()], ValueType::Nil)
+        );
+        // each iteration of the loop is 16 instructions, 7 of them local accesses
+        assert_eq!(vm.instructions / 100000, 16);
+    }
+
+    #[test]
+    fn test_global_after_redefinition() {
+        let (_, result) = run(r#"
+        let a = 1;
+        let i = 0;
+        while (i < 2) {
+            print(a);
+            a = "redefined";
+            i = i + 1;
         }
+        let a = 3;
+        print(a);
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "1".to_string(),
+                    "\"redefined\"".to_string(),
+                    "3".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+    }
+
+    #[test]
+    fn test_global_forward_reference() {
+        // `b` has a slot from its first use, reading it before the definition runs is an error
+        let (_, result) = run("let a = 1; print(b); let b = 2;");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Undefined variable 'b'".to_string())
+        );
+
+        let (_, result) = run("let a = 1; let b = a + 1; print(b);");
+        assert_eq!(result, Result::Ok(vec!["2".to_string()], ValueType::Nil));
+    }
+
+    #[test]
+    fn test_assign_undefined_global() {
+        let (vm, result) = run("let a = 1; x = 5;");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Undefined variable 'x'".to_string())
+        );
+        assert_eq!(vm.globals.iter().flatten().count(), 1);
+
+        let (_, result) = run("let x = 1; x = x + 5; print(x);");
+        assert_eq!(result, Result::Ok(vec!["6".to_string()], ValueType::Nil));
+    }
+
+    #[test]
+    fn test_no_std() {
+        let run_no_std = |src: &str| {
+            let mut interner = Interner::default();
+            let chunk = compile(src, &mut interner).unwrap();
+            VM::with_capabilities(chunk, interner, Capabilities::NONE).run()
+        };
+
+        assert_eq!(
+            run_no_std("let a = 2.0; a.relu();"),
+            Result::RuntimeErr(
+                "Undefined function 'relu', builtins are disabled (--no-std)".to_string()
+            )
+        );
+        assert_eq!(
+            run_no_std("let a = randn(2);"),
+            Result::RuntimeErr(
+                "Undefined function 'randn', builtins are disabled (--no-std)".to_string()
+            )
+        );
+        assert_eq!(
+            run_no_std("print(1 + 2);"),
+            Result::Ok(vec!["3".to_string()], ValueType::Nil)
+        );
+        // a method compiled to its id still finds a function of its name
+        assert_eq!(
+            run_no_std("fn relu(x) { return x + 1; } let a = 2; print(a.relu());"),
+            Result::Ok(vec!["3".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_activations() {
+        let (vm, result) = run(r#"
+        let x = tensor([0, 0]);
+        let y = x.sigmoid() + x.tanh();
+        y.backward(tensor([1, 2]));
+        print(x.sigmoid(), x.tanh(), x.grad());
+        print(sigmoid(0), tanh(0));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "[0.5, 0.5] [0, 0] [1.25, 2.5]".to_string(),
+                    "0.5 0".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_sum_mean() {
+        let (vm, result) = run(r#"
+        let pred = tensor([[1, 2], [3, 4]]);
+        let target = tensor([[1, 1], [1, 1]]);
+        let loss = ((pred - target) * (pred - target)).sum();
+        loss.backward();
+        print(loss, pred.grad());
+        let x = tensor([1, 2, 3, 6]);
+        let m = x.mean();
+        m.backward();
+        print(m, x.grad(), mean(2));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "14 [[0, 2], [4, 6]]".to_string(),
+                    "3 [0.25, 0.25, 0.25, 0.25] 2".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        // a function of the program hides the method
+        let (_, result) = run("fn mean(a, b) { return (a + b) / 2; } print(mean(1, 3));");
+        assert_eq!(result, Result::Ok(vec!["2".to_string()], ValueType::Nil));
+    }
+
+    #[test]
+    fn test_method_dispatch() {
+        let (vm, result) = run(r#"
+        let w = tensor([-1, 2]);
+        print(w.relu(), relu(w));
+        let i = 0;
+        let y = w;
+        while (i < 2000) {
+            y = (w * 2).relu();
+            i = i + 1;
+        }
+        y.backward(tensor([1, 1]));
+        print(y, w.grad(), w.has_grad());
+        1.relu(2);
+        "#);
+        assert_eq!(
+            result,
+            Result::RuntimeErr("relu takes no arguments".to_string())
+        );
+        assert_eq!(
+            vm.outputs,
+            vec![
+                "[0, 2] [0, 2]".to_string(),
+                "[0, 4] [0, 2] true".to_string()
+            ]
+        );
+
+        let (_, result) = run("relu();");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("relu is a tensor method, call it like x.relu()".to_string())
+        );
+    }
+
+    #[test]
+    fn test_if_else() {
+        let (vm, result) = run(r#"
+        let a = 3;
+        if (a > 2) print("taken"); else print("not taken");
+        if (a < 2) print("taken"); else print("not taken");
+        if (false) print("dangling");
+        fn nothing() { let x = 1; }
+        if (nothing()) print("nil"); else print("nil is falsy");
+        if (0) print("0 is truthy");
+        if (a > 0) {
+            if (a > 5) print("big"); else if (a > 1) print("medium"); else print("small");
+        } else {
+            print("negative");
+        }
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "\"taken\"".to_string(),
+                    "\"not taken\"".to_string(),
+                    "\"nil is falsy\"".to_string(),
+                    "\"0 is truthy\"".to_string(),
+                    "\"medium\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        // the conditions are popped whichever branch runs
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let (vm, result) = run(r#"
+        let x = 2;
+        let name = "grad";
+        print("x = ${x}");
+        print("${x * 10 + 1} from ${name}, ${[x, true]}");
+        print("costs \${x}, or $5 {x}");
+        print("${-x} and ${x / 4.0}, like print shows them:", -x, x / 4.0);
+        print("${"a"}, ${format("{}-{}", x, "y")} and ${len("}{")}");
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "\"x = 2\"".to_string(),
+                    "\"21 from grad, [2, true]\"".to_string(),
+                    "\"costs ${x}, or $5 {x}\"".to_string(),
+                    "\"-2 and 0.5, like print shows them:\" -2 0.5".to_string(),
+                    "\"a, 2-y and 2\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let diagnostics = compile("print(\"a ${x\");", &mut Interner::default()).unwrap_err();
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 1:7] error: Syntax error: Unterminated '${' in string"
+        );
+        assert!(compile("print(\"${1 2}\");", &mut Interner::default()).is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let (vm, result) = run(r#"
+        let a = 2;
+        let b = 3.5;
+        print(format("{} + {} = {}", a, b, a + b));
+        print(format("{}: {}", "loss", [1, true]));
+        print(format("no placeholders"));
+        // both give a string like any other, not the text `print` shows
+        print(format("{}!", 1) + "x", "v: " + str(5), str("a", [1]) + format("{}", "b"));
+        print(len(format("{}", "ab")), len(str("ab", 1)), str(format("{}", "c"))[0]);
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "\"2 + 3.5 = 5.5\"".to_string(),
+                    "\"loss: [1, true]\"".to_string(),
+                    "\"no placeholders\"".to_string(),
+                    "\"1!x\" \"v: 5\" \"a [1]b\"".to_string(),
+                    "2 4 \"c\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run("format(\"{} and {}\", 1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("format got 2 placeholders but 1 arguments".to_string())
+        );
+        let (_, result) = run("format(\"{}\", 1, 2);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("format got 1 placeholders but 2 arguments".to_string())
+        );
+        let (_, result) = run("format(1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("format expects a string template, got number".to_string())
+        );
+    }
+
+    #[test]
+    fn test_string_len_char_at() {
+        let (vm, result) = run(r#"
+        let s = "grad";
+        print(len(s), s.len(), char_at(s, 0), s.char_at(3));
+        let e = "a🦀b";
+        print(len(e), e.char_at(1), e.char_at(2));
+        print(len(""));
+        let c = "ab" + "cd";
+        print(len(c), c, char_at(c, 2), char_at("abc", 1) + "z", len(char_at(e, 1) + str(5)));
+        print(s[1], e[1], c[3], [1, [2, 3]][1][0], "${s[0]}!");
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "4 4 \"g\" \"d\"".to_string(),
+                    "3 \"🦀\" \"b\"".to_string(),
+                    "0".to_string(),
+                    "4 \"abcd\" \"c\" \"bz\" 2".to_string(),
+                    "\"r\" \"🦀\" \"d\" 2 \"g!\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        for (src, error) in [
+            (
+                "\"a🦀b\"[3];",
+                "Index 3 is out of range for a string of 3 characters",
+            ),
+            (
+                "[1, 2][-1];",
+                "Index -1 is out of range for an array of 2 elements",
+            ),
+            ("[1, 2][0.5];", "Index must be an integer, got number"),
+            (
+                "5[0];",
+                "Only arrays and strings can be indexed, got 5 (number)",
+            ),
+        ] {
+            let (_, result) = run(src);
+            assert_eq!(result, Result::RuntimeErr(error.to_string()), "{}", src);
+        }
+
+        let (_, result) = run("char_at(\"a🦀b\", 3);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr(
+                "char_at index 3 is out of range for a string of 3 characters".to_string()
+            )
+        );
+        let (_, result) = run("char_at(\"ab\", -1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr(
+                "char_at index -1 is out of range for a string of 2 characters".to_string()
+            )
+        );
+        let (_, result) = run("len(1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("len expects a string, got number".to_string())
+        );
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        let (_, result) = run(r#"
+        print(3 >= 3, 3 >= 4, 2.5 >= 1);
+        print(3 <= 3, 4 <= 3, 1 <= 2.5);
+        print(1 != 2, 2 != 2, 2.5 != 2.5);
+        print(1 < 1.5, 2.5 > 3, tensor([1, 2]) >= 2);
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "true false true".to_string(),
+                    "true false true".to_string(),
+                    "true false false".to_string(),
+                    "true false [0, 1]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        for (src, error) in [
+            ("tensor(1) > true;", "Can't compare tensor and bool"),
+            ("true >= 1;", "Can't compare bool and number"),
+            ("\"a\" <= \"b\";", "Can't compare string and string"),
+        ] {
+            assert_eq!(run(src).1, Result::RuntimeErr(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_not() {
+        let (_, result) = run(r#"
+        fn nothing() {}
+        print(!true, !nothing(), !0, !"", !(1 > 2), !!3);
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["false true false false true true".to_string()],
+                ValueType::Nil
+            )
+        );
+    }
+
+    #[test]
+    fn test_and_or() {
+        let (vm, result) = run(r#"
+        fn side(x) { print("evaluated"); return x; }
+        print(false and side(1));
+        print(true and side(2));
+        print(1 or side(3));
+        print(false or side(4));
+        fn nothing() {}
+        print(nothing() or false, 0 and 5);
+        let i = 0;
+        while (i < 10 and i != 3) i = i + 1;
+        print(i);
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "false".to_string(),
+                    "\"evaluated\"".to_string(),
+                    "2".to_string(),
+                    "1".to_string(),
+                    "\"evaluated\"".to_string(),
+                    "4".to_string(),
+                    "false 5".to_string(),
+                    "3".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_while_sum() {
+        let (vm, result) = run(r#"
+        let i = 1;
+        let sum = 0;
+        while (i <= 10) {
+            sum = sum + i;
+            i = i + 1;
+        }
+        print(sum);
+        while (i > 0) i = i - 1;
+        while (false) print("never");
+        print(i);
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(vec!["55".to_string(), "0".to_string()], ValueType::Nil)
+        );
+        // the condition is popped on every iteration and on the way out
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let (vm, result) = run(r#"
+        try {
+            let a = 1;
+            print(a / 0);
+            print("not reached");
+        } catch (e) {
+            print("caught", e);
+        }
+        try { print(undefined); } catch (e) {}
+        fn f(x) {
+            try {
+                return g(x);
+            } catch (e) {
+                return "${e} in f";
+            }
+        }
+        fn g(x) { return x + missing; }
+        let b = 2;
+        try { b = b + 1; } catch (e) { print("no error"); }
+        print(f(1), b);
+        try {
+            try { print(1 / 0); } catch (inner) { print(inner, nope); }
+        } catch (outer) {
+            print(outer);
+        }
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "\"caught\" \"Division by zero\"".to_string(),
+                    "\"Undefined variable 'missing' in f\" 3".to_string(),
+                    "\"Undefined variable 'nope'\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+        assert!(vm.handlers.is_empty());
+
+        // a returning function ends the `try`s it was running
+        let (_, result) = run(r#"
+        fn h() { try { return 1; } catch (e) { print("wrong handler"); } }
+        h();
+        print(1 / 0);
+        "#);
+        assert_eq!(result, Result::RuntimeErr("Division by zero".to_string()));
+
+        let (_, result) = run("try { print(1); } catch (e) {} print(undefined);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Undefined variable 'undefined'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_finally() {
+        let (vm, result) = run(r#"
+        try { print("body"); } catch (e) { print("not run"); } finally { print("finally 1"); }
+        try { print(1 / 0); } catch (e) { print("caught", e); } finally { print("finally 2"); }
+        try {
+            try { throw 7; } finally { let x = 1; print("finally 3", x); }
+        } catch (e) {
+            print("outer", e);
+        }
+        fn f() {
+            try { return "returned"; } finally { print("finally 4"); }
+        }
+        print(f());
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "\"body\"".to_string(),
+                    "\"finally 1\"".to_string(),
+                    "\"caught\" \"Division by zero\"".to_string(),
+                    "\"finally 2\"".to_string(),
+                    "\"finally 3\" 1".to_string(),
+                    "\"outer\" 7".to_string(),
+                    "\"finally 4\"".to_string(),
+                    "\"returned\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+        assert!(vm.handlers.is_empty());
+
+        // an uncaught error, or one raised by the handler, still stops the program
+        let (vm, result) = run("try { print(undefined); } finally { print(\"cleanup\"); }");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Undefined variable 'undefined'".to_string())
+        );
+        assert_eq!(vm.outputs, vec!["\"cleanup\"".to_string()]);
+        let (vm, result) = run(r#"
+        try { throw "first"; } catch (e) { throw "${e} again"; } finally { print("cleanup"); }
+        "#);
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Uncaught first again".to_string())
+        );
+        assert_eq!(vm.outputs, vec!["\"cleanup\"".to_string()]);
+
+        assert!(compile("try { print(1); }", &mut Interner::default()).is_err());
+    }
+
+    #[test]
+    fn test_throw() {
+        let (vm, result) = run(r#"
+        fn check(x) {
+            if (x < 0) throw [x, "negative"];
+            return x;
+        }
+        try {
+            print(check(1));
+            print(check(-2));
+        } catch (e) {
+            print("caught", e);
+        }
+        try { throw "message"; } catch (e) { print("${e}!"); }
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "1".to_string(),
+                    "\"caught\" [-2, \"negative\"]".to_string(),
+                    "\"message!\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run("print(1); throw \"bad input\";");
+        assert_eq!(result, Result::RuntimeErr("Uncaught bad input".to_string()));
+        let (_, result) = run("try { throw 1; } catch (e) { throw e + 1; }");
+        assert_eq!(result, Result::RuntimeErr("Uncaught 2".to_string()));
+    }
+
+    #[test]
+    fn test_defer() {
+        let (vm, result) = run(r#"
+        {
+            defer print("first deferred");
+            defer print("second deferred");
+            print("body");
+        }
+        fn f(x) {
+            defer print("cleanup", x);
+            if (x > 0) {
+                defer print("inner");
+                return x * 10;
+            }
+            print("no early return");
+        }
+        print(f(1));
+        print(f(0));
+        let i = 0;
+        while (i < 2) {
+            defer print("iteration", i);
+            i = i + 1;
+        }
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "\"body\"".to_string(),
+                    "\"second deferred\"".to_string(),
+                    "\"first deferred\"".to_string(),
+                    "\"inner\"".to_string(),
+                    "\"cleanup\" 1".to_string(),
+                    "10".to_string(),
+                    "\"no early return\"".to_string(),
+                    "\"cleanup\" 0".to_string(),
+                    "nil".to_string(),
+                    "\"iteration\" 1".to_string(),
+                    "\"iteration\" 2".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let diagnostics = compile("defer print(1);", &mut Interner::default()).unwrap_err();
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 1:1] error: Can't defer outside of a block."
+        );
+    }
+
+    #[test]
+    fn test_unless_until() {
+        let (vm, result) = run(r#"
+        let a = 3;
+        unless (a > 5) print("small");
+        unless (a > 2) print("never");
+        let i = 0;
+        until (i >= 3) {
+            print(i);
+            i = i + 1;
+        }
+        until (true) print("never");
+        unless (false) until (i == 0) i = i - 1;
+        print(i);
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "\"small\"".to_string(),
+                    "0".to_string(),
+                    "1".to_string(),
+                    "2".to_string(),
+                    "0".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let diagnostics = compile("until i > 0 print(i);", &mut Interner::default()).unwrap_err();
+        assert!(diagnostics[0]
+            .to_string()
+            .contains("to start until condition"));
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let (vm, result) = run(r#"
+        let i = 0;
+        while (true) {
+            let next = i + 1;
+            if (next > 3) break;
+            i = next;
+        }
+        print(i);
+        let j = 0;
+        until (j >= 4) {
+            j = j + 1;
+            let odd = j % 2;
+            if (odd == 1) continue;
+            print(j);
+        }
+        while (true) {
+            defer print("deferred");
+            try {
+                break;
+            } finally {
+                print("finally");
+            }
+        }
+        while (true) {
+            while (true) break;
+            try { break; } catch (e) {}
+        }
+        fn f() {
+            let n = 0;
+            while (n < 10) {
+                n = n + 1;
+                if (n == 2) break;
+            }
+            return n;
+        }
+        print(f());
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "3".to_string(),
+                    "2".to_string(),
+                    "4".to_string(),
+                    "\"finally\"".to_string(),
+                    "\"deferred\"".to_string(),
+                    "2".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        // the locals of the body and the open handlers are left behind
+        assert_eq!(vm.stack_top, 0);
+        assert!(vm.handlers.is_empty());
+
+        let diagnostics =
+            compile("break;\nfn f() { continue; }", &mut Interner::default()).unwrap_err();
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 1:1] error: Can't break outside of a loop."
+        );
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "[line 2:10] error: Can't continue outside of a loop."
+        );
+    }
+
+    #[test]
+    fn test_function_call() {
+        let (vm, result) = run(r#"
+        fn add(a, b) {
+            let c = a + b;
+            return c * 2;
+        }
+        fn nothing() {}
+        print(add(1, 2));
+        print(add(add(1, 1), 3));
+        print(nothing());
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["6".to_string(), "14".to_string(), "nil".to_string()],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run("fn add(a, b) { return a + b; } add(1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("add expects 2 arguments, got 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_recursive_factorial() {
+        let (vm, result) = run(r#"
+        fn factorial(n) {
+            if (n < 2) { return 1; }
+            return n * factorial(n - 1);
+        }
+        fn sub(a, b) { return a - b; }
+        // locals of the caller survive the call
+        fn twice(x) {
+            let before = x;
+            let after = factorial(x);
+            return [before, after, sub(after, before)];
+        }
+        print(factorial(1), factorial(10));
+        print(sub(10, 3), sub(3, 10));
+        print(twice(4));
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "1 3628800".to_string(),
+                    "7 -7".to_string(),
+                    "[4, 24, 20]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+        assert!(vm.call_frames.is_empty());
+    }
+
+    #[test]
+    fn test_deep_recursion_doesnt_grow_rust_stack() {
+        // calls don't recurse in Rust, so recursing up to the frame cap fits the
+        // small stack of this thread
+        let result = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(|| {
+                let src = format!(
+                    r#"
+                    fn count(n) {{
+                        if (n == 0) {{ return 0; }}
+                        return 1 + count(n - 1);
+                    }}
+                    print(count({}));
+                    "#,
+                    FRAMES_MAX - 1
+                );
+                // only the outputs leave the thread, values can't
+                match run(&src).1 {
+                    Result::Ok(outputs, _) => outputs,
+                    result => panic!("{:?}", result),
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(result, vec![(FRAMES_MAX - 1).to_string()]);
+
+        let (_, result) = run("fn forever(n) { return 1 + forever(n + 1); } forever(0);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr(format!(
+                "Stack overflow, more than {} nested calls",
+                FRAMES_MAX
+            ))
+        );
+    }
+
+    #[test]
+    fn test_incremental_compile() {
+        let mut compiler = Compiler::new();
+        let mut vm = VM::init(Chunk::new(), Interner::default());
+        let mut eval = |line: &str| {
+            let chunk = compiler.compile_source(line, &mut vm.interner).unwrap();
+            vm.load(chunk);
+            vm.run()
+        };
+
+        assert_eq!(eval("let a = 2;"), Result::Ok(vec![], ValueType::Nil));
+        assert_eq!(
+            eval("fn double(x) { return x * 2; }"),
+            Result::Ok(vec![], ValueType::Nil)
+        );
+        assert_eq!(
+            eval("let b = double(a);"),
+            Result::Ok(vec![], ValueType::Nil)
+        );
+        assert_eq!(
+            eval("print(a + b);"),
+            Result::Ok(vec!["6".to_string()], ValueType::Nil)
+        );
+        // the natives interned by the VM don't collide with names from later lines
+        assert_eq!(
+            eval("let t = tensor([a, b]); print(t);"),
+            Result::Ok(vec!["[2, 4]".to_string()], ValueType::Nil)
+        );
+        assert_eq!(
+            eval("print(c);"),
+            Result::RuntimeErr("Undefined variable 'c'".to_string())
+        );
+        assert_eq!(
+            eval("print(double(b));"),
+            Result::Ok(vec!["8".to_string()], ValueType::Nil)
+        );
+
+        // a line replaces the code and the constants of the one before, only the
+        // globals and the interned strings are kept
+        let chunk = compiler
+            .compile_source("let big = 1000; print(t, big);", &mut vm.interner)
+            .unwrap();
+        vm.load(chunk);
+        assert_eq!(vm.ip(), 0);
+        assert_eq!(vm.chunk.constants, vec![ValueType::Integer(1000)]);
+        assert_eq!(
+            vm.run(),
+            Result::Ok(vec!["[2, 4] 1000".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_stack_bounds() {
+        // every element is on the stack before the array is built
+        let src = format!("print([{}]);", vec!["1"; STACK_MAX + 1].join(", "));
+        let (vm, result) = run(&src);
+        assert_eq!(result, Result::RuntimeErr("Stack overflow".to_string()));
+        assert_eq!(vm.stack_top, STACK_MAX);
+
+        // bytecode popping more than it pushed
+        for op in [
+            chunk::OpCode::OpPop,
+            chunk::OpCode::OpAdd,
+            chunk::OpCode::OpNegate,
+        ] {
+            let mut chunk = Chunk::new();
+            chunk.write_op(op);
+            chunk.write_op(chunk::OpCode::OpReturn);
+            let mut vm = VM::init(chunk, Interner::default());
+            assert_eq!(vm.run(), Result::RuntimeErr("Stack underflow".to_string()));
+            assert_eq!(vm.stack_top, 0);
+        }
+
+        // and operands counting or addressing values below the top
+        for op in [
+            chunk::OpCode::OpPrint,
+            chunk::OpCode::OpBuildArray,
+            chunk::OpCode::OpGetLocal,
+            chunk::OpCode::OpSetLocal,
+        ] {
+            let mut chunk = Chunk::new();
+            chunk.write_op(op);
+            match op.operands() {
+                [2] => chunk.write_short(1),
+                _ => chunk.write(1),
+            }
+            chunk.write_op(chunk::OpCode::OpReturn);
+            let mut vm = VM::init(chunk, Interner::default());
+            assert_eq!(vm.run(), Result::RuntimeErr("Stack underflow".to_string()));
+        }
+
+        // and calls counting more arguments than there are values
+        fn set_arg_counts(code: &mut [u8], call: chunk::OpCode) -> bool {
+            let mut found = false;
+            let mut offset = 0;
+            while offset < code.len() {
+                let op = chunk::OpCode::try_from(code[offset]).unwrap();
+                if op == call {
+                    // the argument count follows the callee, if there is one
+                    let count_at = match op.operands() {
+                        [_] => offset + 1,
+                        [callee, ..] => offset + 1 + callee,
+                        [] => unreachable!(),
+                    };
+                    code[count_at] = 9;
+                    found = true;
+                }
+                offset += 1 + op.operands().iter().sum::<usize>();
+            }
+            found
+        }
+        for (src, op) in [
+            ("max(1, 2);", chunk::OpCode::OpCall),
+            ("max(...[1, 2]);", chunk::OpCode::OpCallSpread),
+            ("relu(1);", chunk::OpCode::OpCallMethod),
+            ("randn(rows = 1);", chunk::OpCode::OpCallNamed),
+            (
+                "fn f(x) {} { let g = f; g(1); }",
+                chunk::OpCode::OpCallValue,
+            ),
+            ("fn f(n) { return f(n); } f(1);", chunk::OpCode::OpTailCall),
+        ] {
+            let mut interner = Interner::default();
+            let mut chunk = compile(src, &mut interner).unwrap();
+            // a tail call is in the body of `f`
+            let found = chunk.constants.iter_mut().any(|constant| match constant {
+                ValueType::Function(function) => {
+                    set_arg_counts(&mut Rc::get_mut(function).unwrap().chunk.code, op)
+                }
+                _ => false,
+            });
+            assert!(found || set_arg_counts(&mut chunk.code, op), "{}", src);
+            let mut vm = VM::init(chunk, interner);
+            assert_eq!(
+                vm.run(),
+                Result::RuntimeErr("Stack underflow".to_string()),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let run_with = |src: &str, config: VmConfig| {
+            let mut interner = Interner::default();
+            let chunk = compile(src, &mut interner).unwrap();
+            let mut vm = VM::with_config(chunk, interner, Capabilities::ALL, config);
+            vm.run()
+        };
+
+        let config = VmConfig {
+            stack_size: 8,
+            ..VmConfig::default()
+        };
+        assert_eq!(
+            run_with("print([1, 2, 3, 4, 5, 6, 7, 8, 9]);", config),
+            Result::RuntimeErr("Stack overflow".to_string())
+        );
+        assert!(matches!(
+            run_with("print([1, 2, 3]);", config),
+            Result::Ok(..)
+        ));
+
+        let recurse = "fn f(n) { if (n == 0) return 0; return 1 + f(n - 1); } print(f(5));";
+        let config = VmConfig {
+            max_frames: 3,
+            ..VmConfig::default()
+        };
+        assert_eq!(
+            run_with(recurse, config),
+            Result::RuntimeErr("Stack overflow, more than 3 nested calls".to_string())
+        );
+        assert_eq!(
+            run_with(recurse, VmConfig::default()),
+            Result::Ok(vec!["5".to_string()], ValueType::Nil)
+        );
+
+        let config = VmConfig {
+            max_steps: Some(100),
+            ..VmConfig::default()
+        };
+        assert_eq!(
+            run_with("while (true) {}", config),
+            Result::RuntimeErr("Step limit exceeded, more than 100 instructions".to_string())
+        );
+        assert!(matches!(run_with("print(1);", config), Result::Ok(..)));
+
+        let config = VmConfig {
+            seed: Some(7),
+            ..VmConfig::default()
+        };
+        let first = run_with("print(randn(3));", config);
+        assert!(matches!(first, Result::Ok(..)));
+        assert_eq!(run_with("print(randn(3));", config), first);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut vm = VM::init(Chunk::new(), Interner::default());
+        let eval = |vm: &mut VM, src: &str| {
+            let chunk = compile(src, &mut vm.interner).unwrap();
+            vm.load(chunk);
+            vm.run()
+        };
+
+        assert_eq!(
+            eval(&mut vm, "let a = 1; print(a);"),
+            Result::Ok(vec!["1".to_string()], ValueType::Nil)
+        );
+        vm.reset(false);
+        assert_eq!(
+            eval(&mut vm, "print(a);"),
+            Result::RuntimeErr("Undefined variable 'a'".to_string())
+        );
+
+        // a failed run leaves its values on the stack, they are cleared too
+        assert!(matches!(
+            eval(&mut vm, "let b = 2; print(1 + (b + true));"),
+            Result::RuntimeErr(_)
+        ));
+        vm.reset(true);
+        assert_eq!(vm.stack_top, 0);
+        assert!(vm.stack.iter().all(|value| matches!(value, ValueType::Nil)));
+        // only the natives' and the prelude's names are left
+        let fresh = VM::init(Chunk::new(), Interner::default());
+        assert_eq!(vm.interner.map.len(), fresh.interner.map.len());
+        assert_eq!(
+            eval(&mut vm, "print(b);"),
+            Result::RuntimeErr("Undefined variable 'b'".to_string())
+        );
+        assert_eq!(
+            eval(&mut vm, "print(tensor([1, 2]));"),
+            Result::Ok(vec!["[1, 2]".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let mut interner = Interner::default();
+        let chunk = compile(
+            "let i = 0; while (i < 10) { print(i); i = i + 1; }",
+            &mut interner,
+        )
+        .unwrap();
+
+        let mut vm = VM::init(chunk.clone(), interner.clone());
+        vm.set_output_limit(Some(3));
+        assert_eq!(
+            vm.run(),
+            Result::RuntimeErr("output limit exceeded".to_string())
+        );
+
+        let mut vm = VM::init(chunk, interner);
+        vm.set_output_limit(Some(10));
+        assert!(matches!(vm.run(), Result::Ok(outputs, _) if outputs.len() == 10));
+    }
+
+    #[test]
+    fn test_buffered_output() {
+        // a sink whose bytes the test can still read once the VM owns the writer
+        #[derive(Clone, Default)]
+        struct Sink(Rc<RefCell<Vec<u8>>>);
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut interner = Interner::default();
+        let chunk = compile("print(1); print(\"two\", 3);", &mut interner).unwrap();
+        let sink = Sink::default();
+        let mut vm = VM::init(chunk, interner);
+        vm.set_output(std::io::BufWriter::with_capacity(1024, sink.clone()));
+        assert!(matches!(vm.run(), Result::Ok(..)));
+        assert_eq!(sink.0.borrow().as_slice(), b"1\n\"two\" 3\n");
+
+        // what was printed before a runtime error is flushed too
+        let mut interner = Interner::default();
+        let chunk = compile("print(1); print(2 / 0);", &mut interner).unwrap();
+        let sink = Sink::default();
+        let mut vm = VM::init(chunk, interner);
+        vm.set_output(std::io::BufWriter::with_capacity(1024, sink.clone()));
+        assert!(matches!(vm.run(), Result::RuntimeErr(_)));
+        assert_eq!(sink.0.borrow().as_slice(), b"1\n");
+    }
+
+    #[test]
+    fn test_call_function_value() {
+        let (vm, result) = run(r#"
+        fn double(x) { return x * 2; }
+        fn apply(f, x) { return f(x); }
+        let f = double;
+        print(f(3));
+        print(apply(double, 5));
+        {
+            let g = f;
+            print(g(4));
+            print((1.5).g());
+        }
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "6".to_string(),
+                    "10".to_string(),
+                    "8".to_string(),
+                    "3".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run("fn apply(f) { return f(); } apply(2);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("'2' is not callable (number)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_keyword_arguments() {
+        let (_, result) = run(r#"
+        // zeroed to compare the shapes
+        print(randn(rows=2, cols=3) * 0 + 1);
+        print(randn(2, cols=1) * 0 + 1);
+        print(tensor(data=[1, 2]));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "[[1, 1, 1], [1, 1, 1]]".to_string(),
+                    "[[1], [1]]".to_string(),
+                    "[1, 2]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("randn(rows=2, depth=3);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("randn got an unknown keyword argument 'depth'".to_string())
+        );
+        let (_, result) = run("randn(cols=3);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("randn missing argument 'rows'".to_string())
+        );
+        let (_, result) = run("randn(2, rows=3);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("randn got multiple values for 'rows'".to_string())
+        );
+        let (_, result) = run("fn f(x) { return x; } f(x=1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("'f' doesn't take keyword arguments".to_string())
+        );
+    }
+
+    #[test]
+    fn test_variadic_natives() {
+        let (vm, result) = run(r#"
+        let t = tensor(1, 2, 3, 4);
+        print(t);
+        print(t * tensor(1, 2, 3, 4));
+        print(tensor([1, 2], [3, 4]));
+        print(1, 2);
+        print();
+        print(str(1, "a", [true]));
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "[1, 2, 3, 4]".to_string(),
+                    "[1, 4, 9, 16]".to_string(),
+                    "[[1, 2], [3, 4]]".to_string(),
+                    "1 2".to_string(),
+                    "".to_string(),
+                    "\"1 a [true]\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run("tensor();");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("tensor takes at least 1 argument".to_string())
+        );
+        let (_, result) = run("free_graph(tensor(1.0), 2);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("free_graph expects 1 arguments, got 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_default_parameters() {
+        let (vm, result) = run(r#"
+        fn greet(name, greeting = "hello", times = 1) {
+            return str(greeting, name, times);
+        }
+        print(greet("ada"));
+        print(greet("ada", "hi"));
+        print(greet("ada", "hi", 2));
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "\"hello ada 1\"".to_string(),
+                    "\"hi ada 1\"".to_string(),
+                    "\"hi ada 2\"".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run(r#"fn greet(name, greeting = "hello") {} greet();"#);
+        assert_eq!(
+            result,
+            Result::RuntimeErr("greet expects 1 to 2 arguments, got 0".to_string())
+        );
+
+        let diagnostics = compile("fn f(a = 1, b) {}", &mut Interner::default()).unwrap_err();
+        assert_eq!(
+            diagnostics[0].message,
+            "Parameter 'b' without a default follows one with a default"
+        );
+    }
+
+    #[test]
+    fn test_every_opcode_runs() {
+        let (vm, result) = run(r#"
+        fn add(a, b) { return a + b; }
+        fn fact(n, acc) {
+            if (n < 2) { return acc; }
+            return fact(n - 1, acc * n);
+        }
+        fn apply(g, x) {
+            let y = x;
+            y = -y;
+            return g(y, 1);
+        }
+        let total = 0;
+        let i = 0;
+        while (i < 3) {
+            total = total + i;
+            i = i + 1;
+        }
+        print(total);
+        print(fact(5, 1));
+        print(apply(add, 2.5));
+        print(add(...[2, 2]) / 2 ** 2 % 3);
+        print((randn(rows = 1, cols = 2) * 0 + 1).sum());
+        print([true, false, 1 <= 1, 1 == 1, 2 > 1][1]);
+        print("i is ${i}");
+        print((tensor([[1, 2]]) @ tensor([[3], [4]])).relu());
+        try {
+            try { throw "inner"; } finally { print("cleanup"); }
+        } catch (e) {
+            print(e);
+        }
+        try { i.field; } catch (e) { print(e); }
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "3".to_string(),
+                    "120".to_string(),
+                    "-1.5".to_string(),
+                    "1".to_string(),
+                    "2".to_string(),
+                    "false".to_string(),
+                    "\"i is 3\"".to_string(),
+                    "[[11]]".to_string(),
+                    "\"cleanup\"".to_string(),
+                    "\"inner\"".to_string(),
+                    "\"Only modules have fields, got 3 (number)\"".to_string(),
+                ],
+                ValueType::Nil
+            )
+        );
+
+        // the program above compiles to every opcode, `OpDefineLocal` aside
+        let mut chunks = vec![vm.chunk.clone()];
+        for constant in &vm.chunk.constants {
+            if let ValueType::Function(function) = constant {
+                chunks.push(function.chunk.clone());
+            }
+        }
+        let mut ran = HashSet::new();
+        for chunk in &chunks {
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let op = chunk::OpCode::try_from(chunk.code[offset]).unwrap();
+                ran.insert(op as u8);
+                offset += 1 + op.operands().iter().sum::<usize>();
+            }
+        }
+        for op in chunk::OPCODES {
+            if *op != chunk::OpCode::OpDefineLocal {
+                assert!(ran.contains(&(*op as u8)), "{} isn't covered", op);
+            }
+        }
+    }
+
+    #[test]
+    fn test_spread() {
+        let (vm, result) = run(r#"
+        fn f(a, b, c) { return a * 100 + b * 10 + c; }
+        let args = [1, 2, 3];
+        print(f(...args));
+        print(f(4, ...[5, 6]), f(7, 8, 9, ...[]));
+        print(max(...[3, 5]), tensor(-2).relu(...[]));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["123".to_string(), "456 789".to_string(), "5 0".to_string()],
+                ValueType::Nil
+            )
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        let (_, result) = run("fn f(a, b, c) { } f(...[1, 2]);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("f expects 3 arguments, got 2".to_string())
+        );
+        let (_, result) = run("fn f(a) { } f(...2);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Can only spread an array, got 2 (number)".to_string())
+        );
+
+        let diagnostics = compile("f(...[1], 2);", &mut Interner::default()).unwrap_err();
+        assert!(diagnostics[0]
+            .message
+            .contains("A spread '...' must be the last argument"));
+    }
+
+    #[test]
+    fn test_tail_call_reuses_frame() {
+        let (vm, result) = run(r#"
+        fn sum(n, total = 0) {
+            if (n == 0) { return total; }
+            return sum(n - 1, total + n);
+        }
+        print(sum(10000));
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(vec!["50005000".to_string()], ValueType::Nil)
+        );
+        assert_eq!(vm.stack_top, 0);
+
+        // `1 + count(n - 1)` isn't in tail position, it still takes a frame per call
+        let (_, result) = run(r#"
+        fn count(n) {
+            if (n == 0) { return 0; }
+            return 1 + count(n - 1);
+        }
+        count(10000);
+        "#);
+        assert_eq!(
+            result,
+            Result::RuntimeErr(format!(
+                "Stack overflow, more than {} nested calls",
+                FRAMES_MAX
+            ))
+        );
+
+        // a call inside a `try` isn't in tail position, the `catch` stays
+        let (_, result) = run(r#"
+        fn f(n, g) {
+            if (g) {
+                try { return f(n - 1, false); } catch (e) { return "caught"; }
+            }
+            throw "x";
+        }
+        print(f(1, true));
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(vec!["\"caught\"".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_two_byte_operands() {
+        // more than 256 constants and globals, their indices take both bytes
+        let mut src = String::new();
+        for i in 0..300 {
+            src.push_str(&format!("let a{} = {};\n", i, i * 10));
+        }
+        src.push_str("if (a299 > a0) { print(a299, a256); } else { print(a0); }");
+
+        let (vm, result) = run(&src);
+        assert_eq!(
+            result,
+            Result::Ok(vec!["2990 2560".to_string()], ValueType::Nil)
+        );
+        assert_eq!(vm.chunk.global_names.len(), 300);
+        assert!(vm.chunk.constants.len() > 256);
+    }
+
+    #[test]
+    fn test_misaligned_chunk() {
+        // the jump lands on the slot operand of `OpGetLocal`
+        let mut chunk = Chunk::new();
+        chunk.write_op(chunk::OpCode::OpJump);
+        chunk.write_short(4);
+        chunk.write_op(chunk::OpCode::OpGetLocal);
+        chunk.write(200);
+        chunk.write_op(chunk::OpCode::OpReturn);
+
+        let mut vm = VM::init(chunk.clone(), Interner::default());
+        vm.set_verify(true);
+        assert_eq!(
+            vm.run(),
+            Result::CompileErr(
+                "Invalid bytecode: Jump at 0 targets 4, in the middle of an instruction"
+                    .to_string()
+            )
+        );
+
+        let mut vm = VM::init(chunk, Interner::default());
+        vm.set_verify(false);
+        assert_eq!(
+            vm.run(),
+            Result::RuntimeErr("invalid instruction".to_string())
+        );
+
+        // code without a return runs off its end
+        let mut chunk = Chunk::new();
+        chunk.write_op(chunk::OpCode::OpNil);
+        let mut vm = VM::init(chunk, Interner::default());
+        vm.set_verify(false);
+        assert_eq!(
+            vm.run(),
+            Result::RuntimeErr("invalid instruction".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tensor_masks() {
+        let (_, result) = run(r#"
+        let t = tensor(-1, 0, 2);
+        print(t > 0, t <= 0, 1 < t);
+        print(all(t > -2), all(t > 0), any(t > 0), any(t > 5));
+        if (t > -2) { print("all above -2"); }
+        if (t > 0) { print("all positive"); } else { print("not all positive"); }
+        let i = 0;
+        while (tensor(i) < 2) { i = i + 1; }
+        print(i);
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "[0, 0, 1] [1, 1, 0] [0, 0, 1]".to_string(),
+                    "true false true false".to_string(),
+                    "\"all above -2\"".to_string(),
+                    "\"not all positive\"".to_string(),
+                    "2".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("tensor(1, 2) > tensor(1, 2, 3);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Can't broadcast tensors of shape [2] and [3]".to_string())
+        );
+        let (_, result) = run("all(true);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("all expects a tensor, got bool".to_string())
+        );
+    }
+
+    #[test]
+    fn test_step() {
+        let mut interner = Interner::default();
+        let chunk = compile("let a = 1 + 2; print(a);", &mut interner).unwrap();
+        let mut vm = VM::init(chunk, interner);
+
+        assert_eq!(vm.step(), Step::Ran(chunk::OpCode::OpConstantSmall));
+        assert_eq!(vm.ip(), 2);
+        assert_eq!(vm.peek_stack(0), Some(&ValueType::Integer(1)));
+        assert_eq!(vm.step(), Step::Ran(chunk::OpCode::OpConstantSmall));
+        assert_eq!(vm.peek_stack(1), Some(&ValueType::Integer(1)));
+        assert_eq!(vm.step(), Step::Ran(chunk::OpCode::OpAdd));
+        assert_eq!(vm.peek_stack(0), Some(&ValueType::Integer(3)));
+        assert_eq!(vm.peek_stack(1), None);
+        assert_eq!(vm.global("a"), None);
+
+        assert_eq!(vm.step(), Step::Ran(chunk::OpCode::OpDefineGlobal));
+        assert_eq!(vm.peek_stack(0), None);
+        assert_eq!(vm.global("a"), Some(&ValueType::Integer(3)));
+        assert_eq!(vm.step(), Step::Ran(chunk::OpCode::OpGetGlobal));
+        assert_eq!(vm.step(), Step::Ran(chunk::OpCode::OpPrint));
+        assert_eq!(vm.step(), Step::Halted);
+        assert_eq!(vm.ip(), vm.chunk.code.len());
+
+        // running the rest of a program that was stepped into
+        let mut interner = Interner::default();
+        let chunk = compile("print(1); print(2 / true);", &mut interner).unwrap();
+        let mut vm = VM::init(chunk, interner);
+        vm.step();
+        assert_eq!(vm.step(), Step::Ran(chunk::OpCode::OpPrint));
+        assert!(matches!(vm.run(), Result::RuntimeErr(_)));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut interner = Interner::default();
+        let src = "let a = 1;\nlet i = 0;\nwhile (i < 2) {\n    a = a * 10;\n    i = i + 1;\n}\nprint(a);";
+        let chunk = compile(src, &mut interner).unwrap();
+        let mut vm = VM::init(chunk, interner);
+        vm.add_breakpoint(4);
+        vm.add_breakpoint(7);
+
+        assert_eq!(vm.run(), Result::Paused(4));
+        assert_eq!(vm.global("a"), Some(&ValueType::Integer(1)));
+        assert_eq!(vm.peek_stack(0), None);
+
+        // every iteration stops again
+        assert_eq!(vm.run(), Result::Paused(4));
+        assert_eq!(vm.global("a"), Some(&ValueType::Integer(10)));
+        assert_eq!(vm.global("i"), Some(&ValueType::Integer(1)));
+
+        vm.remove_breakpoint(4);
+        assert_eq!(vm.run(), Result::Paused(7));
+        assert_eq!(vm.global("a"), Some(&ValueType::Integer(100)));
+        assert_eq!(
+            vm.run(),
+            Result::Ok(vec!["100".to_string()], ValueType::Nil)
+        );
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut interner = Interner::default();
+        let src = "let a = 1; let i = 0; while (i < 3) { a = a * 2; a = a; i = i + 1; }";
+        let chunk = compile(src, &mut interner).unwrap();
+        let mut vm = VM::init(chunk, interner);
+        vm.watch("a");
+
+        let changed = |old: Option<&str>, new: &str| Result::Changed {
+            name: "a".to_string(),
+            old: old.map(str::to_string),
+            new: new.to_string(),
+        };
+        assert_eq!(vm.run(), changed(None, "1"));
+        assert_eq!(vm.global("i"), None);
+        // `a = a` doesn't change it
+        assert_eq!(vm.run(), changed(Some("1"), "2"));
+        assert_eq!(vm.global("i"), Some(&ValueType::Integer(0)));
+        assert_eq!(vm.run(), changed(Some("2"), "4"));
+        assert_eq!(vm.global("i"), Some(&ValueType::Integer(1)));
+
+        vm.unwatch("a");
+        assert_eq!(vm.run(), Result::Ok(vec![], ValueType::Nil));
+        assert_eq!(vm.global("a"), Some(&ValueType::Integer(8)));
+    }
+
+    #[test]
+    fn test_profile_errors() {
+        let mut interner = Interner::default();
+        let src = "let a = 0; while (a < 3) { a = a + 1; } a + true;";
+        let chunk = compile(src, &mut interner).unwrap();
+        let mut vm = VM::init(chunk, interner);
+        vm.set_profile_errors(true);
+
+        // 2 to define `a`, 5 for each of the 4 checks of the condition, 6 for each
+        // of the 3 iterations, then `a + true` fails on its third instruction
+        let Result::RuntimeErr(e) = vm.run() else {
+            panic!("expected a runtime error");
+        };
+        assert!(
+            e.starts_with("Operands must be numbers or tensors. Got: number and bool (after 43 instructions, "),
+            "{}",
+            e
+        );
+
+        // off by default
+        let (_, result) = run("1 + true;");
+        assert_eq!(
+            result,
+            Result::RuntimeErr(
+                "Operands must be numbers or tensors. Got: number and bool".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_max_value_depth() {
+        let src = "let a = 1; let i = 0; while (i < 5) { a = [a]; i = i + 1; } print(a);";
+        let (_, result) = run(src);
+        assert_eq!(
+            result,
+            Result::Ok(vec!["[[[[[1]]]]]".to_string()], ValueType::Nil)
+        );
+
+        let mut interner = Interner::default();
+        let chunk = compile(src, &mut interner).unwrap();
+        let mut vm = VM::init(chunk, interner);
+        vm.set_max_value_depth(4);
+        assert_eq!(
+            vm.run(),
+            Result::RuntimeErr("Arrays can't be nested more than 4 deep".to_string())
+        );
+    }
+
+    #[test]
+    fn test_print_options() {
+        let (mut vm, result) = run(r#"
+        let t = tensor([[1, 2], [3, 4]]);
+        print(t);
+        set_print_options(" ", "; ");
+        print(t, tensor(1, 2));
+        set_print_options(sep = ",", row_sep = " | ");
+        print(t);
+        "#);
+
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec![
+                    "[[1, 2], [3, 4]]".to_string(),
+                    "[[1 2]; [3 4]] [1 2]".to_string(),
+                    "[[1,2] | [3,4]]".to_string()
+                ],
+                ValueType::Nil
+            )
+        );
+
+        vm.reset(false);
+        let t = tensor::Tensor::from_vec(vec![1.0, 2.0], vec![2]);
+        assert_eq!(t.to_string(), "[1, 2]");
+        vm.set_print_options(PrintOptions {
+            element_separator: "  ".to_string(),
+            row_separator: ", ".to_string(),
+        });
+        assert_eq!(t.to_string(), "[1  2]");
+    }
+
+    #[test]
+    fn test_backward_with_seed() {
+        let (_, result) = run(r#"
+        let x = tensor(3.0);
+        (x * x).backward();
+        print(x.grad());
+        let a = tensor([[1, 2], [3, 4]]);
+        (a * 2).backward(tensor([[1, 1], [0, 2]]));
+        print(a.grad());
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(
+                vec!["6".to_string(), "[[2, 2], [0, 4]]".to_string()],
+                ValueType::Nil
+            )
+        );
+
+        let (_, result) = run("tensor([[1, 2], [3, 4]]).backward();");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("backward needs a scalar, got a tensor of shape [2, 2]".to_string())
+        );
+        let (_, result) = run("tensor(1, 2).backward(tensor(1, 2, 3));");
+        assert_eq!(
+            result,
+            Result::RuntimeErr(
+                "backward got a gradient of shape [3] for a tensor of shape [2]".to_string()
+            )
+        );
+        let (_, result) = run("tensor(1).backward(true);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("backward expects a tensor gradient, got bool".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tensor_method_registry() {
+        let (vm, result) = run(r#"
+        let x = tensor(-2.0);
+        let y = (x * x).relu();
+        print(y, y.backward(), x.grad(), x.has_grad(), x.zero_grad(), x.grad());
+        "#);
+        assert_eq!(
+            result,
+            Result::Ok(vec!["4 4 -4 true nil 0".to_string()], ValueType::Nil)
+        );
+
+        let mut names: Vec<&str> = vm.methods.keys().copied().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "backward",
+                "grad",
+                "has_grad",
+                "mean",
+                "relu",
+                "sigmoid",
+                "sum",
+                "tanh",
+                "zero_grad"
+            ]
+        );
+
+        let (_, result) = run("relu();");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("relu is a tensor method, call it like x.relu()".to_string())
+        );
+        let (_, result) = run("tensor(1).grad(1);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("grad takes no arguments".to_string())
+        );
+        let (_, result) = run("tensor(1).backward(1, 2);");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("backward takes at most 1 argument".to_string())
+        );
+
+        let mut interner = Interner::default();
+        let chunk = compile("print(1);", &mut interner).unwrap();
+        let vm = VM::with_capabilities(chunk, interner, Capabilities::NONE);
+        assert!(vm.methods.is_empty());
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = std::env::temp_dir().join(format!("grad_import_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib/utils.ai"),
+            "import \"consts.ai\"; fn twice(x) { return x * two; }",
+        )
+        .unwrap();
+        std::fs::write(dir.join("lib/consts.ai"), "let two = 2;").unwrap();
+        std::fs::write(dir.join("a.ai"), "import \"b.ai\";").unwrap();
+        std::fs::write(dir.join("b.ai"), "let b = 1\nimport \"a.ai\";").unwrap();
+        std::fs::write(dir.join("bad.ai"), "let x = ;").unwrap();
+
+        let run_file = |src: &str| {
+            let mut interner = Interner::default();
+            let mut compiler = Compiler::new();
+            compiler.set_source_path(dir.join("main.ai"));
+            compiler
+                .compile_source(src, &mut interner)
+                .map(|chunk| VM::init(chunk, interner).run())
+                .map_err(|errors| errors[0].message.clone())
+        };
+
+        // a file imported twice is only compiled once
+        assert_eq!(
+            run_file("import \"lib/utils.ai\"; import \"lib/utils.ai\"; print(twice(21));"),
+            Ok(Result::Ok(vec!["42".to_string()], ValueType::Nil))
+        );
+        assert_eq!(
+            run_file("import \"missing.ai\";"),
+            Err("Can't import 'missing.ai': file not found".to_string())
+        );
+        assert_eq!(
+            run_file("import \"a.ai\";"),
+            Err("In 'a.ai' [line 1:1]: In 'b.ai' [line 2:1]: Can't import 'a.ai': it imports itself".to_string())
+        );
+        assert!(run_file("import \"bad.ai\";")
+            .unwrap_err()
+            .starts_with("In 'bad.ai' [line 1:"));
+        assert_eq!(
+            run_file("fn f() { import \"lib/consts.ai\"; }"),
+            Err("Imports must be at the top level.".to_string())
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_as() {
+        let dir = std::env::temp_dir().join(format!("grad_import_as_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("math.ai"),
+            "let pi = 3.0\nfn area(r) { return pi * r * r; }",
+        )
+        .unwrap();
+
+        let mut interner = Interner::default();
+        let mut compiler = Compiler::new();
+        compiler.set_source_path(dir.join("main.ai"));
+        let chunk = compiler
+            .compile_source(
+                r#"
+                import "math.ai" as m;
+                let pi = 1;
+                print(m.pi, pi, m.area(2), m);
+                m.nope;
+                "#,
+                &mut interner,
+            )
+            .unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let mut vm = VM::init(chunk, interner);
+        assert_eq!(
+            vm.run(),
+            Result::RuntimeErr("Module m has no member 'nope'".to_string())
+        );
+        assert_eq!(vm.outputs, vec!["3 1 12 <module m>".to_string()]);
+
+        let (_, result) = run("let a = 1; a.b;");
+        assert_eq!(
+            result,
+            Result::RuntimeErr("Only modules have fields, got 1 (number)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_prelude() {
+        // a program's own function takes the place of the prelude's
+        let (_, result) = run("fn abs(x) { return 0; } print(abs(-2), square(3));");
+        assert_eq!(result, Result::Ok(vec!["0 9".to_string()], ValueType::Nil));
+
+        let mut interner = Interner::default();
+        let chunk = compile("print(sign(-4));", &mut interner).unwrap();
+        let mut vm = VM::init(chunk.clone(), interner);
+        vm.set_prelude(false);
+        assert!(matches!(vm.run(), Result::RuntimeErr(_)));
+        vm.set_prelude(true);
+        vm.load(chunk);
+        assert_eq!(vm.run(), Result::Ok(vec!["-1".to_string()], ValueType::Nil));
+    }
+
+    #[test]
+    fn test_profile_tensor_ops() {
+        let mut interner = Interner::default();
+        let src = r#"
+        let x = tensor([[1, -2], [3, 4]]);
+        x.relu(); x.relu(); (x * 2).relu();
+        let y = (x + 1).relu();
+        y.backward(tensor([[1, 1], [1, 1]]));
+        print(tensor(1, 2).relu());
+        "#;
+        let chunk = compile(src, &mut interner).unwrap();
+        let mut vm = VM::init(chunk, interner);
+        assert_eq!(vm.tensor_op_stats(), None);
+        vm.set_profile_tensor_ops(true);
+        assert!(matches!(vm.run(), Result::Ok(..)));
+
+        let stats = vm.tensor_op_stats().unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats["relu"],
+            TensorOpStats {
+                calls: 5,
+                shape: vec![2]
+            }
+        );
+        assert_eq!(
+            stats["backward"],
+            TensorOpStats {
+                calls: 1,
+                shape: vec![2, 2]
+            }
+        );
+        assert_eq!(
+            vm.tensor_op_report().unwrap(),
+            "tensor op       calls  last shape\nbackward            1  [2, 2]\nrelu                5  [2]"
+        );
     }
 }
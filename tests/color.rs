@@ -0,0 +1,29 @@
+// `--color` decides whether errors and the `--debug` output have ANSI escapes
+
+use std::process::Command;
+
+fn run_with(color: &str) -> String {
+    let script = std::env::temp_dir().join(format!("grad_color_{}.ai", color));
+    std::fs::write(&script, "let a = 1;\nprint(a + true);\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_grad"))
+        .args(["--debug", "--color", color])
+        .arg(&script)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&script).unwrap();
+
+    String::from_utf8(output.stdout).unwrap() + &String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn color() {
+    let plain = run_with("never");
+    assert!(plain.contains("Runtime error"));
+    assert!(plain.contains("OP_DEFINE_GLOBAL"));
+    assert!(!plain.contains('\x1b'));
+
+    let colored = run_with("always");
+    assert!(colored.contains("\x1b[31mRuntime error"));
+    assert!(colored.contains('\x1b'));
+}
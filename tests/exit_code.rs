@@ -0,0 +1,33 @@
+// a script that fails exits with 65 (compile error) or 70 (runtime error)
+
+use std::process::Command;
+
+fn status_of(name: &str, src: &str) -> (Option<i32>, String) {
+    let script = std::env::temp_dir().join(format!("grad_exit_{}.ai", name));
+    std::fs::write(&script, src).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_grad"))
+        .args(["--color", "never"])
+        .arg(&script)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&script).unwrap();
+
+    (
+        output.status.code(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn exit_code() {
+    assert_eq!(status_of("ok", "print(1);\n"), (Some(0), String::new()));
+
+    let (code, stderr) = status_of("compile", "let = 1;\n");
+    assert_eq!(code, Some(65));
+    assert!(stderr.starts_with("Compile error"));
+
+    let (code, stderr) = status_of("runtime", "let a = 1;\nprint(a + true);\n");
+    assert_eq!(code, Some(70));
+    assert!(stderr.starts_with("Runtime error"));
+}
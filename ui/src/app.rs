@@ -2,6 +2,7 @@ use grad::{
     ast::{ast_to_ascii, ASTNode, Parser},
     chunk::Chunk,
     compiler, debug,
+    diagnostic::format_diagnostics,
     interner::Interner,
     run_source,
     scanner::Lexer,
@@ -35,11 +36,13 @@ impl CustomLanguage {
         Ok(ast_out)
     }
 
-    fn compile(&self, ast: &Vec<ASTNode>) -> DisassembledOutput {
+    fn compile(&self, ast: &Vec<ASTNode>) -> Result<DisassembledOutput, String> {
         let mut compiler = compiler::Compiler::new();
-        let (bytecode, interner) = compiler.compile(ast.clone());
+        let (bytecode, interner) = compiler
+            .compile(ast.clone())
+            .map_err(|diagnostics| format_diagnostics(&diagnostics))?;
 
-        DisassembledOutput { bytecode, interner }
+        Ok(DisassembledOutput { bytecode, interner })
     }
 
     fn execute(&self, compiled: DisassembledOutput) -> String {
@@ -231,11 +234,13 @@ impl eframe::App for CustomLanguageDemo {
 
 impl CustomLanguageDemo {
     fn update_output(&mut self) {
-        match self.custom_lang.parse(&self.code) {
-            Ok(ast) => {
-                self.ast = Some(ast.clone());
+        let compiled = self.custom_lang.parse(&self.code).and_then(|ast| {
+            self.ast = Some(ast.clone());
+            self.custom_lang.compile(&ast)
+        });
 
-                let disassembled_output = self.custom_lang.compile(&ast);
+        match compiled {
+            Ok(disassembled_output) => {
                 self.disassembled = Some(disassembled_output.clone());
 
                 self.result = self.custom_lang.execute(disassembled_output);